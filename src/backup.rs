@@ -0,0 +1,531 @@
+use chrono::Utc;
+use color_eyre::eyre::{eyre, Context, Result};
+use color_eyre::owo_colors::OwoColorize;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use indicatif::HumanBytes;
+use serde::Serialize;
+use std::fs::File;
+use std::path::{Path, PathBuf};
+
+use crate::daemon::{self, DaemonState};
+use crate::run_as;
+use crate::update_manager::installed;
+use crate::update_manager::metadata::{metadata_path, Metadata, METADATA_FILE_NAME};
+use crate::versions::{get_current_version, VersionWithV};
+use crate::{
+    error, exit_code, info, output, prompt_for_confirmation, warn, BackupCommand, PromptDefault,
+};
+
+/// Directory under `LODESTONE_PATH` backups are stored in
+pub const BACKUP_DIR_NAME: &str = ".core_backup";
+
+const BACKUP_EXTENSION: &str = ".tar.gz";
+
+/// Follows the creation time in the id of backups made before version changes
+const AUTOMATIC_LABEL: &str = "auto";
+
+/// How many of the backups made before version changes are kept, older ones are deleted
+pub const AUTOMATIC_BACKUPS_KEPT: usize = 3;
+
+/// A compressed snapshot of `LODESTONE_PATH`
+#[derive(Debug, Clone, Serialize)]
+pub struct Backup {
+    pub id: String,
+    pub path: PathBuf,
+    pub size: u64,
+    /// Made before a version change rather than asked for, so it is deleted once there are newer ones
+    pub automatic: bool,
+}
+
+pub fn backup_dir(lodestone_path: &Path) -> PathBuf {
+    lodestone_path.join(BACKUP_DIR_NAME)
+}
+
+/// Whether the top level entry `name` of `LODESTONE_PATH` is left out of backups
+///
+/// Core executables and their signatures can be downloaded again, and the files of the cli itself aren't needed to
+/// restore an installation, so only instance and config data is backed up. The metadata of the installation is, so a
+/// restore brings back the version the data was used with
+fn is_excluded(lodestone_path: &Path, name: &str) -> bool {
+    (run_as::is_cli_entry(name) && name != METADATA_FILE_NAME)
+        || (lodestone_path.join(name).is_file()
+            && installed::is_core_executable(name.strip_suffix(".minisig").unwrap_or(name)))
+}
+
+/// Every backup under `lodestone_path`, newest first
+pub fn list_backups(lodestone_path: &Path) -> Result<Vec<Backup>> {
+    let backup_dir = backup_dir(lodestone_path);
+    if !backup_dir.exists() {
+        return Ok(Vec::new());
+    }
+    let mut backups = Vec::new();
+    for entry in std::fs::read_dir(&backup_dir)? {
+        let entry = entry?;
+        let file_name = entry.file_name().to_string_lossy().into_owned();
+        if let Some(id) = file_name.strip_suffix(BACKUP_EXTENSION) {
+            backups.push(Backup {
+                id: id.to_string(),
+                path: entry.path(),
+                size: entry.metadata()?.len(),
+                // the creation time takes the first two parts of the id
+                automatic: id.split('-').nth(2) == Some(AUTOMATIC_LABEL),
+            });
+        }
+    }
+    // ids start with the creation time, so they sort chronologically
+    backups.sort_by(|a, b| b.id.cmp(&a.id));
+    Ok(backups)
+}
+
+/// `id`, or `id` with a `-2`, `-3`... suffix if a backup with that id already exists in `backup_dir`
+fn unused_id(backup_dir: &Path, id: &str) -> String {
+    let mut unused = id.to_string();
+    let mut suffix = 1;
+    while backup_dir
+        .join(format!("{unused}{BACKUP_EXTENSION}"))
+        .exists()
+    {
+        suffix += 1;
+        unused = format!("{id}-{suffix}");
+    }
+    unused
+}
+
+/// Creates a compressed snapshot of `lodestone_path`, `version` is the installed version it is labelled with
+///
+/// `automatic` backups are the ones made before version changes, only the newest of them are kept.
+/// This walks the whole data directory, call it from a blocking context
+pub fn create_backup(
+    lodestone_path: &Path,
+    version: Option<&VersionWithV>,
+    automatic: bool,
+) -> Result<Backup> {
+    let backup_dir = backup_dir(lodestone_path);
+    std::fs::create_dir_all(&backup_dir).context("Failed to create backup directory")?;
+
+    let mut id = Utc::now().format("%Y%m%d-%H%M%S").to_string();
+    if automatic {
+        id = format!("{id}-{AUTOMATIC_LABEL}");
+    }
+    if let Some(version) = version {
+        id = format!("{id}-{version}");
+    }
+    let id = unused_id(&backup_dir, &id);
+    let path = backup_dir.join(format!("{id}{BACKUP_EXTENSION}"));
+
+    // write to a temporary file first so an interrupted backup never looks complete
+    let partial_path = path.with_extension("part");
+    let result = (|| -> Result<()> {
+        let encoder = GzEncoder::new(File::create(&partial_path)?, Compression::default());
+        let mut builder = tar::Builder::new(encoder);
+        builder.follow_symlinks(false);
+        for entry in std::fs::read_dir(lodestone_path)? {
+            let entry = entry?;
+            let name = entry.file_name().to_string_lossy().into_owned();
+            if is_excluded(lodestone_path, &name) {
+                continue;
+            }
+            if entry.file_type()?.is_dir() {
+                builder.append_dir_all(&name, entry.path())?;
+            } else {
+                builder.append_path_with_name(entry.path(), &name)?;
+            }
+        }
+        builder.into_inner()?.finish()?;
+        Ok(())
+    })();
+    if let Err(e) = result {
+        let _ = std::fs::remove_file(&partial_path);
+        return Err(e.wrap_err("Failed to create backup"));
+    }
+    std::fs::rename(&partial_path, &path)?;
+    let size = std::fs::metadata(&path)?.len();
+    Ok(Backup {
+        id,
+        path,
+        size,
+        automatic,
+    })
+}
+
+/// Deletes all but the `keep` newest automatic backups, returning the deleted ones
+pub fn prune_automatic_backups(lodestone_path: &Path, keep: usize) -> Result<Vec<Backup>> {
+    let pruned: Vec<_> = list_backups(lodestone_path)?
+        .into_iter()
+        .filter(|backup| backup.automatic)
+        .skip(keep)
+        .collect();
+    for backup in &pruned {
+        std::fs::remove_file(&backup.path)
+            .with_context(|| format!("Failed to delete backup {}", backup.id))?;
+    }
+    Ok(pruned)
+}
+
+/// Replaces the data under `lodestone_path` with the content of backup `id`
+///
+/// Core executables and other backups are left alone. This walks the whole data directory, call it from a blocking context
+/// Fails if Lodestone Core is running under `lodestone_path`, its files can't be swapped out under it
+fn ensure_not_running(lodestone_path: &Path) -> Result<()> {
+    match daemon::state(lodestone_path)? {
+        DaemonState::Running(pid_file) => Err(eyre!(
+            "Lodestone Core is running with PID {}, run 'stop' before restoring a backup",
+            pid_file.pid
+        )),
+        DaemonState::Stale(_) | DaemonState::NotRunning => Ok(()),
+    }
+}
+
+pub fn restore_backup(lodestone_path: &Path, id: &str) -> Result<()> {
+    ensure_not_running(lodestone_path)?;
+    let backup = list_backups(lodestone_path)?
+        .into_iter()
+        .find(|backup| backup.id == id)
+        .ok_or_else(|| eyre!("No backup with id {id}"))?;
+
+    // make sure the archive is readable before deleting anything
+    tar::Archive::new(GzDecoder::new(File::open(&backup.path)?))
+        .entries()?
+        .try_for_each(|entry| entry.map(|_| ()))
+        .context("The backup is corrupted")?;
+
+    for entry in std::fs::read_dir(lodestone_path)? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if is_excluded(lodestone_path, &name) {
+            continue;
+        }
+        if entry.file_type()?.is_dir() {
+            std::fs::remove_dir_all(entry.path())?;
+        } else {
+            std::fs::remove_file(entry.path())?;
+        }
+    }
+    tar::Archive::new(GzDecoder::new(File::open(&backup.path)?))
+        .unpack(lodestone_path)
+        .context("Failed to restore backup")?;
+    Ok(())
+}
+
+pub async fn backup_command(command: BackupCommand, lodestone_path: &Path, yes_all: bool) -> i32 {
+    let current_version = get_current_version().await.ok();
+    match command {
+        BackupCommand::List => match list_backups(lodestone_path) {
+            Ok(backups) if output::is_machine_readable() => {
+                output::result("backups", backups);
+                exit_code::SUCCESS
+            }
+            Ok(backups) if backups.is_empty() => {
+                info!("There are no backups yet");
+                exit_code::SUCCESS
+            }
+            Ok(backups) => {
+                println!("Backups:");
+                for backup in backups {
+                    if backup.automatic {
+                        println!(
+                            "  {} ({}, before a version change)",
+                            backup.id,
+                            HumanBytes(backup.size)
+                        );
+                    } else {
+                        println!("  {} ({})", backup.id, HumanBytes(backup.size));
+                    }
+                }
+                exit_code::SUCCESS
+            }
+            Err(e) => {
+                error!("Failed to list backups: {}", e);
+                exit_code::FAILURE
+            }
+        },
+        BackupCommand::Create => {
+            if !lodestone_path.exists() {
+                error!(
+                    "Nothing to back up, {} does not exist",
+                    lodestone_path.display().bold().blue()
+                );
+                return exit_code::NOT_INSTALLED;
+            }
+            info!(
+                "Backing up {}, this may take a while",
+                lodestone_path.display().bold().blue()
+            );
+            let path = lodestone_path.to_owned();
+            match tokio::task::spawn_blocking(move || {
+                create_backup(&path, current_version.as_ref(), false)
+            })
+            .await
+            .unwrap()
+            {
+                Ok(backup) => {
+                    info!(
+                        "Created backup {} ({})",
+                        backup.id.bold().blue(),
+                        HumanBytes(backup.size)
+                    );
+                    output::result("backup", backup);
+                    exit_code::SUCCESS
+                }
+                Err(e) => {
+                    error!("{:#}", e);
+                    exit_code::FAILURE
+                }
+            }
+        }
+        BackupCommand::Restore { id } => {
+            warn!(
+                "{}",
+                format!(
+                    "This will replace the data under {} with backup {}",
+                    lodestone_path.display(),
+                    id
+                )
+                .bold()
+                .red()
+            );
+            if !yes_all
+                && !prompt_for_confirmation(
+                    format!(
+                        "Are you sure you want to restore this backup? {}:",
+                        "(yes/n)".bold().magenta()
+                    ),
+                    |input| input.trim() == "yes",
+                    PromptDefault::Required,
+                )
+            {
+                info!("Aborting restore, no file changes were made.");
+                return exit_code::ABORTED;
+            }
+            let path = lodestone_path.to_owned();
+            let result = tokio::task::spawn_blocking(move || {
+                ensure_not_running(&path)?;
+                if !list_backups(&path)?.iter().any(|backup| backup.id == id) {
+                    return Err(eyre!("No backup with id {id}"));
+                }
+                let safety_backup = create_backup(&path, current_version.as_ref(), false)?;
+                info!(
+                    "Backed up the current data as {} in case you want it back",
+                    safety_backup.id.bold().blue()
+                );
+                output::result("safety_backup", safety_backup);
+                restore_backup(&path, &id)?;
+                output::result("restored", id);
+                Ok(())
+            })
+            .await
+            .unwrap();
+            match result {
+                Ok(()) => {
+                    info!("Backup restored");
+                    if let Ok(metadata) =
+                        Metadata::read_metadata(&metadata_path(lodestone_path)).await
+                    {
+                        if installed::find_installed(lodestone_path, &metadata.current_version)
+                            .is_none()
+                        {
+                            warn!(
+                                "The backup uses Lodestone Core {}, which isn't downloaded anymore. Run '{}' to get it back",
+                                metadata.current_version.bold().blue(),
+                                format!("use {}", metadata.current_version).bold().blue()
+                            );
+                        }
+                    }
+                    exit_code::SUCCESS
+                }
+                Err(e) => {
+                    error!("{:#}", e);
+                    exit_code::FAILURE
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{config, supervisor};
+    use std::str::FromStr;
+
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir()
+            .join(format!("lodestone_cli_test_{}", std::process::id()))
+            .join(name);
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn version() -> VersionWithV {
+        VersionWithV::from_str("v0.5.1").unwrap()
+    }
+
+    /// Fills `lodestone_path` with instance data, a core executable and a partial download
+    fn populate(lodestone_path: &Path) {
+        std::fs::create_dir_all(lodestone_path.join("instances/survival")).unwrap();
+        std::fs::write(lodestone_path.join("instances/survival/world.dat"), "world").unwrap();
+        std::fs::write(lodestone_path.join("config.json"), "{}").unwrap();
+        std::fs::write(lodestone_path.join(METADATA_FILE_NAME), "{}").unwrap();
+        std::fs::write(
+            lodestone_path.join(crate::util::get_executable_name(&version())),
+            "core",
+        )
+        .unwrap();
+        std::fs::create_dir_all(lodestone_path.join("tmp")).unwrap();
+        std::fs::write(lodestone_path.join("tmp/download.part"), "partial").unwrap();
+        std::fs::write(
+            lodestone_path.join(config::INSTALL_CONFIG_FILE_NAME),
+            "yes_all = true",
+        )
+        .unwrap();
+        std::fs::write(lodestone_path.join(supervisor::STATE_FILE_NAME), "{}").unwrap();
+        // left by a process that is gone
+        std::fs::write(
+            lodestone_path.join(daemon::PID_FILE_NAME),
+            r#"{"pid": 4242, "started_at": "2020-01-01 00:00:00 UTC", "detached": true}"#,
+        )
+        .unwrap();
+    }
+
+    fn archived_names(backup: &Backup) -> Vec<String> {
+        let mut archive = tar::Archive::new(GzDecoder::new(File::open(&backup.path).unwrap()));
+        let mut names: Vec<_> = archive
+            .entries()
+            .unwrap()
+            .map(|entry| {
+                let path = entry.unwrap().path().unwrap().into_owned();
+                path.to_string_lossy().trim_end_matches('/').to_string()
+            })
+            .collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn backs_up_instance_data_only() {
+        let lodestone_path = test_dir("backup_create");
+        populate(&lodestone_path);
+
+        let backup = create_backup(&lodestone_path, Some(&version()), false).unwrap();
+        assert!(backup.id.ends_with("-v0.5.1"));
+        assert!(!backup.automatic);
+        assert_eq!(
+            archived_names(&backup),
+            [
+                METADATA_FILE_NAME,
+                "config.json",
+                "instances",
+                "instances/survival",
+                "instances/survival/world.dat"
+            ]
+        );
+        let backups = list_backups(&lodestone_path).unwrap();
+        assert_eq!(backups.len(), 1);
+        assert_eq!(backups[0].id, backup.id);
+        assert_eq!(backups[0].size, backup.size);
+    }
+
+    #[test]
+    fn restores_a_backup() {
+        let lodestone_path = test_dir("backup_restore");
+        populate(&lodestone_path);
+        let backup = create_backup(&lodestone_path, None, false).unwrap();
+
+        std::fs::write(
+            lodestone_path.join("instances/survival/world.dat"),
+            "changed",
+        )
+        .unwrap();
+        std::fs::write(lodestone_path.join("added.txt"), "added").unwrap();
+        restore_backup(&lodestone_path, &backup.id).unwrap();
+
+        assert_eq!(
+            std::fs::read_to_string(lodestone_path.join("instances/survival/world.dat")).unwrap(),
+            "world"
+        );
+        assert!(!lodestone_path.join("added.txt").exists());
+        // excluded entries are left alone
+        assert!(lodestone_path
+            .join(crate::util::get_executable_name(&version()))
+            .exists());
+        assert!(lodestone_path.join("tmp/download.part").exists());
+        assert!(lodestone_path
+            .join(config::INSTALL_CONFIG_FILE_NAME)
+            .exists());
+        assert!(lodestone_path.join(supervisor::STATE_FILE_NAME).exists());
+        assert!(lodestone_path.join(daemon::PID_FILE_NAME).exists());
+        assert_eq!(list_backups(&lodestone_path).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn does_not_touch_anything_when_the_backup_is_corrupted() {
+        let lodestone_path = test_dir("backup_corrupted");
+        populate(&lodestone_path);
+        let backup = create_backup(&lodestone_path, None, false).unwrap();
+        let archive = std::fs::read(&backup.path).unwrap();
+        std::fs::write(&backup.path, &archive[..archive.len() / 2]).unwrap();
+
+        std::fs::write(lodestone_path.join("added.txt"), "added").unwrap();
+        assert!(restore_backup(&lodestone_path, &backup.id).is_err());
+        assert!(lodestone_path.join("added.txt").exists());
+        assert!(lodestone_path.join("instances/survival/world.dat").exists());
+        assert!(restore_backup(&lodestone_path, "20200101-000000").is_err());
+    }
+
+    #[test]
+    fn suffixes_the_id_of_a_backup_made_in_the_same_second() {
+        let lodestone_path = test_dir("backup_same_second");
+        let backup_dir = backup_dir(&lodestone_path);
+        std::fs::create_dir_all(&backup_dir).unwrap();
+        assert_eq!(unused_id(&backup_dir, "20200101-000000"), "20200101-000000");
+        std::fs::write(backup_dir.join("20200101-000000.tar.gz"), "").unwrap();
+        assert_eq!(
+            unused_id(&backup_dir, "20200101-000000"),
+            "20200101-000000-2"
+        );
+        std::fs::write(backup_dir.join("20200101-000000-2.tar.gz"), "").unwrap();
+        assert_eq!(
+            unused_id(&backup_dir, "20200101-000000"),
+            "20200101-000000-3"
+        );
+    }
+
+    #[test]
+    fn keeps_only_the_newest_automatic_backups() {
+        let lodestone_path = test_dir("backup_prune");
+        let backup_dir = backup_dir(&lodestone_path);
+        std::fs::create_dir_all(&backup_dir).unwrap();
+        for id in [
+            "20200101-000000-auto-v0.5.0",
+            "20200102-000000-auto-v0.5.1",
+            "20200103-000000-v0.5.1",
+            "20200104-000000-auto-v0.5.2",
+            "20200104-000000-auto-v0.5.2-2",
+        ] {
+            std::fs::write(backup_dir.join(format!("{id}{BACKUP_EXTENSION}")), "").unwrap();
+        }
+
+        let pruned = prune_automatic_backups(&lodestone_path, 2).unwrap();
+        let pruned: Vec<_> = pruned.iter().map(|backup| backup.id.as_str()).collect();
+        assert_eq!(
+            pruned,
+            ["20200102-000000-auto-v0.5.1", "20200101-000000-auto-v0.5.0"]
+        );
+        let kept: Vec<_> = list_backups(&lodestone_path)
+            .unwrap()
+            .into_iter()
+            .map(|backup| backup.id)
+            .collect();
+        assert_eq!(
+            kept,
+            [
+                "20200104-000000-auto-v0.5.2-2",
+                "20200104-000000-auto-v0.5.2",
+                "20200103-000000-v0.5.1"
+            ]
+        );
+    }
+}
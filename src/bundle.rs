@@ -0,0 +1,406 @@
+use color_eyre::eyre::{eyre, Context, Result};
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+use std::str::FromStr;
+
+use crate::update_manager::installed;
+use crate::verify::{check_signature, VerifyOptions};
+use crate::versions::VersionWithV;
+use crate::{util, warn};
+use color_eyre::owo_colors::OwoColorize;
+
+/// Name of the manifest at the root of a bundle
+pub const MANIFEST_NAME: &str = "manifest.json";
+
+/// Describes the Lodestone Core executable a bundle carries
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Manifest {
+    pub version: VersionWithV,
+    pub executable_name: String,
+    pub sha256: String,
+    pub created: String,
+}
+
+/// Whether the file at `path` is a gzip compressed bundle, rather than a bare executable
+fn is_bundle(path: &Path) -> Result<bool> {
+    let mut magic = [0; 2];
+    let mut file =
+        File::open(path).with_context(|| format!("Failed to open {}", path.display()))?;
+    Ok(file.read_exact(&mut magic).is_ok() && magic == [0x1f, 0x8b])
+}
+
+/// Packages the installed executable `executable_name` of `version` into a bundle at `dest`
+///
+/// The bundle holds a manifest, the executable, its checksum and its signature if it was downloaded with one
+pub fn export_bundle(
+    lodestone_path: &Path,
+    version: &VersionWithV,
+    executable_name: &str,
+    dest: &Path,
+) -> Result<Manifest> {
+    let executable_path = lodestone_path.join(executable_name);
+    let manifest = Manifest {
+        version: version.clone(),
+        executable_name: executable_name.to_string(),
+        sha256: util::sha256_file(&executable_path)
+            .with_context(|| format!("Failed to read {}", executable_path.display()))?,
+        created: chrono::Utc::now().to_rfc3339(),
+    };
+
+    let partial_path = dest.with_extension("part");
+    let result = (|| -> Result<()> {
+        let encoder = GzEncoder::new(File::create(&partial_path)?, Compression::default());
+        let mut builder = tar::Builder::new(encoder);
+        let mut append_bytes = |name: &str, bytes: &[u8]| -> Result<()> {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(bytes.len() as u64);
+            header.set_mode(0o644);
+            header.set_mtime(chrono::Utc::now().timestamp().max(0) as u64);
+            builder.append_data(&mut header, name, bytes)?;
+            Ok(())
+        };
+        append_bytes(MANIFEST_NAME, &serde_json::to_vec_pretty(&manifest)?)?;
+        append_bytes(
+            &format!("{executable_name}.sha256"),
+            format!("{}  {}\n", manifest.sha256, executable_name).as_bytes(),
+        )?;
+        let signature_path = lodestone_path.join(installed::signature_name(executable_name));
+        if signature_path.is_file() {
+            builder.append_path_with_name(
+                &signature_path,
+                installed::signature_name(executable_name),
+            )?;
+        } else {
+            warn!(
+                "{} has no signature, the bundle can only be installed with '--allow-unsigned'",
+                executable_name
+            );
+        }
+        builder.append_path_with_name(&executable_path, executable_name)?;
+        builder.into_inner()?.finish()?;
+        Ok(())
+    })();
+    if let Err(e) = result {
+        let _ = std::fs::remove_file(&partial_path);
+        return Err(e.wrap_err("Failed to create bundle"));
+    }
+    std::fs::rename(&partial_path, dest)?;
+    Ok(manifest)
+}
+
+/// Unpacks the bundle at `path` into `dir`, refusing anything but plain files at its root
+fn unpack_bundle(path: &Path, dir: &Path) -> Result<Manifest> {
+    let mut archive = tar::Archive::new(GzDecoder::new(File::open(path)?));
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let entry_path = entry.path()?.into_owned();
+        let name = match (entry_path.file_name(), entry_path.parent()) {
+            (Some(name), Some(parent)) if parent.as_os_str().is_empty() => name.to_owned(),
+            _ => return Err(eyre!("Unexpected entry {}", entry_path.display())),
+        };
+        if !entry.header().entry_type().is_file() {
+            return Err(eyre!("Unexpected entry {}", entry_path.display()));
+        }
+        entry.unpack(dir.join(name))?;
+    }
+    let manifest = std::fs::read(dir.join(MANIFEST_NAME))
+        .with_context(|| format!("The bundle has no {MANIFEST_NAME}"))?;
+    serde_json::from_slice(&manifest).with_context(|| format!("Malformed {MANIFEST_NAME}"))
+}
+
+/// Checks a Lodestone Core executable from a bundle or a local file and copies it into `lodestone_path`, returning its version
+///
+/// A bundle must carry an executable for this platform matching the checksum of its manifest.
+/// A bare executable is checked against a `.sha256` file next to it, and its version is read from its
+/// name unless `version` is given. Signatures are checked like for downloads. Never touches the network,
+/// call it from a blocking context
+pub fn import(
+    path: &Path,
+    lodestone_path: &Path,
+    version: Option<&VersionWithV>,
+    verify: &VerifyOptions,
+) -> Result<VersionWithV> {
+    let staging_dir = lodestone_path
+        .join("tmp")
+        .join(format!("import-{}", std::process::id()));
+    if staging_dir.exists() {
+        std::fs::remove_dir_all(&staging_dir)?;
+    }
+    std::fs::create_dir_all(&staging_dir).context("Failed to create tmp dir")?;
+    let result = import_into(path, lodestone_path, version, verify, &staging_dir);
+    let _ = std::fs::remove_dir_all(&staging_dir);
+    result
+}
+
+fn import_into(
+    path: &Path,
+    lodestone_path: &Path,
+    version: Option<&VersionWithV>,
+    verify: &VerifyOptions,
+    staging_dir: &Path,
+) -> Result<VersionWithV> {
+    // (version, executable, checksum, signature)
+    let (bundle_version, staged_executable, expected_checksum, signature) = if is_bundle(path)? {
+        let manifest = unpack_bundle(path, staging_dir)
+            .with_context(|| format!("{} is not a valid bundle", path.display()))?;
+        if util::executable_name_without_version().is_none()
+            || manifest.executable_name != util::get_executable_name(&manifest.version)
+        {
+            return Err(eyre!(
+                "{} holds {}, which doesn't run on this platform",
+                path.display(),
+                manifest.executable_name
+            ));
+        }
+        let staged_executable = staging_dir.join(&manifest.executable_name);
+        let sidecar = std::fs::read_to_string(
+            staging_dir.join(format!("{}.sha256", manifest.executable_name)),
+        )
+        .ok();
+        if let Some(sidecar) = sidecar {
+            if !sidecar
+                .split_whitespace()
+                .next()
+                .is_some_and(|hash| hash.eq_ignore_ascii_case(&manifest.sha256))
+            {
+                return Err(eyre!(
+                    "The checksum file of the bundle doesn't match its manifest"
+                ));
+            }
+        }
+        let signature = std::fs::read_to_string(
+            staging_dir.join(installed::signature_name(&manifest.executable_name)),
+        )
+        .ok();
+        (
+            Some(manifest.version),
+            staged_executable,
+            Some(manifest.sha256),
+            signature,
+        )
+    } else {
+        let file_name = path
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        let checksum = std::fs::read_to_string(path.with_file_name(format!("{file_name}.sha256")))
+            .ok()
+            .and_then(|contents| contents.split_whitespace().next().map(str::to_string));
+        let signature =
+            std::fs::read_to_string(path.with_file_name(installed::signature_name(&file_name)))
+                .ok();
+        let name_version = util::executable_name_without_version().and_then(|prefix| {
+            let version = file_name.strip_prefix(&prefix)?.strip_prefix('_')?;
+            VersionWithV::from_str(version.strip_suffix(".exe").unwrap_or(version)).ok()
+        });
+        let staged_executable = staging_dir.join(&file_name);
+        std::fs::copy(path, &staged_executable)
+            .with_context(|| format!("Failed to copy {}", path.display()))?;
+        (name_version, staged_executable, checksum, signature)
+    };
+
+    let version = match (version, bundle_version) {
+        (Some(version), Some(bundle_version)) if *version != bundle_version => {
+            return Err(eyre!(
+                "{} holds Lodestone Core {bundle_version}, not {version}",
+                path.display()
+            ))
+        }
+        (Some(version), _) => version.clone(),
+        (None, Some(bundle_version)) => bundle_version,
+        (None, None) => {
+            return Err(eyre!(
+                "Couldn't tell the version of {} from its name, pass it with '--version'",
+                path.display()
+            ))
+        }
+    };
+    let executable_name = util::get_executable_name(&version);
+    if !staged_executable.is_file() {
+        return Err(eyre!("{} has no executable", path.display()));
+    }
+
+    match expected_checksum {
+        _ if verify.skip_checksum => warn!(
+            "{}",
+            "Skipping checksum verification! The Lodestone Core executable will NOT be checked for corruption or tampering"
+                .bold()
+                .red()
+        ),
+        Some(expected) => util::verify_sha256(&staged_executable, &expected)?,
+        None => {
+            return Err(eyre!(
+                "There is no checksum for {}, put it in '{}' next to it or pass '--skip-verify' to install it anyway",
+                path.display(),
+                format!("{}.sha256", path.display())
+            ))
+        }
+    }
+    check_signature(
+        &staged_executable,
+        &executable_name,
+        signature.as_deref(),
+        verify,
+    )?;
+
+    let executable_path = lodestone_path.join(&executable_name);
+    std::fs::copy(&staged_executable, &executable_path)
+        .with_context(|| format!("Failed to copy {}", executable_path.display()))?;
+    if let Some(signature) = signature {
+        std::fs::write(
+            lodestone_path.join(installed::signature_name(&executable_name)),
+            signature,
+        )?;
+    }
+    Ok(version)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_dir(name: &str) -> std::path::PathBuf {
+        let dir = std::env::temp_dir()
+            .join(format!("lodestone_cli_test_{}", std::process::id()))
+            .join(name);
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn version() -> VersionWithV {
+        VersionWithV::from_str("v0.5.1").unwrap()
+    }
+
+    fn unsigned() -> VerifyOptions {
+        VerifyOptions {
+            skip_checksum: false,
+            allow_unsigned: true,
+            public_key: None,
+        }
+    }
+
+    /// Writes a bundle holding `entries` to `path`, without the checks `tar::Builder` makes on entry names
+    fn write_bundle(path: &Path, entries: &[(&str, &[u8])]) {
+        let encoder = GzEncoder::new(File::create(path).unwrap(), Compression::default());
+        let mut builder = tar::Builder::new(encoder);
+        for (name, bytes) in entries {
+            let mut header = tar::Header::new_old();
+            header.as_old_mut().name[..name.len()].copy_from_slice(name.as_bytes());
+            header.set_size(bytes.len() as u64);
+            header.set_mode(0o644);
+            header.set_cksum();
+            builder.append(&header, *bytes).unwrap();
+        }
+        builder.into_inner().unwrap().finish().unwrap();
+    }
+
+    /// Writes a bundle of an executable holding `executable` whose manifest claims `sha256`
+    fn write_core_bundle(path: &Path, executable: &[u8], sha256: &str, signature: Option<&str>) {
+        let executable_name = util::get_executable_name(&version());
+        let manifest = serde_json::to_vec(&Manifest {
+            version: version(),
+            executable_name: executable_name.clone(),
+            sha256: sha256.to_string(),
+            created: chrono::Utc::now().to_rfc3339(),
+        })
+        .unwrap();
+        let signature_name = installed::signature_name(&executable_name);
+        let mut entries = vec![
+            (MANIFEST_NAME, manifest.as_slice()),
+            (executable_name.as_str(), executable),
+        ];
+        if let Some(signature) = signature {
+            entries.push((signature_name.as_str(), signature.as_bytes()));
+        }
+        write_bundle(path, &entries);
+    }
+
+    fn sha256(dir: &Path, bytes: &[u8]) -> String {
+        std::fs::write(dir.join("checksummed"), bytes).unwrap();
+        util::sha256_file(&dir.join("checksummed")).unwrap()
+    }
+
+    #[test]
+    fn imports_a_bundle() {
+        let dir = test_dir("import_bundle");
+        let lodestone_path = dir.join("lodestone");
+        std::fs::create_dir_all(&lodestone_path).unwrap();
+        write_core_bundle(
+            &dir.join("core.tar.gz"),
+            b"core",
+            &sha256(&dir, b"core"),
+            None,
+        );
+
+        let version = import(&dir.join("core.tar.gz"), &lodestone_path, None, &unsigned()).unwrap();
+
+        assert_eq!(version, self::version());
+        let executable_path = lodestone_path.join(util::get_executable_name(&version));
+        assert_eq!(std::fs::read(executable_path).unwrap(), b"core");
+    }
+
+    #[test]
+    fn refuses_entries_outside_of_the_bundle() {
+        let dir = test_dir("import_traversal");
+        let lodestone_path = dir.join("lodestone");
+        std::fs::create_dir_all(&lodestone_path).unwrap();
+        write_bundle(
+            &dir.join("core.tar.gz"),
+            &[("../../../escaped", b"evil".as_slice())],
+        );
+
+        let result = import(&dir.join("core.tar.gz"), &lodestone_path, None, &unsigned());
+
+        assert!(format!("{:#}", result.unwrap_err()).contains("Unexpected entry"));
+        assert!(!dir.join("escaped").exists());
+        assert!(!lodestone_path.join("escaped").exists());
+    }
+
+    #[test]
+    fn refuses_a_tampered_executable() {
+        let dir = test_dir("import_tampered");
+        let lodestone_path = dir.join("lodestone");
+        std::fs::create_dir_all(&lodestone_path).unwrap();
+        write_core_bundle(
+            &dir.join("core.tar.gz"),
+            b"evil",
+            &sha256(&dir, b"core"),
+            None,
+        );
+
+        let result = import(&dir.join("core.tar.gz"), &lodestone_path, None, &unsigned());
+
+        assert!(format!("{:#}", result.unwrap_err()).contains("Checksum mismatch"));
+        let executable_name = util::get_executable_name(&version());
+        assert!(!lodestone_path.join(executable_name).exists());
+    }
+
+    #[test]
+    fn refuses_a_bad_signature() {
+        let dir = test_dir("import_bad_signature");
+        let lodestone_path = dir.join("lodestone");
+        std::fs::create_dir_all(&lodestone_path).unwrap();
+        write_core_bundle(
+            &dir.join("core.tar.gz"),
+            b"core",
+            &sha256(&dir, b"core"),
+            Some("untrusted comment: tampered\nRWQf6LRCGA9i53mlYecO4IzT51TGPpvWucNSCh1CBM0QTaLn73Y7GFO3\n"),
+        );
+        let verify = VerifyOptions {
+            allow_unsigned: false,
+            ..unsigned()
+        };
+
+        let result = import(&dir.join("core.tar.gz"), &lodestone_path, None, &verify);
+
+        assert!(format!("{:#}", result.unwrap_err()).contains("Signature verification failed"));
+        let executable_name = util::get_executable_name(&version());
+        assert!(!lodestone_path.join(executable_name).exists());
+    }
+}
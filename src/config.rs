@@ -0,0 +1,380 @@
+use std::collections::BTreeMap;
+use std::fmt::Display;
+use std::path::{Path, PathBuf};
+
+use color_eyre::eyre::{eyre, Context, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+
+use crate::exit_code;
+use crate::output::{self, OutputFormat};
+use crate::update_manager::source::ReleaseSource;
+
+/// Prefix of the environment variables overriding a setting, e.g. `LODESTONE_CLI_YES_ALL=true`
+pub const ENV_PREFIX: &str = "LODESTONE_CLI_";
+
+/// Name of the config file under LODESTONE_PATH, settings there only apply to that installation
+pub const INSTALL_CONFIG_FILE_NAME: &str = "lodestone_cli.toml";
+
+/// Settings that can be set in config files, `LODESTONE_CLI_*` environment variables and on the command line
+///
+/// Keys are the names of the command line options with `_` instead of `-`
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub yes_all: Option<bool>,
+    pub non_interactive: Option<bool>,
+    pub install_path: Option<PathBuf>,
+    pub output: Option<OutputFormat>,
+    pub skip_verify: Option<bool>,
+    pub allow_unsigned: Option<bool>,
+    pub public_key: Option<String>,
+    pub retries: Option<u32>,
+    pub timeout: Option<u64>,
+    pub keep_versions: Option<usize>,
+    pub skip_backup: Option<bool>,
+    pub source: Option<ReleaseSource>,
+    pub supervise: Option<bool>,
+    pub max_restarts: Option<u32>,
+    pub shutdown_timeout: Option<u64>,
+    pub capture_logs: Option<bool>,
+    pub log_max_size: Option<u64>,
+    pub log_max_files: Option<usize>,
+    pub log_max_age: Option<u64>,
+    pub core_port: Option<u16>,
+    /// Passed to Lodestone Core on every launch, before the arguments after `run --`
+    pub core_args: Option<Vec<String>>,
+    /// Set for Lodestone Core on every launch, `--core-env` adds to these rather than replacing them
+    pub core_env: Option<BTreeMap<String, String>>,
+    pub core_nice: Option<i32>,
+    pub core_memory_max: Option<u64>,
+    pub core_cpu_quota: Option<u32>,
+    pub core_max_open_files: Option<u64>,
+    pub run_as: Option<String>,
+}
+
+impl Config {
+    fn defaults() -> Self {
+        let retry_policy = crate::http::RetryPolicy::default();
+        Config {
+            yes_all: Some(false),
+            non_interactive: Some(false),
+            output: Some(OutputFormat::Text),
+            skip_verify: Some(false),
+            allow_unsigned: Some(false),
+            retries: Some(retry_policy.attempts.saturating_sub(1)),
+            timeout: Some(retry_policy.timeout.as_secs()),
+            skip_backup: Some(false),
+            supervise: Some(false),
+            max_restarts: Some(crate::supervisor::DEFAULT_MAX_RESTARTS),
+            shutdown_timeout: Some(crate::run_core::DEFAULT_SHUTDOWN_TIMEOUT.as_secs()),
+            capture_logs: Some(false),
+            log_max_size: Some(crate::core_logs::DEFAULT_MAX_SIZE_MB),
+            log_max_files: Some(crate::core_logs::DEFAULT_MAX_FILES),
+            log_max_age: Some(crate::core_logs::DEFAULT_MAX_AGE_DAYS),
+            core_port: Some(crate::health::DEFAULT_CORE_PORT),
+            ..Default::default()
+        }
+    }
+
+    /// Every key of the config, in alphabetical order
+    pub fn keys() -> Vec<String> {
+        match serde_json::to_value(Config::default()) {
+            Ok(Value::Object(map)) => map.into_iter().map(|(key, _)| key).collect(),
+            _ => Vec::new(),
+        }
+    }
+
+    /// The keys that are set, with their values
+    fn to_map(&self) -> Map<String, Value> {
+        match serde_json::to_value(self) {
+            Ok(Value::Object(map)) => map.into_iter().filter(|(_, v)| !v.is_null()).collect(),
+            _ => Map::new(),
+        }
+    }
+}
+
+/// Where a setting comes from, in increasing order of precedence
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Origin {
+    Default,
+    SystemFile(PathBuf),
+    UserFile(PathBuf),
+    InstallFile(PathBuf),
+    /// The deprecated `args.json` in the working directory
+    ArgsJson,
+    Env(String),
+    CommandLine,
+}
+
+impl Display for Origin {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Origin::Default => write!(f, "default"),
+            Origin::SystemFile(path) => write!(f, "system config {}", path.display()),
+            Origin::UserFile(path) => write!(f, "user config {}", path.display()),
+            Origin::InstallFile(path) => write!(f, "LODESTONE_PATH config {}", path.display()),
+            Origin::ArgsJson => write!(f, "args.json"),
+            Origin::Env(var) => write!(f, "environment variable {var}"),
+            Origin::CommandLine => write!(f, "command line"),
+        }
+    }
+}
+
+/// `/etc/lodestone_cli/config.toml`, or `%ProgramData%\lodestone_cli\config.toml` on Windows
+pub fn system_config_path() -> Option<PathBuf> {
+    #[cfg(windows)]
+    let dir = std::env::var_os("ProgramData").map(PathBuf::from);
+    #[cfg(not(windows))]
+    let dir = Some(PathBuf::from("/etc"));
+    dir.map(|dir| dir.join("lodestone_cli").join("config.toml"))
+}
+
+/// `lodestone_cli/config.toml` under the user's config directory, e.g. `~/.config` on Linux
+pub fn user_config_path() -> Option<PathBuf> {
+    dirs::config_dir().map(|dir| dir.join("lodestone_cli").join("config.toml"))
+}
+
+pub fn install_config_path(lodestone_path: &Path) -> PathBuf {
+    lodestone_path.join(INSTALL_CONFIG_FILE_NAME)
+}
+
+/// Reads the config file at `path`, `None` if it doesn't exist
+fn read_config_file(path: &Path) -> Result<Option<Config>> {
+    let content = match std::fs::read_to_string(path) {
+        Ok(content) => content,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e).with_context(|| format!("Failed to read {}", path.display())),
+    };
+    toml::from_str(&content)
+        .map(Some)
+        .with_context(|| format!("Failed to parse {}", path.display()))
+}
+
+/// Reads the `LODESTONE_CLI_*` environment variables
+///
+/// Values are parsed as TOML, so strings like paths don't need to be quoted
+fn read_env() -> Result<Vec<(String, Value, Origin)>> {
+    let mut entries = Vec::new();
+    for key in Config::keys() {
+        let var = format!("{ENV_PREFIX}{}", key.to_uppercase());
+        let Ok(raw) = std::env::var(&var) else {
+            continue;
+        };
+        let value = toml::from_str::<toml::Table>(&format!("value = {raw}"))
+            .ok()
+            .and_then(|mut table| table.remove("value"))
+            .unwrap_or_else(|| toml::Value::String(raw.clone()));
+        let config: Config = toml::Value::Table(toml::Table::from_iter([(key.clone(), value)]))
+            .try_into()
+            .with_context(|| format!("Invalid value '{raw}' for {var}"))?;
+        entries.extend(
+            config
+                .to_map()
+                .into_iter()
+                .map(|(key, value)| (key, value, Origin::Env(var.clone()))),
+        );
+    }
+    Ok(entries)
+}
+
+/// Reads the deprecated `args.json` in the working directory, `None` if it doesn't exist
+pub fn read_args_json() -> Result<Option<Map<String, Value>>> {
+    let file = match std::fs::File::open("args.json") {
+        Ok(file) => file,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e).context("Failed to read args.json"),
+    };
+    match serde_json::from_reader(file).context("Failed to parse args.json")? {
+        Value::Object(map) => Ok(Some(map)),
+        _ => Err(eyre!("Failed to parse args.json: expected an object")),
+    }
+}
+
+/// A config file that was looked at while resolving the configuration
+#[derive(Debug, Clone)]
+pub struct Source {
+    pub origin: Origin,
+    pub found: bool,
+}
+
+/// The configuration the cli runs with, and where each value comes from
+#[derive(Debug, Clone, Default)]
+pub struct Resolved {
+    values: Map<String, Value>,
+    origins: BTreeMap<String, Origin>,
+    /// Every config file looked at, in increasing order of precedence
+    pub sources: Vec<Source>,
+}
+
+impl Resolved {
+    fn apply(&mut self, entries: impl IntoIterator<Item = (String, Value, Origin)>) {
+        for (key, value, origin) in entries {
+            self.values.insert(key.clone(), value);
+            self.origins.insert(key, origin);
+        }
+    }
+
+    fn apply_config(&mut self, config: &Config, origin: &Origin) {
+        self.apply(
+            config
+                .to_map()
+                .into_iter()
+                .map(|(key, value)| (key, value, origin.clone())),
+        );
+    }
+
+    fn apply_file(&mut self, path: Option<PathBuf>, origin: fn(PathBuf) -> Origin) -> Result<()> {
+        let Some(path) = path else {
+            return Ok(());
+        };
+        let config = read_config_file(&path)?;
+        let origin = origin(path);
+        if let Some(config) = &config {
+            self.apply_config(config, &origin);
+        }
+        self.sources.push(Source {
+            origin,
+            found: config.is_some(),
+        });
+        Ok(())
+    }
+
+    pub fn config(&self) -> Result<Config> {
+        serde_json::from_value(Value::Object(self.values.clone()))
+            .context("Failed to resolve the configuration")
+    }
+
+    /// Every key with its value, if set, and where the value comes from
+    pub fn entries(&self) -> Vec<(String, Option<&Value>, Option<&Origin>)> {
+        Config::keys()
+            .into_iter()
+            .map(|key| {
+                let value = self.values.get(&key);
+                let origin = self.origins.get(&key);
+                (key, value, origin)
+            })
+            .collect()
+    }
+}
+
+/// Resolves the configuration, from lowest to highest precedence:
+/// defaults, the system config file, the user config file, the config file under LODESTONE_PATH,
+/// `args.json`, `LODESTONE_CLI_*` environment variables and `command_line`
+///
+/// `install_path` can't be set in the config file under LODESTONE_PATH, since that file is found through it
+pub fn load(args_json: Option<&Map<String, Value>>, command_line: Config) -> Result<Resolved> {
+    let args_json: Config = match args_json {
+        Some(args_json) => serde_json::from_value(Value::Object(
+            args_json
+                .iter()
+                .filter(|(key, _)| Config::keys().contains(key))
+                .map(|(key, value)| (key.clone(), value.clone()))
+                .collect(),
+        ))
+        .context("Failed to parse args.json")?,
+        None => Config::default(),
+    };
+    let env = read_env()?;
+
+    let mut resolved = Resolved::default();
+    resolved.apply_config(&Config::defaults(), &Origin::Default);
+    resolved.apply_file(system_config_path(), Origin::SystemFile)?;
+    resolved.apply_file(user_config_path(), Origin::UserFile)?;
+
+    let install_path = command_line
+        .install_path
+        .clone()
+        .or_else(|| {
+            env.iter()
+                .find(|(key, _, _)| key == "install_path")
+                .and_then(|(_, value, _)| value.as_str().map(PathBuf::from))
+        })
+        .or_else(|| args_json.install_path.clone())
+        .or_else(|| {
+            resolved
+                .config()
+                .ok()
+                .and_then(|config| config.install_path)
+        })
+        .or_else(crate::util::get_lodestone_path);
+    if let Some(install_path) = install_path {
+        let path = install_config_path(&install_path);
+        let config = read_config_file(&path)?;
+        if let Some(config) = &config {
+            if config.install_path.is_some() {
+                return Err(eyre!(
+                    "Failed to parse {}: install_path can't be set in the config file under LODESTONE_PATH",
+                    path.display()
+                ));
+            }
+            resolved.apply_config(config, &Origin::InstallFile(path.clone()));
+        }
+        resolved.sources.push(Source {
+            origin: Origin::InstallFile(path),
+            found: config.is_some(),
+        });
+    }
+
+    resolved.apply_config(&args_json, &Origin::ArgsJson);
+    resolved.apply(env);
+    resolved.apply_config(&command_line, &Origin::CommandLine);
+    resolved.config()?;
+    Ok(resolved)
+}
+
+pub fn show_command(resolved: &Resolved, origin: bool) -> i32 {
+    let entries = resolved.entries();
+    if output::is_machine_readable() {
+        output::result(
+            "config",
+            entries
+                .iter()
+                .map(|(key, value, _)| (key.clone(), value.cloned().unwrap_or(Value::Null)))
+                .collect::<serde_json::Map<_, _>>(),
+        );
+        if origin {
+            output::result(
+                "origins",
+                entries
+                    .iter()
+                    .filter_map(|(key, _, origin)| {
+                        Some((key.clone(), (*origin)?.to_string().into()))
+                    })
+                    .collect::<serde_json::Map<_, _>>(),
+            );
+            output::result(
+                "sources",
+                resolved
+                    .sources
+                    .iter()
+                    .map(|source| json!({ "origin": source.origin.to_string(), "found": source.found }))
+                    .collect::<Vec<_>>(),
+            );
+        }
+        return exit_code::SUCCESS;
+    }
+    if origin {
+        println!("# Config files, from lowest to highest precedence:");
+        for source in &resolved.sources {
+            let found = if source.found { "" } else { " (not found)" };
+            println!("#   {}{}", source.origin, found);
+        }
+        println!(
+            "# followed by args.json, {}* environment variables and the command line",
+            ENV_PREFIX
+        );
+        println!();
+    }
+    for (key, value, source) in entries {
+        let line = match value.map(toml::Value::try_from) {
+            Some(Ok(value)) => format!("{key} = {value}"),
+            _ => format!("# {key} is not set"),
+        };
+        match source {
+            Some(source) if origin => println!("{line:<40} # {source}"),
+            _ => println!("{line}"),
+        }
+    }
+    exit_code::SUCCESS
+}
@@ -0,0 +1,624 @@
+use chrono::Utc;
+use color_eyre::eyre::{eyre, Context, Result};
+use color_eyre::owo_colors::OwoColorize;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashSet};
+use std::env;
+use std::fs::File;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use crate::registry::{self, Descriptor, ImageManifest, Reference, Registry};
+use crate::update_manager::download::download_release;
+use crate::update_manager::metadata::{self, Metadata};
+use crate::update_manager::source::ReleaseSource;
+use crate::util::executable_name_without_version;
+use crate::verify::VerifyOptions;
+use crate::versions::{self, Channel, VersionSpec, VersionWithV};
+use crate::{
+    error, exit_code, health, info, output, resolve_version_requirement, util, warn, Args,
+};
+
+pub const DEFAULT_BASE_IMAGE: &str = "debian:bookworm-slim";
+
+/// Where the cli and `LODESTONE_PATH` live inside the image
+const IMAGE_CLI_PATH: &str = "/usr/local/bin/lodestone_cli";
+const IMAGE_LODESTONE_PATH: &str = "/var/lib/lodestone";
+const IMAGE_CA_BUNDLE_PATH: &str = "/etc/ssl/certs/ca-certificates.crt";
+
+/// Names of the files in the build context
+const DOCKERFILE_NAME: &str = "Dockerfile";
+const CLI_NAME: &str = "lodestone_cli";
+const LODESTONE_DIR_NAME: &str = "lodestone";
+const CA_BUNDLE_NAME: &str = "ca-certificates.crt";
+
+/// Where the CA certificates of this machine may be, copied into the image so Lodestone Core can make TLS connections
+const CA_BUNDLE_PATHS: &[&str] = &[
+    "/etc/ssl/certs/ca-certificates.crt",
+    "/etc/pki/tls/certs/ca-bundle.crt",
+    "/etc/ssl/cert.pem",
+];
+
+const HEALTHCHECK_INTERVAL: Duration = Duration::from_secs(30);
+const HEALTHCHECK_TIMEOUT: Duration = Duration::from_secs(10);
+/// Lodestone Core may still be setting up its instances for a while after it starts
+const HEALTHCHECK_START_PERIOD: Duration = Duration::from_secs(60);
+const HEALTHCHECK_RETRIES: u32 = 3;
+
+/// What goes into the image
+#[derive(Debug, Clone)]
+pub struct ImageSpec {
+    pub version: VersionWithV,
+    pub channel: Channel,
+    pub base_image: String,
+    /// The name the image is loaded as, e.g. `lodestone:v0.5.1`
+    pub tag: String,
+    /// The port the API of Lodestone Core listens on
+    pub core_port: u16,
+}
+
+impl ImageSpec {
+    /// The global options the cli runs with in the container
+    fn cli_options(&self) -> Vec<String> {
+        let mut options = vec!["--non-interactive".to_string()];
+        if self.core_port != health::DEFAULT_CORE_PORT {
+            options.push(format!("--core-port={}", self.core_port));
+        }
+        options
+    }
+
+    /// The command the container runs, the cli running the version of Lodestone Core baked into the image
+    fn entrypoint(&self) -> Vec<String> {
+        let mut command = vec![IMAGE_CLI_PATH.to_string()];
+        command.extend(self.cli_options());
+        command.extend(["run".to_string(), "--skip-update-check".to_string()]);
+        command
+    }
+
+    /// Checks the API of Lodestone Core is ready with the cli, so the image needs no extra tools
+    fn healthcheck(&self) -> Vec<String> {
+        let mut command = vec![IMAGE_CLI_PATH.to_string()];
+        command.extend(self.cli_options());
+        command.push("health".to_string());
+        command
+    }
+}
+
+fn dockerfile(spec: &ImageSpec, has_ca_bundle: bool) -> String {
+    let ca_bundle = if has_ca_bundle {
+        format!("COPY {CA_BUNDLE_NAME} {IMAGE_CA_BUNDLE_PATH}\n")
+    } else {
+        String::new()
+    };
+    format!(
+        "# Generated by lodestone_cli container build, build it with 'docker build -t {tag} .'\n\
+        # Lodestone Core gets {shutdown}s to stop its instances, run the container with '--stop-timeout {stop}'\n\
+        FROM {base_image}\n\
+        COPY {CLI_NAME} {IMAGE_CLI_PATH}\n\
+        COPY {LODESTONE_DIR_NAME}/ {IMAGE_LODESTONE_PATH}/\n\
+        {ca_bundle}\
+        ENV LODESTONE_PATH={IMAGE_LODESTONE_PATH}\n\
+        VOLUME [\"{IMAGE_LODESTONE_PATH}\"]\n\
+        EXPOSE {core_port}\n\
+        STOPSIGNAL SIGTERM\n\
+        HEALTHCHECK --interval={interval}s --timeout={timeout}s --start-period={start_period}s --retries={HEALTHCHECK_RETRIES} \\\n    CMD {healthcheck}\n\
+        ENTRYPOINT {entrypoint}\n",
+        tag = spec.tag,
+        base_image = spec.base_image,
+        shutdown = crate::run_core::DEFAULT_SHUTDOWN_TIMEOUT.as_secs(),
+        stop = (crate::run_core::DEFAULT_SHUTDOWN_TIMEOUT + crate::daemon::STOP_MARGIN).as_secs(),
+        interval = HEALTHCHECK_INTERVAL.as_secs(),
+        timeout = HEALTHCHECK_TIMEOUT.as_secs(),
+        start_period = HEALTHCHECK_START_PERIOD.as_secs(),
+        core_port = spec.core_port,
+        healthcheck = json!(spec.healthcheck()),
+        entrypoint = json!(spec.entrypoint()),
+    )
+}
+
+#[cfg(unix)]
+fn make_executable(path: &Path) -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o755))?;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn make_executable(_path: &Path) -> Result<()> {
+    Ok(())
+}
+
+/// Writes a Docker build context to `dir`: a Dockerfile, the cli, and `LODESTONE_PATH` with `spec.version` installed
+///
+/// `dir` must be empty or not exist yet. Returns the path of the Dockerfile
+pub async fn write_context(
+    dir: &Path,
+    spec: &ImageSpec,
+    source: &ReleaseSource,
+    verify: &VerifyOptions,
+) -> Result<PathBuf> {
+    if dir
+        .read_dir()
+        .is_ok_and(|mut entries| entries.next().is_some())
+    {
+        return Err(eyre!(
+            "{} is not empty, pick another directory or delete it",
+            dir.display()
+        ));
+    }
+    let existed = dir.exists();
+    std::fs::create_dir_all(dir).with_context(|| format!("Failed to create {}", dir.display()))?;
+    let result = fill_context(dir, spec, source, verify).await;
+    if result.is_err() {
+        // leave the directory as it was, so the build can be retried
+        let _ = std::fs::remove_dir_all(dir);
+        if existed {
+            let _ = std::fs::create_dir(dir);
+        }
+    }
+    result
+}
+
+async fn fill_context(
+    dir: &Path,
+    spec: &ImageSpec,
+    source: &ReleaseSource,
+    verify: &VerifyOptions,
+) -> Result<PathBuf> {
+    let cli_path = dir.join(CLI_NAME);
+    std::fs::copy(std::env::current_exe()?, &cli_path).context("Failed to copy the cli")?;
+    make_executable(&cli_path)?;
+
+    let lodestone_path = dir.join(LODESTONE_DIR_NAME);
+    let (executable_path, executable_name) =
+        download_release(source, &spec.version, &lodestone_path, verify).await?;
+    let _ = std::fs::remove_dir_all(lodestone_path.join("tmp"));
+    make_executable(&executable_path)?;
+    Metadata {
+        current_version: spec.version.clone(),
+        last_updated: Utc::now().to_string(),
+        executable_name,
+        previous: None,
+        pending_health_check: false,
+        failed_versions: Vec::new(),
+        channel: spec.channel,
+        constraint: None,
+        source: source.clone(),
+    }
+    .write_metadata(&metadata::metadata_path(&lodestone_path))
+    .await?;
+
+    let ca_bundle = CA_BUNDLE_PATHS
+        .iter()
+        .map(Path::new)
+        .find(|path| path.is_file());
+    match ca_bundle {
+        Some(path) => {
+            std::fs::copy(path, dir.join(CA_BUNDLE_NAME))
+                .with_context(|| format!("Failed to copy {}", path.display()))?;
+        }
+        None => warn!(
+            "Couldn't find the CA certificates of this machine, Lodestone Core won't be able to make TLS connections unless the base image has them"
+        ),
+    }
+
+    let dockerfile_path = dir.join(DOCKERFILE_NAME);
+    std::fs::write(&dockerfile_path, dockerfile(spec, ca_bundle.is_some()))
+        .context("Failed to write the Dockerfile")?;
+    Ok(dockerfile_path)
+}
+
+/// Hashes everything written through it
+struct HashingWriter<W> {
+    inner: W,
+    hasher: Sha256,
+    size: u64,
+}
+
+impl<W: Write> HashingWriter<W> {
+    fn new(inner: W) -> Self {
+        HashingWriter {
+            inner,
+            hasher: Sha256::new(),
+            size: 0,
+        }
+    }
+
+    /// The inner writer, the `sha256:` digest and the size of what was written
+    fn finish(self) -> (W, String, u64) {
+        let digest = format!("sha256:{}", util::to_hex(&self.hasher.finalize()));
+        (self.inner, digest, self.size)
+    }
+}
+
+impl<W: Write> Write for HashingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.hasher.update(&buf[..written]);
+        self.size += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Writes a layer into `blobs_dir` holding what the Dockerfile copies from the context in `context_dir`
+///
+/// Returns its descriptor and its diff id, the digest of the uncompressed layer
+fn write_layer(context_dir: &Path, blobs_dir: &Path) -> Result<(Descriptor, String)> {
+    let mut files = vec![(
+        context_dir.join(CLI_NAME),
+        IMAGE_CLI_PATH.to_string(),
+        0o755,
+    )];
+    let lodestone_path = context_dir.join(LODESTONE_DIR_NAME);
+    for entry in std::fs::read_dir(lodestone_path)? {
+        let entry = entry?;
+        if !entry.file_type()?.is_file() {
+            continue;
+        }
+        let name = entry.file_name().to_string_lossy().into_owned();
+        let mode = if crate::update_manager::installed::is_core_executable(&name) {
+            0o755
+        } else {
+            0o644
+        };
+        files.push((entry.path(), format!("{IMAGE_LODESTONE_PATH}/{name}"), mode));
+    }
+    let ca_bundle = context_dir.join(CA_BUNDLE_NAME);
+    if ca_bundle.is_file() {
+        files.push((ca_bundle, IMAGE_CA_BUNDLE_PATH.to_string(), 0o644));
+    }
+
+    let partial_path = blobs_dir.join("layer.partial");
+    let compressed = HashingWriter::new(File::create(&partial_path)?);
+    let mut builder = tar::Builder::new(HashingWriter::new(GzEncoder::new(
+        compressed,
+        Compression::default(),
+    )));
+    let mtime = Utc::now().timestamp().max(0) as u64;
+    let mut dirs = HashSet::new();
+    // LODESTONE_PATH is created even if the release left nothing but the executable in it
+    for path in std::iter::once(IMAGE_LODESTONE_PATH).chain(files.iter().map(|(_, path, _)| {
+        Path::new(path)
+            .parent()
+            .and_then(Path::to_str)
+            .unwrap_or("/")
+    })) {
+        let mut ancestors: Vec<_> = Path::new(path)
+            .ancestors()
+            .filter_map(|dir| dir.strip_prefix("/").ok())
+            .filter(|dir| !dir.as_os_str().is_empty())
+            .map(Path::to_path_buf)
+            .collect();
+        ancestors.reverse();
+        for dir in ancestors {
+            if dirs.insert(dir.clone()) {
+                let mut header = tar::Header::new_gnu();
+                header.set_entry_type(tar::EntryType::Directory);
+                header.set_size(0);
+                header.set_mode(0o755);
+                header.set_mtime(mtime);
+                builder.append_data(&mut header, &dir, std::io::empty())?;
+            }
+        }
+    }
+    for (source, path, mode) in &files {
+        let file =
+            File::open(source).with_context(|| format!("Failed to read {}", source.display()))?;
+        let mut header = tar::Header::new_gnu();
+        header.set_size(file.metadata()?.len());
+        header.set_mode(*mode);
+        header.set_mtime(mtime);
+        builder.append_data(&mut header, path.trim_start_matches('/'), file)?;
+    }
+    let (encoder, diff_id, _) = builder.into_inner()?.finish();
+    let (mut file, digest, size) = encoder.finish()?.finish();
+    file.flush()?;
+    std::fs::rename(&partial_path, registry::blob_path(blobs_dir, &digest)?)?;
+    Ok((
+        Descriptor {
+            media_type: registry::OCI_LAYER_GZIP.to_string(),
+            digest,
+            size,
+            platform: None,
+            annotations: BTreeMap::new(),
+        },
+        diff_id,
+    ))
+}
+
+/// Writes `bytes` as a blob into `blobs_dir`, returning its descriptor
+fn write_blob(blobs_dir: &Path, media_type: &str, bytes: &[u8]) -> Result<Descriptor> {
+    let digest = format!("sha256:{}", util::to_hex(&Sha256::digest(bytes)));
+    std::fs::write(registry::blob_path(blobs_dir, &digest)?, bytes)?;
+    Ok(Descriptor {
+        media_type: media_type.to_string(),
+        digest,
+        size: bytes.len() as u64,
+        platform: None,
+        annotations: BTreeMap::new(),
+    })
+}
+
+/// Extends the config of the base image with what the Dockerfile sets
+fn image_config(mut config: Value, spec: &ImageSpec, diff_id: &str, created: &str) -> Value {
+    let settings = &mut config["config"];
+    if !settings.is_object() {
+        *settings = json!({});
+    }
+    let mut env: Vec<Value> = settings["Env"]
+        .as_array()
+        .cloned()
+        .unwrap_or_default()
+        .into_iter()
+        .filter(|var| {
+            !var.as_str()
+                .is_some_and(|var| var.starts_with("LODESTONE_PATH="))
+        })
+        .collect();
+    env.push(json!(format!("LODESTONE_PATH={IMAGE_LODESTONE_PATH}")));
+    settings["Env"] = json!(env);
+    settings["Entrypoint"] = json!(spec.entrypoint());
+    settings["Cmd"] = Value::Null;
+    settings["Volumes"] = json!({ IMAGE_LODESTONE_PATH: {} });
+    if !settings["ExposedPorts"].is_object() {
+        settings["ExposedPorts"] = json!({});
+    }
+    settings["ExposedPorts"][format!("{}/tcp", spec.core_port)] = json!({});
+    settings["StopSignal"] = json!("SIGTERM");
+    // not part of the OCI spec, but Docker and Podman read it
+    settings["Healthcheck"] = json!({
+        "Test": std::iter::once("CMD".to_string()).chain(spec.healthcheck()).collect::<Vec<_>>(),
+        "Interval": HEALTHCHECK_INTERVAL.as_nanos() as u64,
+        "Timeout": HEALTHCHECK_TIMEOUT.as_nanos() as u64,
+        "StartPeriod": HEALTHCHECK_START_PERIOD.as_nanos() as u64,
+        "Retries": HEALTHCHECK_RETRIES,
+    });
+
+    if !config["rootfs"].is_object() {
+        config["rootfs"] = json!({ "type": "layers", "diff_ids": [] });
+    }
+    match config["rootfs"]["diff_ids"].as_array_mut() {
+        Some(diff_ids) => diff_ids.push(json!(diff_id)),
+        None => config["rootfs"]["diff_ids"] = json!([diff_id]),
+    }
+    let history = json!({ "created": created, "created_by": "lodestone_cli container build" });
+    match config["history"].as_array_mut() {
+        Some(entries) => entries.push(history),
+        None => config["history"] = json!([history]),
+    }
+    config["created"] = json!(created);
+    config
+}
+
+/// Adds every file under `dir` to `builder`, with paths relative to `root`
+fn append_dir<W: Write>(builder: &mut tar::Builder<W>, root: &Path, dir: &Path) -> Result<()> {
+    let mut entries = std::fs::read_dir(dir)?.collect::<std::io::Result<Vec<_>>>()?;
+    entries.sort_by_key(|entry| entry.file_name());
+    for entry in entries {
+        let path = entry.path();
+        let name = path.strip_prefix(root)?;
+        if entry.file_type()?.is_dir() {
+            builder.append_dir(name, &path)?;
+            append_dir(builder, root, &path)?;
+        } else {
+            builder.append_path_with_name(&path, name)?;
+        }
+    }
+    Ok(())
+}
+
+/// An OCI image archive written by [`build_oci`]
+#[derive(Debug, Clone)]
+pub struct OciImage {
+    pub path: PathBuf,
+    /// The digest of the image manifest
+    pub digest: String,
+}
+
+/// Builds the image described by the context in `context_dir` into an OCI archive at `dest`, without a Docker daemon
+///
+/// The layers of the base image are pulled from its registry, the files the Dockerfile copies become one more layer.
+/// The archive also has a Docker `manifest.json`, so both `docker load` and `podman load` take it
+pub async fn build_oci(context_dir: &Path, spec: &ImageSpec, dest: &Path) -> Result<OciImage> {
+    let base: Reference = spec.base_image.parse()?;
+    let parent = match dest.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent.to_path_buf(),
+        _ => PathBuf::from("."),
+    };
+    let staging = tempfile::tempdir_in(&parent)
+        .with_context(|| format!("Failed to create a directory in {}", parent.display()))?;
+    let blobs_dir = staging.path().join("blobs").join("sha256");
+    std::fs::create_dir_all(&blobs_dir)?;
+
+    let pulled = Registry::new(base).pull(&blobs_dir).await?;
+    let (context, blobs) = (context_dir.to_path_buf(), blobs_dir.clone());
+    let (layer, diff_id) = tokio::task::spawn_blocking(move || write_layer(&context, &blobs))
+        .await?
+        .context("Failed to write the image layer")?;
+
+    let created = Utc::now().to_rfc3339();
+    let config = image_config(pulled.config, spec, &diff_id, &created);
+    let config = write_blob(
+        &blobs_dir,
+        registry::OCI_CONFIG,
+        &serde_json::to_vec(&config)?,
+    )?;
+    let mut layers = pulled.layers;
+    layers.push(layer);
+    let manifest = ImageManifest {
+        schema_version: 2,
+        media_type: Some(registry::OCI_MANIFEST.to_string()),
+        config: config.clone(),
+        layers: layers.clone(),
+    };
+    let mut manifest = write_blob(
+        &blobs_dir,
+        registry::OCI_MANIFEST,
+        &serde_json::to_vec(&manifest)?,
+    )?;
+
+    let (name, tag) = spec.tag.rsplit_once(':').unwrap_or((&spec.tag, "latest"));
+    manifest.annotations = BTreeMap::from([
+        (
+            "org.opencontainers.image.ref.name".to_string(),
+            tag.to_string(),
+        ),
+        (
+            "io.containerd.image.name".to_string(),
+            format!("{name}:{tag}"),
+        ),
+    ]);
+    let layout_files = [
+        ("oci-layout", json!({ "imageLayoutVersion": "1.0.0" })),
+        (
+            "index.json",
+            json!({
+                "schemaVersion": 2,
+                "mediaType": registry::OCI_INDEX,
+                "manifests": [manifest],
+            }),
+        ),
+        // what `docker load` reads before Docker 25
+        (
+            "manifest.json",
+            json!([{
+                "Config": format!("blobs/sha256/{}", config.digest.trim_start_matches("sha256:")),
+                "RepoTags": [format!("{name}:{tag}")],
+                "Layers": layers
+                    .iter()
+                    .map(|layer| format!("blobs/sha256/{}", layer.digest.trim_start_matches("sha256:")))
+                    .collect::<Vec<_>>(),
+            }]),
+        ),
+    ];
+    for (file_name, contents) in &layout_files {
+        std::fs::write(
+            staging.path().join(file_name),
+            serde_json::to_vec(contents)?,
+        )?;
+    }
+
+    let (root, archive_path) = (staging.path().to_path_buf(), dest.to_path_buf());
+    tokio::task::spawn_blocking(move || -> Result<()> {
+        let partial_path = archive_path.with_extension("partial");
+        let result = (|| -> Result<()> {
+            let mut builder = tar::Builder::new(File::create(&partial_path)?);
+            append_dir(&mut builder, &root, &root)?;
+            builder.into_inner()?.flush()?;
+            Ok(())
+        })();
+        if let Err(e) = result {
+            let _ = std::fs::remove_file(&partial_path);
+            return Err(e);
+        }
+        std::fs::rename(&partial_path, &archive_path)?;
+        Ok(())
+    })
+    .await?
+    .context("Failed to write the image archive")?;
+
+    Ok(OciImage {
+        path: dest.to_path_buf(),
+        digest: manifest.digest,
+    })
+}
+
+pub async fn build_command(
+    dir: &Path,
+    version: Option<VersionSpec>,
+    base_image: String,
+    tag: Option<String>,
+    oci: Option<&Path>,
+    args: &Args,
+) -> i32 {
+    if env::consts::OS != "linux" || executable_name_without_version().is_none() {
+        error!(
+            "Container images can only be built on Linux, the image gets the cli and Lodestone Core built for this machine ({} {})",
+            env::consts::OS,
+            env::consts::ARCH
+        );
+        return exit_code::FAILURE;
+    }
+    let channel = args.channel.unwrap_or_default();
+    let version = match version {
+        Some(VersionSpec::Exact(version)) => version,
+        Some(VersionSpec::Req(req)) => match resolve_version_requirement(&req, args).await {
+            Ok(version) => version,
+            Err(code) => return code,
+        },
+        None => match versions::get_latest_release(&args.release_source(), channel, None).await {
+            Ok(version) => version,
+            Err(e) => {
+                error!("Failed to find the latest version of Lodestone Core: {}", e);
+                return exit_code::FAILURE;
+            }
+        },
+    };
+    let spec = ImageSpec {
+        tag: tag.unwrap_or_else(|| format!("lodestone:{version}")),
+        version,
+        channel,
+        base_image,
+        core_port: args.core_port(),
+    };
+    info!(
+        "Writing a build context for Lodestone Core {} to {}",
+        spec.version.bold().blue(),
+        dir.display().bold().blue()
+    );
+    let dockerfile =
+        match write_context(dir, &spec, &args.release_source(), &args.verify_options()).await {
+            Ok(dockerfile) => dockerfile,
+            Err(e) => {
+                error!("Failed to write the build context: {e:#}");
+                return exit_code::FAILURE;
+            }
+        };
+    info!(
+        "Wrote {}, build the image with '{}'",
+        dockerfile.display().bold().blue(),
+        format!("docker build -t {} {}", spec.tag, dir.display())
+            .bold()
+            .blue()
+    );
+    output::result(
+        "container",
+        json!({
+            "context": dir,
+            "dockerfile": dockerfile,
+            "version": spec.version,
+            "base_image": spec.base_image,
+            "tag": spec.tag,
+        }),
+    );
+
+    let Some(oci) = oci else {
+        return exit_code::SUCCESS;
+    };
+    match build_oci(dir, &spec, oci).await {
+        Ok(image) => {
+            info!(
+                "Built {} into {}, load it with '{}'",
+                spec.tag.bold().blue(),
+                image.path.display().bold().blue(),
+                format!("docker load -i {}", image.path.display())
+                    .bold()
+                    .blue()
+            );
+            output::result("oci", json!({ "path": image.path, "digest": image.digest }));
+            exit_code::SUCCESS
+        }
+        Err(e) => {
+            error!("Failed to build the OCI image: {e:#}");
+            exit_code::FAILURE
+        }
+    }
+}
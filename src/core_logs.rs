@@ -0,0 +1,543 @@
+use chrono::{DateTime, NaiveDate, Utc};
+use color_eyre::eyre::{Context, Result};
+use std::fs::File;
+use std::io::{BufRead, BufReader, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::Duration;
+
+use color_eyre::owo_colors::OwoColorize;
+
+use crate::output::OutputFormat;
+use crate::{error, exit_code, info, output, warn};
+
+/// Directory under `LODESTONE_PATH` the output of Lodestone Core is captured in
+pub const LOGS_DIR_NAME: &str = "logs";
+
+/// The log Lodestone Core is currently writing to, rotated logs are named `lodestone_core-<timestamp>.log`
+const CURRENT_LOG_NAME: &str = "lodestone_core.log";
+const ROTATED_LOG_PREFIX: &str = "lodestone_core-";
+const ROTATED_LOG_EXTENSION: &str = ".log";
+const ROTATED_LOG_TIME_FORMAT: &str = "%Y%m%d-%H%M%S%.3f";
+
+pub const DEFAULT_MAX_SIZE_MB: u64 = 10;
+pub const DEFAULT_MAX_FILES: usize = 5;
+pub const DEFAULT_MAX_AGE_DAYS: u64 = 30;
+
+/// How often `logs --follow` checks for new lines
+const FOLLOW_POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+/// How the output of Lodestone Core is captured
+#[derive(Debug, Clone)]
+pub struct LogPolicy {
+    pub dir: PathBuf,
+    /// The current log is rotated once it is larger than this, in bytes, or at midnight UTC
+    pub max_size: u64,
+    /// How many rotated logs are kept
+    pub max_files: usize,
+    /// Rotated logs older than this are deleted
+    pub max_age: Duration,
+    /// Whether the output is also printed by the cli, rather than only written to the log
+    pub echo: bool,
+}
+
+static POLICY: OnceLock<LogPolicy> = OnceLock::new();
+
+/// Turns on log capture, must be called before the core is started to take effect
+pub fn configure(policy: LogPolicy) {
+    let _ = POLICY.set(policy);
+}
+
+/// How the output of Lodestone Core is captured, `None` if it isn't
+pub fn policy() -> Option<&'static LogPolicy> {
+    POLICY.get()
+}
+
+pub fn logs_dir(lodestone_path: &Path) -> PathBuf {
+    lodestone_path.join(LOGS_DIR_NAME)
+}
+
+/// Which output of Lodestone Core a line comes from
+#[derive(Debug, Clone, Copy)]
+pub enum Stream {
+    Stdout,
+    Stderr,
+}
+
+impl Stream {
+    fn as_str(self) -> &'static str {
+        match self {
+            Stream::Stdout => "stdout",
+            Stream::Stderr => "stderr",
+        }
+    }
+}
+
+/// The current log file, rotating itself as lines are written
+pub struct LogFile {
+    policy: LogPolicy,
+    file: File,
+    size: u64,
+    /// The day the current log was started on
+    day: NaiveDate,
+}
+
+impl LogFile {
+    pub fn open(policy: &LogPolicy) -> Result<LogFile> {
+        std::fs::create_dir_all(&policy.dir)
+            .with_context(|| format!("Failed to create {}", policy.dir.display()))?;
+        let path = policy.dir.join(CURRENT_LOG_NAME);
+        let file = File::options()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .with_context(|| format!("Failed to open {}", path.display()))?;
+        let metadata = file.metadata()?;
+        let day = metadata
+            .created()
+            .or_else(|_| metadata.modified())
+            .map_or_else(|_| Utc::now(), DateTime::<Utc>::from)
+            .date_naive();
+        let mut log = LogFile {
+            policy: policy.clone(),
+            file,
+            size: metadata.len(),
+            day,
+        };
+        if log.needs_rotation() {
+            log.rotate()?;
+        }
+        Ok(log)
+    }
+
+    fn needs_rotation(&self) -> bool {
+        self.size > 0 && (self.size >= self.policy.max_size || self.day != Utc::now().date_naive())
+    }
+
+    /// Moves the current log aside, starts a new one and deletes rotated logs past the retention limits
+    fn rotate(&mut self) -> Result<()> {
+        let path = self.policy.dir.join(CURRENT_LOG_NAME);
+        // logs rotated within the same millisecond get a `-<n>` suffix, higher than any taken even if older ones were pruned
+        let timestamp = Utc::now().format(ROTATED_LOG_TIME_FORMAT).to_string();
+        let taken = rotated_logs(&self.policy.dir)?
+            .into_iter()
+            .filter(|log| log.time_string == timestamp)
+            .map(|log| log.suffix)
+            .max();
+        let rotated_path = self.policy.dir.join(match taken {
+            Some(suffix) => format!(
+                "{ROTATED_LOG_PREFIX}{timestamp}-{}{ROTATED_LOG_EXTENSION}",
+                suffix + 1
+            ),
+            None => format!("{ROTATED_LOG_PREFIX}{timestamp}{ROTATED_LOG_EXTENSION}"),
+        });
+        std::fs::rename(&path, rotated_path).context("Failed to rotate the log")?;
+        self.file = File::options()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .with_context(|| format!("Failed to open {}", path.display()))?;
+        self.size = 0;
+        self.day = Utc::now().date_naive();
+        prune(&self.policy)
+    }
+
+    /// Appends a line of output, prefixed with the time and the stream it comes from
+    pub fn write_line(&mut self, stream: Stream, line: &str) -> Result<()> {
+        if self.needs_rotation() {
+            self.rotate()?;
+        }
+        let line = format!(
+            "{} [{}] {}\n",
+            Utc::now().format("%Y-%m-%dT%H:%M:%S%.3fZ"),
+            stream.as_str(),
+            output::strip_ansi(line.trim_end_matches(['\r', '\n']))
+        );
+        self.file.write_all(line.as_bytes())?;
+        self.size += line.len() as u64;
+        Ok(())
+    }
+}
+
+struct RotatedLog {
+    path: PathBuf,
+    time: DateTime<Utc>,
+    time_string: String,
+    suffix: u32,
+}
+
+/// Rotated logs, newest first
+fn rotated_logs(dir: &Path) -> Result<Vec<RotatedLog>> {
+    let mut logs = Vec::new();
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().into_owned();
+        let Some(stem) = name
+            .strip_prefix(ROTATED_LOG_PREFIX)
+            .and_then(|name| name.strip_suffix(ROTATED_LOG_EXTENSION))
+        else {
+            continue;
+        };
+        let (time_string, suffix) = match stem
+            .split_once('-')
+            .and_then(|(_, rest)| rest.split_once('-'))
+        {
+            Some((_, suffix)) => (&stem[..stem.len() - suffix.len() - 1], suffix.parse().ok()),
+            None => (stem, Some(1)),
+        };
+        let time = chrono::NaiveDateTime::parse_from_str(time_string, ROTATED_LOG_TIME_FORMAT);
+        if let (Ok(time), Some(suffix)) = (time, suffix) {
+            logs.push(RotatedLog {
+                path: entry.path(),
+                time: time.and_utc(),
+                time_string: time_string.to_string(),
+                suffix,
+            });
+        }
+    }
+    logs.sort_by_key(|log| std::cmp::Reverse((log.time, log.suffix)));
+    Ok(logs)
+}
+
+/// Deletes rotated logs beyond `max_files` or older than `max_age`
+fn prune(policy: &LogPolicy) -> Result<()> {
+    let max_age = chrono::Duration::from_std(policy.max_age).unwrap_or(chrono::Duration::MAX);
+    for (index, log) in rotated_logs(&policy.dir)?.into_iter().enumerate() {
+        if index >= policy.max_files || Utc::now() - log.time > max_age {
+            std::fs::remove_file(&log.path)
+                .with_context(|| format!("Failed to delete {}", log.path.display()))?;
+        }
+    }
+    Ok(())
+}
+
+/// Copies the lines of `reader` to `echo` and to `log`, until the stream is closed
+pub fn tee(
+    reader: impl Read,
+    mut echo: impl Write,
+    stream: Stream,
+    log: &Arc<Mutex<LogFile>>,
+) -> Result<()> {
+    let mut reader = BufReader::new(reader);
+    let mut line = Vec::new();
+    let mut log_failed = false;
+    loop {
+        line.clear();
+        if reader.read_until(b'\n', &mut line)? == 0 {
+            return Ok(());
+        }
+        let _ = echo.write_all(&line);
+        let _ = echo.flush();
+        if let Err(e) = log
+            .lock()
+            .unwrap()
+            .write_line(stream, &String::from_utf8_lossy(&line))
+        {
+            // keep echoing the output, but only complain once
+            if !log_failed {
+                warn!("Failed to write the log of Lodestone Core: {}", e);
+                log_failed = true;
+            }
+        }
+    }
+}
+
+fn print_line(line: &str) {
+    if output::is_machine_readable() {
+        output::result("line", line);
+    } else {
+        println!("{line}");
+    }
+}
+
+/// The last `count` lines of the logs in `dir`, oldest first, reading rotated logs if the current one is too short
+pub fn tail(dir: &Path, count: usize) -> Result<Vec<String>> {
+    let mut paths = vec![dir.join(CURRENT_LOG_NAME)];
+    paths.extend(rotated_logs(dir)?.into_iter().map(|log| log.path));
+    let mut lines = Vec::new();
+    for path in paths {
+        if lines.len() >= count {
+            break;
+        }
+        let contents = match std::fs::read(&path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e).with_context(|| format!("Failed to read {}", path.display())),
+        };
+        let contents = String::from_utf8_lossy(&contents);
+        let mut file_lines: Vec<String> = contents
+            .lines()
+            .rev()
+            .take(count - lines.len())
+            .map(str::to_string)
+            .collect();
+        file_lines.reverse();
+        file_lines.append(&mut lines);
+        lines = file_lines;
+    }
+    Ok(lines)
+}
+
+/// Whether `file` is no longer the file at `path`, which was moved aside and replaced when the log was rotated
+#[cfg(unix)]
+fn is_replaced(file: &File, path: &Path) -> bool {
+    use std::os::unix::fs::MetadataExt;
+    match (file.metadata(), std::fs::metadata(path)) {
+        (Ok(open), Ok(current)) => (open.dev(), open.ino()) != (current.dev(), current.ino()),
+        // between moving the log aside and creating the new one, the old one may still be written to
+        _ => false,
+    }
+}
+
+#[cfg(not(unix))]
+fn is_replaced(_file: &File, _path: &Path) -> bool {
+    false
+}
+
+/// Reads the lines appended to the current log in a directory, following it across rotations
+struct Follower {
+    path: PathBuf,
+    file: Option<File>,
+    position: u64,
+    /// The start of a line that isn't complete yet
+    pending: String,
+}
+
+impl Follower {
+    /// Starts at the end of the current log in `dir`, what is already there was printed by `tail`
+    fn new(dir: &Path) -> Result<Follower> {
+        let path = dir.join(CURRENT_LOG_NAME);
+        let mut file = Follower::open(&path)?;
+        let position = match file.as_mut() {
+            Some(file) => file.seek(SeekFrom::End(0))?,
+            None => 0,
+        };
+        Ok(Follower {
+            path,
+            file,
+            position,
+            pending: String::new(),
+        })
+    }
+
+    fn open(path: &Path) -> Result<Option<File>> {
+        match File::open(path) {
+            Ok(file) => Ok(Some(file)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e).with_context(|| format!("Failed to read {}", path.display())),
+        }
+    }
+
+    /// The lines written since the last call
+    fn poll(&mut self) -> Result<Vec<String>> {
+        // checked before reading, so no line written before the rotation is missed. A log that shrank was truncated,
+        // or rotated where files can't be told apart
+        let rotated = self.file.as_ref().is_some_and(|file| {
+            is_replaced(file, &self.path)
+                || std::fs::metadata(&self.path)
+                    .is_ok_and(|metadata| metadata.len() < self.position)
+        });
+        let mut lines = Vec::new();
+        if let Some(file) = self.file.as_mut() {
+            let mut new_data = Vec::new();
+            self.position += file.read_to_end(&mut new_data)? as u64;
+            self.pending.push_str(&String::from_utf8_lossy(&new_data));
+            while let Some(end) = self.pending.find('\n') {
+                lines.push(self.pending[..end].trim_end_matches('\r').to_string());
+                self.pending.drain(..=end);
+            }
+        }
+        if self.file.is_none() || rotated {
+            self.file = Follower::open(&self.path)?;
+            self.position = 0;
+        }
+        Ok(lines)
+    }
+}
+
+/// Prints new lines of the current log in `dir` as they are written, following it across rotations. Never returns unless reading fails
+pub async fn follow(dir: &Path) -> Result<()> {
+    let mut follower = Follower::new(dir)?;
+    loop {
+        for line in follower.poll()? {
+            print_line(&line);
+        }
+        tokio::time::sleep(FOLLOW_POLL_INTERVAL).await;
+    }
+}
+
+pub async fn logs_command(lodestone_path: &Path, lines: usize, follow: bool) -> i32 {
+    if follow && output::format() == OutputFormat::Json {
+        error!(
+            "'{}' never finishes, use '{}' to get its lines as they come",
+            "--follow".bold().blue(),
+            "--output ndjson".bold().blue()
+        );
+        return exit_code::FAILURE;
+    }
+    let dir = logs_dir(lodestone_path);
+    if !dir.is_dir() {
+        error!(
+            "No logs of Lodestone Core under {}",
+            lodestone_path.display().bold().blue()
+        );
+        info!(
+            "Pass '{}' to '{}' to capture them",
+            "--capture-logs".bold().blue(),
+            "run".bold().blue()
+        );
+        return exit_code::FAILURE;
+    }
+    match tail(&dir, lines) {
+        Ok(lines) if output::is_machine_readable() => output::result("lines", lines),
+        Ok(lines) => lines.iter().for_each(|line| println!("{line}")),
+        Err(e) => {
+            error!("Failed to read the logs: {}", e);
+            return exit_code::FAILURE;
+        }
+    }
+    if follow {
+        if let Err(e) = self::follow(&dir).await {
+            error!("Failed to follow the logs: {}", e);
+            return exit_code::FAILURE;
+        }
+    }
+    exit_code::SUCCESS
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy(name: &str, max_size: u64, max_files: usize) -> LogPolicy {
+        let dir = std::env::temp_dir()
+            .join(format!("lodestone_cli_test_{}", std::process::id()))
+            .join(name);
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        LogPolicy {
+            dir,
+            max_size,
+            max_files,
+            max_age: Duration::from_secs(30 * 24 * 60 * 60),
+            echo: false,
+        }
+    }
+
+    fn rotated_log_name(time: DateTime<Utc>) -> String {
+        format!(
+            "{ROTATED_LOG_PREFIX}{}{ROTATED_LOG_EXTENSION}",
+            time.format(ROTATED_LOG_TIME_FORMAT)
+        )
+    }
+
+    fn file_names(dir: &Path) -> Vec<String> {
+        let mut names: Vec<_> = std::fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn rotates_by_size() {
+        let policy = policy("rotate_size", 1, 10);
+        let mut log = LogFile::open(&policy).unwrap();
+        for line in ["one", "two", "three", "four", "five"] {
+            log.write_line(Stream::Stdout, line).unwrap();
+        }
+        // every line but the first rotates the log, even within the same millisecond
+        assert_eq!(rotated_logs(&policy.dir).unwrap().len(), 4);
+        let lines = tail(&policy.dir, 5).unwrap();
+        let lines: Vec<_> = lines
+            .iter()
+            .map(|line| line.rsplit_once(' ').unwrap().1)
+            .collect();
+        assert_eq!(lines, ["one", "two", "three", "four", "five"]);
+    }
+
+    #[test]
+    fn keeps_writing_below_the_size_limit() {
+        let policy = policy("no_rotation", 1024, 10);
+        let mut log = LogFile::open(&policy).unwrap();
+        log.write_line(Stream::Stdout, "\u{1b}[32mready\u{1b}[0m\n")
+            .unwrap();
+        log.write_line(Stream::Stderr, "warning").unwrap();
+        assert_eq!(file_names(&policy.dir), [CURRENT_LOG_NAME]);
+        let lines = tail(&policy.dir, 10).unwrap();
+        assert!(lines[0].ends_with(" [stdout] ready"));
+        assert!(lines[1].ends_with(" [stderr] warning"));
+    }
+
+    #[test]
+    fn prunes_by_count_and_age() {
+        let policy = policy("prune", 1024, 2);
+        let now = Utc::now();
+        let names: Vec<_> = [1, 2, 3, 40]
+            .map(|days| rotated_log_name(now - chrono::Duration::days(days)))
+            .into();
+        for name in &names {
+            std::fs::write(policy.dir.join(name), "old\n").unwrap();
+        }
+        std::fs::write(policy.dir.join("unrelated.log"), "kept\n").unwrap();
+
+        prune(&policy).unwrap();
+        let mut kept = vec![
+            names[0].clone(),
+            names[1].clone(),
+            "unrelated.log".to_string(),
+        ];
+        kept.sort();
+        assert_eq!(file_names(&policy.dir), kept);
+
+        let policy = LogPolicy {
+            max_files: 10,
+            max_age: Duration::from_secs(36 * 60 * 60),
+            ..policy
+        };
+        prune(&policy).unwrap();
+        let mut kept = vec![names[0].clone(), "unrelated.log".to_string()];
+        kept.sort();
+        assert_eq!(file_names(&policy.dir), kept);
+    }
+
+    #[test]
+    fn follows_the_log_across_rotations() {
+        let policy = policy("follow", 1024, 10);
+        let path = policy.dir.join(CURRENT_LOG_NAME);
+        std::fs::write(&path, "printed by tail\n").unwrap();
+        let mut follower = Follower::new(&policy.dir).unwrap();
+        assert!(follower.poll().unwrap().is_empty());
+
+        let append = |path: &Path, data: &str| {
+            let mut file = File::options().append(true).open(path).unwrap();
+            file.write_all(data.as_bytes()).unwrap();
+        };
+        append(&path, "first\nsec");
+        assert_eq!(follower.poll().unwrap(), ["first"]);
+
+        // the new log is already longer than the old one, only its identity tells them apart
+        append(&path, "ond\n");
+        std::fs::rename(&path, policy.dir.join(rotated_log_name(Utc::now()))).unwrap();
+        std::fs::write(&path, "a line written right after the rotation\n").unwrap();
+        assert_eq!(follower.poll().unwrap(), ["second"]);
+        assert_eq!(
+            follower.poll().unwrap(),
+            ["a line written right after the rotation"]
+        );
+        assert!(follower.poll().unwrap().is_empty());
+    }
+
+    #[test]
+    fn follows_a_log_created_later() {
+        let policy = policy("follow_new", 1024, 10);
+        let mut follower = Follower::new(&policy.dir).unwrap();
+        assert!(follower.poll().unwrap().is_empty());
+        std::fs::write(policy.dir.join(CURRENT_LOG_NAME), "started\n").unwrap();
+        // the log is opened on one poll and read on the next
+        let mut lines = follower.poll().unwrap();
+        lines.extend(follower.poll().unwrap());
+        assert_eq!(lines, ["started"]);
+    }
+}
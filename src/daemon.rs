@@ -0,0 +1,605 @@
+use chrono::Utc;
+use color_eyre::eyre::{eyre, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use color_eyre::owo_colors::OwoColorize;
+
+use crate::update_manager::metadata::{metadata_path, Metadata};
+use crate::{
+    core_logs, error, exit_code, info, install_or_update, output, run_core, start_core, warn, Args,
+};
+
+/// Set in the environment of the background process `start --detach` launches
+const DETACHED_ENV: &str = "LODESTONE_CLI_DETACHED";
+
+/// Name of the file under `LODESTONE_PATH/logs` the output of the cli itself goes to when it runs in the background
+pub const DETACHED_LOG_NAME: &str = "lodestone_cli.log";
+
+/// How long `start --detach` waits for the background process to come up
+const STARTUP_TIMEOUT: Duration = Duration::from_secs(10);
+
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// How long creating the PID file waits for another command only checking whether it is held
+const LOCK_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// How much longer than its shutdown timeout `stop` gives the cli to exit, before trying harder
+pub const STOP_MARGIN: Duration = Duration::from_secs(5);
+
+/// Name of the PID file under `LODESTONE_PATH`, held locked by the process running Lodestone Core
+pub const PID_FILE_NAME: &str = ".lodestone_cli.pid";
+
+pub fn pid_path(lodestone_path: &Path) -> PathBuf {
+    lodestone_path.join(PID_FILE_NAME)
+}
+
+/// Whether this process is the background process launched by `start --detach`
+pub fn is_detached() -> bool {
+    std::env::var_os(DETACHED_ENV).is_some()
+}
+
+/// Identifies the cli process running Lodestone Core with `start`
+///
+/// The process keeps its PID file locked for as long as it runs, the lock is released by the OS however it exits. A
+/// PID file nobody holds is stale, even if its PID was reused by another process since
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PidFile {
+    pub pid: u32,
+    pub started_at: String,
+    pub detached: bool,
+}
+
+/// Whether Lodestone Core is running under `start`
+#[derive(Debug, Clone)]
+pub enum DaemonState {
+    Running(PidFile),
+    /// The PID file was left behind by a process that is gone, for example after a reboot
+    Stale(PidFile),
+    NotRunning,
+}
+
+/// The PID file of this process and its path, held open so it stays locked
+static HELD: Mutex<Option<(PathBuf, File)>> = Mutex::new(None);
+
+/// Opens the PID file at `path` and locks it, `None` if another process holds it
+///
+/// A shared lock is only taken to check whether the file is held, it keeps others from taking it while it is
+#[cfg(unix)]
+fn lock(path: &Path, shared: bool) -> std::io::Result<Option<File>> {
+    use std::os::unix::fs::MetadataExt;
+    use std::os::unix::io::AsRawFd;
+    loop {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)?;
+        let operation = if shared { libc::LOCK_SH } else { libc::LOCK_EX };
+        // SAFETY: flock has no memory safety requirements
+        if unsafe { libc::flock(file.as_raw_fd(), operation | libc::LOCK_NB) } == -1 {
+            let error = std::io::Error::last_os_error();
+            return match error.raw_os_error() {
+                Some(libc::EWOULDBLOCK) => Ok(None),
+                _ => Err(error),
+            };
+        }
+        // the process that held it may have deleted it before exiting, then the lock is on a file nobody else sees
+        let locked = file.metadata()?;
+        match std::fs::metadata(path) {
+            Ok(current) if (current.dev(), current.ino()) == (locked.dev(), locked.ino()) => {
+                return Ok(Some(file))
+            }
+            Ok(_) => continue,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Opens the PID file at `path` without letting other processes write to it, `None` if another process holds it
+///
+/// There are no shared locks, checking whether the file is held opens it like taking it
+#[cfg(windows)]
+fn lock(path: &Path, _shared: bool) -> std::io::Result<Option<File>> {
+    use std::os::windows::fs::OpenOptionsExt;
+    const FILE_SHARE_READ: u32 = 0x0000_0001;
+    const ERROR_SHARING_VIOLATION: i32 = 32;
+    match OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .share_mode(FILE_SHARE_READ)
+        .open(path)
+    {
+        Ok(file) => Ok(Some(file)),
+        Err(e) if e.raw_os_error() == Some(ERROR_SHARING_VIOLATION) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// Whether a process holds the PID file at `path`, this one included
+fn is_held(path: &Path) -> std::io::Result<bool> {
+    if HELD
+        .lock()
+        .unwrap()
+        .as_ref()
+        .is_some_and(|(held, _)| held == path)
+    {
+        return Ok(true);
+    }
+    match lock(path, true) {
+        Ok(file) => Ok(file.is_none()),
+        // the PID file was deleted by the process that held it
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+impl PidFile {
+    /// Records this process as the one running Lodestone Core under `lodestone_path`, failing if another one already is
+    pub fn create(lodestone_path: &Path) -> Result<PidFile> {
+        let path = pid_path(lodestone_path);
+        let deadline = Instant::now() + LOCK_TIMEOUT;
+        let mut file = loop {
+            if let Some(file) = lock(&path, false).context("Failed to open the PID file")? {
+                break file;
+            }
+            if Instant::now() > deadline {
+                return Err(eyre!(
+                    "Lodestone Core is already running under {}",
+                    lodestone_path.display()
+                ));
+            }
+            std::thread::sleep(POLL_INTERVAL);
+        };
+        let pid_file = PidFile {
+            pid: std::process::id(),
+            started_at: Utc::now().to_string(),
+            detached: is_detached(),
+        };
+        let contents = serde_json::to_vec_pretty(&pid_file)?;
+        file.set_len(0)
+            .and_then(|_| file.write_all(&contents))
+            .context("Failed to write the PID file")?;
+        *HELD.lock().unwrap() = Some((path, file));
+        Ok(pid_file)
+    }
+
+    /// Deletes the PID file, if this process holds it
+    pub fn remove(lodestone_path: &Path) {
+        let path = pid_path(lodestone_path);
+        let mut held = HELD.lock().unwrap();
+        if !held.as_ref().is_some_and(|(held, _)| *held == path) {
+            return;
+        }
+        let Some((_, file)) = held.take() else {
+            return;
+        };
+        // deleted while still locked, so no other process can take it over in between. Windows doesn't delete open files
+        if cfg!(windows) {
+            drop(file);
+            let _ = std::fs::remove_file(path);
+        } else {
+            let _ = std::fs::remove_file(path);
+            drop(file);
+        }
+    }
+}
+
+/// Deletes the PID file under `lodestone_path` if no process holds it
+pub fn remove_stale(lodestone_path: &Path) {
+    let path = pid_path(lodestone_path);
+    if matches!(is_held(&path), Ok(false)) {
+        let _ = std::fs::remove_file(path);
+    }
+}
+
+/// Finds the process running Lodestone Core under `lodestone_path`
+pub fn state(lodestone_path: &Path) -> Result<DaemonState> {
+    let contents = match std::fs::read_to_string(pid_path(lodestone_path)) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(DaemonState::NotRunning),
+        Err(e) => return Err(e).context("Failed to read the PID file"),
+    };
+    let pid_file: PidFile = serde_json::from_str(&contents).context("Malformed PID file")?;
+    if is_held(&pid_path(lodestone_path)).context("Failed to read the PID file")? {
+        Ok(DaemonState::Running(pid_file))
+    } else {
+        Ok(DaemonState::Stale(pid_file))
+    }
+}
+
+/// Whether the process `pid` still runs Lodestone Core under `lodestone_path`
+///
+/// Checked before signalling it, a process that exited may have left its PID to an unrelated one
+pub fn is_running(lodestone_path: &Path, pid: u32) -> bool {
+    matches!(state(lodestone_path), Ok(DaemonState::Running(pid_file)) if pid_file.pid == pid)
+}
+
+/// Launches the cli again in the background with the same arguments, returning its PID once it is running
+///
+/// Its own output goes to `LODESTONE_PATH/logs/lodestone_cli.log`
+pub fn detach(lodestone_path: &Path) -> Result<u32> {
+    let logs_dir = core_logs::logs_dir(lodestone_path);
+    std::fs::create_dir_all(&logs_dir)
+        .with_context(|| format!("Failed to create {}", logs_dir.display()))?;
+    let log_path = logs_dir.join(DETACHED_LOG_NAME);
+    let log = File::create(&log_path)
+        .with_context(|| format!("Failed to create {}", log_path.display()))?;
+
+    let mut command = Command::new(std::env::current_exe()?);
+    command
+        .args(std::env::args_os().skip(1))
+        .env(DETACHED_ENV, "1")
+        .env("LODESTONE_PATH", lodestone_path)
+        .stdin(Stdio::null())
+        .stdout(log.try_clone()?)
+        .stderr(log);
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::CommandExt;
+        // SAFETY: setsid is async-signal-safe
+        unsafe {
+            command.pre_exec(|| {
+                // leave the terminal's session, so closing the terminal doesn't stop it
+                if libc::setsid() == -1 {
+                    return Err(std::io::Error::last_os_error());
+                }
+                Ok(())
+            });
+        }
+    }
+    #[cfg(windows)]
+    {
+        use std::os::windows::process::CommandExt;
+        const DETACHED_PROCESS: u32 = 0x0000_0008;
+        const CREATE_NEW_PROCESS_GROUP: u32 = 0x0000_0200;
+        command.creation_flags(DETACHED_PROCESS | CREATE_NEW_PROCESS_GROUP);
+    }
+    let mut child = command
+        .spawn()
+        .context("Failed to start the background process")?;
+
+    let deadline = Instant::now() + STARTUP_TIMEOUT;
+    loop {
+        if let Some(status) = child.try_wait()? {
+            return Err(eyre!(
+                "The background process exited with {status}, see {} for why",
+                log_path.display()
+            ));
+        }
+        if let Ok(DaemonState::Running(pid_file)) = state(lodestone_path) {
+            if pid_file.pid == child.id() {
+                return Ok(pid_file.pid);
+            }
+        }
+        if Instant::now() > deadline {
+            return Err(eyre!(
+                "The background process didn't start in time, see {} for why",
+                log_path.display()
+            ));
+        }
+        std::thread::sleep(POLL_INTERVAL);
+    }
+}
+
+/// Asks the process `pid` to stop Lodestone Core, as if it received SIGTERM
+///
+/// With `force`, the process is killed outright. There are no signals on Windows, so it always is
+pub fn signal_stop(pid: u32, force: bool) -> Result<()> {
+    #[cfg(unix)]
+    {
+        let signal = if force { libc::SIGKILL } else { libc::SIGTERM };
+        // SAFETY: kill has no memory safety requirements
+        if unsafe { libc::kill(pid as libc::pid_t, signal) } == -1 {
+            return Err(std::io::Error::last_os_error().into());
+        }
+    }
+    #[cfg(windows)]
+    {
+        let _ = force;
+        let status = Command::new("taskkill")
+            .args(["/PID", &pid.to_string(), "/T", "/F"])
+            .stdout(Stdio::null())
+            .status()?;
+        if !status.success() {
+            return Err(eyre!("taskkill exited with {status}"));
+        }
+    }
+    Ok(())
+}
+
+/// Waits up to `timeout` for the process `pid` running Lodestone Core under `lodestone_path` to exit, returning whether
+/// it did
+pub fn wait_for_exit(lodestone_path: &Path, pid: u32, timeout: Duration) -> bool {
+    let deadline = Instant::now() + timeout;
+    while is_running(lodestone_path, pid) {
+        if Instant::now() > deadline {
+            return false;
+        }
+        std::thread::sleep(POLL_INTERVAL);
+    }
+    true
+}
+
+/// Runs Lodestone Core under the supervisor until it is stopped, recording this process in the PID file for 'stop' and 'status'
+async fn serve(lodestone_path: &Path, executable_path: &Path, args: &Args) -> i32 {
+    let pid_file = match PidFile::create(lodestone_path) {
+        Ok(pid_file) => pid_file,
+        Err(e) => {
+            error!("{}", e);
+            return exit_code::FAILURE;
+        }
+    };
+    if pid_file.detached {
+        info!(
+            "Running Lodestone Core in the background with PID {}",
+            pid_file.pid
+        );
+    }
+    let mut args = args.clone();
+    args.supervise = true;
+    args.capture_logs |= pid_file.detached;
+    let code = start_core(lodestone_path, executable_path, &args).await;
+    PidFile::remove(lodestone_path);
+    code
+}
+
+pub async fn start(
+    lodestone_path: &Path,
+    detach: bool,
+    skip_update_check: bool,
+    args: &Args,
+) -> i32 {
+    match state(lodestone_path) {
+        Ok(DaemonState::Running(pid_file)) => {
+            error!(
+                "Lodestone Core is already running under {} with PID {}",
+                lodestone_path.display().bold().blue(),
+                pid_file.pid
+            );
+            info!(
+                "Use '{}' or '{}' to control it",
+                "stop".bold().blue(),
+                "restart".bold().blue()
+            );
+            return exit_code::FAILURE;
+        }
+        Ok(DaemonState::Stale(pid_file)) => {
+            info!(
+                "Process {} stopped without cleaning up its PID file, probably because of a reboot, ignoring it",
+                pid_file.pid
+            );
+        }
+        Ok(DaemonState::NotRunning) => {}
+        Err(e) => warn!("{}, ignoring it", e),
+    }
+
+    if is_detached() {
+        // the process that launched this one already installed or updated Lodestone Core
+        return match Metadata::read_metadata(&metadata_path(lodestone_path)).await {
+            Ok(metadata) => {
+                serve(
+                    lodestone_path,
+                    &lodestone_path.join(metadata.executable_name),
+                    args,
+                )
+                .await
+            }
+            Err(e) => {
+                error!("Failed to read the installed version: {}", e);
+                exit_code::NOT_INSTALLED
+            }
+        };
+    }
+    let executable_path =
+        match install_or_update(lodestone_path, None, skip_update_check, args).await {
+            Ok(executable_path) => executable_path,
+            Err(code) => return code,
+        };
+    if !detach {
+        return serve(lodestone_path, &executable_path, args).await;
+    }
+
+    let path = lodestone_path.to_owned();
+    match tokio::task::spawn_blocking(move || self::detach(&path))
+        .await
+        .unwrap()
+    {
+        Ok(pid) => {
+            info!(
+                "Started Lodestone Core in the background with PID {}",
+                pid.bold().blue()
+            );
+            info!(
+                "Use '{}' to see its output and '{}' to stop it",
+                "logs --follow".bold().blue(),
+                "stop".bold().blue()
+            );
+            output::result("pid", pid);
+            exit_code::SUCCESS
+        }
+        Err(e) => {
+            error!("Failed to start Lodestone Core in the background: {e:#}");
+            exit_code::FAILURE
+        }
+    }
+}
+
+pub async fn stop(lodestone_path: &Path, args: &Args) -> i32 {
+    let pid_file = match state(lodestone_path) {
+        Ok(DaemonState::Running(pid_file)) => pid_file,
+        Ok(DaemonState::Stale(pid_file)) => {
+            info!(
+                "Lodestone Core is not running, removing the PID file left by process {}",
+                pid_file.pid
+            );
+            remove_stale(lodestone_path);
+            return exit_code::SUCCESS;
+        }
+        Ok(DaemonState::NotRunning) => {
+            info!(
+                "Lodestone Core is not running under {}",
+                lodestone_path.display().bold().blue()
+            );
+            return exit_code::SUCCESS;
+        }
+        Err(e) => {
+            error!("{}", e);
+            return exit_code::FAILURE;
+        }
+    };
+    let pid = pid_file.pid;
+    info!("Stopping Lodestone Core (PID {})", pid.bold().blue());
+    let shutdown_timeout = args
+        .shutdown_timeout
+        .map_or(run_core::DEFAULT_SHUTDOWN_TIMEOUT, Duration::from_secs);
+    // the first signal gives Lodestone Core the shutdown timeout to stop, a second one kills it,
+    // and the cli itself is only killed if it doesn't react to either
+    for (force, timeout) in [
+        (false, shutdown_timeout + STOP_MARGIN),
+        (false, STOP_MARGIN),
+        (true, STOP_MARGIN),
+    ] {
+        if !is_running(lodestone_path, pid) {
+            info!("Stopped Lodestone Core");
+            output::result("stopped", pid);
+            return exit_code::SUCCESS;
+        }
+        if let Err(e) = signal_stop(pid, force) {
+            error!("Failed to stop process {}: {}", pid, e);
+            return exit_code::FAILURE;
+        }
+        let path = lodestone_path.to_owned();
+        let stopped = tokio::task::spawn_blocking(move || wait_for_exit(&path, pid, timeout))
+            .await
+            .unwrap();
+        if stopped {
+            // a killed process can't clean up after itself
+            remove_stale(lodestone_path);
+            info!("Stopped Lodestone Core");
+            output::result("stopped", pid);
+            return exit_code::SUCCESS;
+        }
+        warn!(
+            "Process {} is still running after {}s",
+            pid,
+            timeout.as_secs()
+        );
+    }
+    error!("Failed to stop process {}", pid);
+    exit_code::FAILURE
+}
+
+pub async fn restart(lodestone_path: &Path, skip_update_check: bool, args: &Args) -> i32 {
+    // the background process launched by restart starts like one launched by 'start --detach'
+    if !is_detached() {
+        let code = stop(lodestone_path, args).await;
+        if code != exit_code::SUCCESS {
+            return code;
+        }
+    }
+    start(lodestone_path, true, skip_update_check, args).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lodestone_path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir()
+            .join(format!("lodestone_cli_test_{}", std::process::id()))
+            .join(name);
+        let _ = std::fs::remove_dir_all(&path);
+        std::fs::create_dir_all(&path).unwrap();
+        path
+    }
+
+    fn write_pid_file(lodestone_path: &Path, pid: u32) {
+        let pid_file = PidFile {
+            pid,
+            started_at: Utc::now().to_string(),
+            detached: true,
+        };
+        std::fs::write(
+            pid_path(lodestone_path),
+            serde_json::to_vec(&pid_file).unwrap(),
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn a_pid_file_nobody_holds_is_stale() {
+        let lodestone_path = lodestone_path("stale");
+        assert!(matches!(
+            state(&lodestone_path).unwrap(),
+            DaemonState::NotRunning
+        ));
+        // the PID is in use, by this very process, but not by the one that wrote the file
+        write_pid_file(&lodestone_path, std::process::id());
+        assert!(matches!(
+            state(&lodestone_path).unwrap(),
+            DaemonState::Stale(_)
+        ));
+        assert!(!is_running(&lodestone_path, std::process::id()));
+
+        remove_stale(&lodestone_path);
+        assert!(!pid_path(&lodestone_path).exists());
+    }
+
+    #[test]
+    fn a_held_pid_file_is_running() {
+        let lodestone_path = lodestone_path("held");
+        write_pid_file(&lodestone_path, 4242);
+        // stands in for the process running Lodestone Core
+        let held = lock(&pid_path(&lodestone_path), false).unwrap().unwrap();
+        assert!(matches!(
+            state(&lodestone_path).unwrap(),
+            DaemonState::Running(PidFile { pid: 4242, .. })
+        ));
+        assert!(is_running(&lodestone_path, 4242));
+        assert!(!is_running(&lodestone_path, 4243));
+
+        let error = PidFile::create(&lodestone_path).unwrap_err();
+        assert!(error.to_string().contains("already running"));
+        remove_stale(&lodestone_path);
+        assert!(pid_path(&lodestone_path).exists());
+        // nor can a backup be restored under it
+        let error = crate::backup::restore_backup(&lodestone_path, "20200101-000000").unwrap_err();
+        assert!(error.to_string().contains("is running with PID 4242"));
+
+        drop(held);
+        assert!(!is_running(&lodestone_path, 4242));
+        assert!(wait_for_exit(&lodestone_path, 4242, Duration::ZERO));
+    }
+
+    #[test]
+    fn creates_and_removes_its_pid_file() {
+        let lodestone_path = lodestone_path("own");
+        write_pid_file(&lodestone_path, 4242);
+        let pid_file = PidFile::create(&lodestone_path).unwrap();
+        assert_eq!(pid_file.pid, std::process::id());
+        // others can't take it, while checking whether it is held doesn't keep it from being taken
+        assert!(lock(&pid_path(&lodestone_path), false).unwrap().is_none());
+        drop(lock(&pid_path(&lodestone_path), true).unwrap());
+        match state(&lodestone_path).unwrap() {
+            DaemonState::Running(running) => assert_eq!(running.pid, std::process::id()),
+            other => panic!("expected the PID file to be held, it is {other:?}"),
+        }
+
+        PidFile::remove(&lodestone_path);
+        assert!(!pid_path(&lodestone_path).exists());
+        assert!(matches!(
+            state(&lodestone_path).unwrap(),
+            DaemonState::NotRunning
+        ));
+    }
+}
@@ -0,0 +1,318 @@
+use reqwest::Client;
+use serde::Serialize;
+use std::fmt::Display;
+use std::sync::OnceLock;
+use std::time::{Duration, Instant};
+
+use color_eyre::owo_colors::OwoColorize;
+
+use crate::{error, exit_code, info, output, run_core, warn};
+
+/// The port the API of Lodestone Core listens on unless it was configured otherwise
+pub const DEFAULT_CORE_PORT: u16 = 16662;
+
+/// Answered by Lodestone Core without logging in, as soon as its API is up
+const PROBE_PATH: &str = "/api/v1/info";
+
+/// How long a single probe waits for an answer
+const PROBE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// An answer slower than this means Lodestone Core is overloaded
+const SLOW_RESPONSE: Duration = Duration::from_secs(2);
+
+/// How often the API is probed while waiting for it to come up
+const READY_POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// How often the watchdog probes a running core, and how many failed probes in a row it takes to give up on it
+const WATCH_INTERVAL: Duration = Duration::from_secs(30);
+const WATCH_FAILURES: u32 = 3;
+
+static CLIENT: OnceLock<Client> = OnceLock::new();
+
+/// Probes go straight to the core on this machine, never through a proxy
+fn client() -> &'static Client {
+    CLIENT.get_or_init(|| {
+        Client::builder()
+            .user_agent("lodestone_cli")
+            .no_proxy()
+            .build()
+            .expect("Failed to build HTTP client")
+    })
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum HealthStatus {
+    /// The API answers quickly and correctly
+    Ready,
+    /// The API answers, but with errors, something unexpected or slowly
+    Degraded,
+    /// Nothing answers on the port
+    Unreachable,
+}
+
+impl Display for HealthStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            HealthStatus::Ready => write!(f, "ready"),
+            HealthStatus::Degraded => write!(f, "degraded"),
+            HealthStatus::Unreachable => write!(f, "unreachable"),
+        }
+    }
+}
+
+/// The result of probing the API of Lodestone Core
+#[derive(Serialize, Debug, Clone)]
+pub struct Health {
+    pub status: HealthStatus,
+    pub port: u16,
+    /// What is wrong, unless it is ready
+    pub detail: Option<String>,
+    /// The version of Lodestone Core, as reported by its API
+    pub version: Option<String>,
+    pub latency_ms: Option<u64>,
+}
+
+impl Health {
+    pub fn is_ready(&self) -> bool {
+        self.status == HealthStatus::Ready
+    }
+
+    /// A short description for `status` and `health`, e.g. `ready on port 16662 (v0.5.1, 12ms)`
+    pub fn describe(&self) -> String {
+        let mut description = format!("{} on port {}", self.status, self.port);
+        match (&self.detail, &self.version, self.latency_ms) {
+            (Some(detail), _, _) => description.push_str(&format!(": {detail}")),
+            (None, Some(version), Some(latency)) => {
+                description.push_str(&format!(" ({version}, {latency}ms)"))
+            }
+            (None, None, Some(latency)) => description.push_str(&format!(" ({latency}ms)")),
+            _ => {}
+        }
+        description
+    }
+}
+
+/// The innermost cause of `error`, reqwest's own message only repeats the URL
+fn root_cause(error: &(dyn std::error::Error + 'static)) -> String {
+    let mut cause = error;
+    while let Some(source) = cause.source() {
+        cause = source;
+    }
+    cause.to_string()
+}
+
+/// Probes the API of Lodestone Core on `port` once
+pub async fn probe(port: u16) -> Health {
+    let url = format!("http://127.0.0.1:{port}{PROBE_PATH}");
+    let started = Instant::now();
+    let health = |status, detail: Option<String>, version| Health {
+        status,
+        port,
+        detail,
+        version,
+        latency_ms: Some(started.elapsed().as_millis() as u64),
+    };
+    let response = match client().get(&url).timeout(PROBE_TIMEOUT).send().await {
+        Ok(response) => response,
+        Err(e) if e.is_timeout() => {
+            return health(
+                HealthStatus::Degraded,
+                Some(format!("no answer within {}s", PROBE_TIMEOUT.as_secs())),
+                None,
+            )
+        }
+        Err(e) => {
+            return Health {
+                latency_ms: None,
+                ..health(HealthStatus::Unreachable, Some(root_cause(&e)), None)
+            }
+        }
+    };
+    let status = response.status();
+    if !status.is_success() {
+        return health(
+            HealthStatus::Degraded,
+            Some(format!("the API answered with {status}")),
+            None,
+        );
+    }
+    let info = match response.json::<serde_json::Value>().await {
+        Ok(info) if info.is_object() => info,
+        _ => {
+            return health(
+                HealthStatus::Degraded,
+                Some(format!(
+                    "unexpected answer, is something else listening on port {port}?"
+                )),
+                None,
+            )
+        }
+    };
+    let version = info["version"].as_str().map(str::to_string);
+    if started.elapsed() > SLOW_RESPONSE {
+        let took = format!("slow to answer ({:.1}s)", started.elapsed().as_secs_f32());
+        return health(HealthStatus::Degraded, Some(took), version);
+    }
+    health(HealthStatus::Ready, None, version)
+}
+
+/// Probes the API until it is ready or `timeout` has passed, returning the last probe
+pub async fn wait_until_ready(port: u16, timeout: Duration) -> Health {
+    let mut probes = probe_until_ready(port, timeout).await;
+    probes.pop().expect("the API is probed at least once")
+}
+
+/// Probes the API until it is ready or `timeout` has passed, returning every probe in order
+pub async fn probe_until_ready(port: u16, timeout: Duration) -> Vec<Health> {
+    let deadline = Instant::now() + timeout;
+    let mut probes = Vec::new();
+    loop {
+        let health = probe(port).await;
+        let done = health.is_ready() || Instant::now() >= deadline;
+        probes.push(health);
+        if done {
+            return probes;
+        }
+        tokio::time::sleep(
+            READY_POLL_INTERVAL.min(deadline.saturating_duration_since(Instant::now())),
+        )
+        .await;
+    }
+}
+
+/// Probes a running core until it fails enough probes in a row to be considered hung, returning the last probe
+///
+/// Never returns once a stop was requested, a core shutting down is expected to stop answering
+pub async fn watch(port: u16) -> Health {
+    let mut failures = 0;
+    loop {
+        tokio::time::sleep(WATCH_INTERVAL).await;
+        if run_core::stop_requested() {
+            return std::future::pending().await;
+        }
+        let health = probe(port).await;
+        if health.is_ready() {
+            failures = 0;
+            continue;
+        }
+        failures += 1;
+        warn!(
+            "Lodestone Core is {} ({} of {} failed probes)",
+            health.describe(),
+            failures,
+            WATCH_FAILURES
+        );
+        if failures >= WATCH_FAILURES {
+            return health;
+        }
+    }
+}
+
+pub async fn health_command(port: u16, wait: Option<u64>) -> i32 {
+    let health = match wait {
+        Some(wait) => wait_until_ready(port, Duration::from_secs(wait)).await,
+        None => probe(port).await,
+    };
+    output::result("health", &health);
+    match health.status {
+        HealthStatus::Ready => {
+            info!("Lodestone Core is {}", health.describe().bold().blue());
+            exit_code::SUCCESS
+        }
+        HealthStatus::Degraded => {
+            warn!("Lodestone Core is {}", health.describe());
+            exit_code::DEGRADED
+        }
+        HealthStatus::Unreachable => {
+            error!("Lodestone Core is {}", health.describe());
+            exit_code::UNREACHABLE
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_server::{self, Response};
+    use std::sync::atomic::{AtomicU32, Ordering};
+    use std::sync::Arc;
+
+    fn info() -> Response {
+        Response::new(200, r#"{"version":"v0.5.1"}"#).header("Content-Type", "application/json")
+    }
+
+    #[tokio::test]
+    async fn an_answering_core_is_ready() {
+        let port = test_server::serve(|request| match request.path.as_str() {
+            PROBE_PATH => info(),
+            _ => Response::new(404, ""),
+        });
+        let health = probe(port).await;
+        assert_eq!(health.status, HealthStatus::Ready);
+        assert_eq!(health.version.as_deref(), Some("v0.5.1"));
+        assert!(health.detail.is_none());
+        assert!(health.latency_ms.is_some());
+    }
+
+    #[tokio::test]
+    async fn a_slow_core_is_degraded() {
+        let port = test_server::serve(|_| info().delay(SLOW_RESPONSE + Duration::from_millis(300)));
+        let health = probe(port).await;
+        assert_eq!(health.status, HealthStatus::Degraded);
+        assert_eq!(health.version.as_deref(), Some("v0.5.1"));
+        assert!(health.detail.unwrap().starts_with("slow to answer"));
+    }
+
+    #[tokio::test]
+    async fn errors_and_other_servers_are_degraded() {
+        let port = test_server::serve(|_| Response::new(500, ""));
+        let health = probe(port).await;
+        assert_eq!(health.status, HealthStatus::Degraded);
+        assert_eq!(
+            health.detail.as_deref(),
+            Some("the API answered with 500 Internal Server Error")
+        );
+
+        let port = test_server::serve(|_| Response::new(200, "<html></html>"));
+        assert_eq!(probe(port).await.status, HealthStatus::Degraded);
+    }
+
+    #[tokio::test]
+    async fn a_refused_connection_is_unreachable() {
+        let health = probe(test_server::closed_port()).await;
+        assert_eq!(health.status, HealthStatus::Unreachable);
+        assert!(health.latency_ms.is_none());
+        assert!(health.detail.is_some());
+    }
+
+    #[tokio::test]
+    async fn waits_until_the_core_is_ready() {
+        let probes = Arc::new(AtomicU32::new(0));
+        let counted = probes.clone();
+        let port = test_server::serve(move |_| {
+            if counted.fetch_add(1, Ordering::SeqCst) < 2 {
+                Response::new(503, "")
+            } else {
+                info()
+            }
+        });
+        let probes_made = probe_until_ready(port, Duration::from_secs(10)).await;
+        let statuses: Vec<_> = probes_made.iter().map(|health| health.status).collect();
+        assert_eq!(
+            statuses,
+            [
+                HealthStatus::Degraded,
+                HealthStatus::Degraded,
+                HealthStatus::Ready
+            ]
+        );
+        assert_eq!(probes.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn stops_waiting_after_the_timeout() {
+        let health = wait_until_ready(test_server::closed_port(), Duration::from_secs(1)).await;
+        assert_eq!(health.status, HealthStatus::Unreachable);
+    }
+}
@@ -0,0 +1,239 @@
+use std::collections::hash_map::RandomState;
+use std::future::Future;
+use std::hash::{BuildHasher, Hasher};
+use std::sync::OnceLock;
+use std::time::Duration;
+
+use color_eyre::eyre::{Report, Result};
+use reqwest::{Client, Response, StatusCode};
+use serde::de::DeserializeOwned;
+
+use crate::warn;
+
+const BASE_DELAY: Duration = Duration::from_millis(500);
+const MAX_DELAY: Duration = Duration::from_secs(30);
+
+/// How network calls are retried, shared by every request the cli makes
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    /// Total number of attempts before giving up, including the first one
+    pub attempts: u32,
+    /// Timeout of a single request. For downloads this is the longest we wait for the next chunk
+    pub timeout: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            attempts: 4,
+            timeout: Duration::from_secs(30),
+        }
+    }
+}
+
+static POLICY: OnceLock<RetryPolicy> = OnceLock::new();
+static CLIENT: OnceLock<Client> = OnceLock::new();
+
+/// Sets the retry policy, must be called before the first request to take effect
+pub fn configure(policy: RetryPolicy) {
+    let _ = POLICY.set(policy);
+}
+
+pub fn policy() -> &'static RetryPolicy {
+    POLICY.get_or_init(RetryPolicy::default)
+}
+
+pub fn client() -> &'static Client {
+    CLIENT.get_or_init(|| {
+        Client::builder()
+            .user_agent("lodestone_cli")
+            .connect_timeout(policy().timeout)
+            .build()
+            .expect("Failed to build HTTP client")
+    })
+}
+
+/// Whether `error` is worth retrying: timeouts, connection errors and 5xx/429 responses
+fn is_transient(error: &Report) -> bool {
+    error.chain().any(|cause| {
+        if let Some(e) = cause.downcast_ref::<reqwest::Error>() {
+            return match e.status() {
+                Some(status) => status.is_server_error() || status == StatusCode::TOO_MANY_REQUESTS,
+                None => e.is_timeout() || e.is_connect() || e.is_request() || e.is_body(),
+            };
+        }
+        if let Some(e) = cause.downcast_ref::<std::io::Error>() {
+            return matches!(
+                e.kind(),
+                std::io::ErrorKind::ConnectionReset
+                    | std::io::ErrorKind::ConnectionAborted
+                    | std::io::ErrorKind::BrokenPipe
+                    | std::io::ErrorKind::TimedOut
+                    | std::io::ErrorKind::UnexpectedEof
+            );
+        }
+        cause.is::<tokio::time::error::Elapsed>()
+            || matches!(
+                cause.downcast_ref::<self_update::errors::Error>(),
+                Some(self_update::errors::Error::Network(_))
+            )
+    })
+}
+
+/// Exponential backoff with jitter, so retries from many machines don't hit GitHub at the same time
+fn backoff(attempt: u32) -> Duration {
+    let cap = BASE_DELAY
+        .saturating_mul(1 << attempt.min(16))
+        .min(MAX_DELAY);
+    let jitter = (RandomState::new().build_hasher().finish() % 1000) as f64 / 1000.0;
+    cap / 2 + (cap / 2).mul_f64(jitter)
+}
+
+/// Runs `f` until it succeeds, fails with a non transient error, or runs out of attempts
+pub async fn retry<T, F, Fut>(what: &str, mut f: F) -> Result<T>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T>>,
+{
+    let attempts = policy().attempts.max(1);
+    let mut attempt = 1;
+    loop {
+        match f().await {
+            Ok(v) => return Ok(v),
+            Err(e) if attempt < attempts && is_transient(&e) => {
+                let delay = backoff(attempt);
+                warn!(
+                    "{} failed: {}, retrying in {:.1}s ({}/{})",
+                    what,
+                    e,
+                    delay.as_secs_f32(),
+                    attempt + 1,
+                    attempts
+                );
+                tokio::time::sleep(delay).await;
+                attempt += 1;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Same as [`retry`], for the blocking calls made through `self_update`
+///
+/// Must be called from a blocking thread of the runtime, like the ones `spawn_blocking` runs on
+pub fn retry_blocking<T>(what: &str, mut f: impl FnMut() -> Result<T>) -> Result<T> {
+    tokio::runtime::Handle::current().block_on(retry(what, || std::future::ready(f())))
+}
+
+async fn send_get(url: &str) -> Result<Response> {
+    let response = client().get(url).timeout(policy().timeout).send().await?;
+    if response.status().is_server_error() || response.status() == StatusCode::TOO_MANY_REQUESTS {
+        response.error_for_status_ref()?;
+    }
+    Ok(response)
+}
+
+/// GETs `url` and parses the response as JSON
+pub async fn get_json<T: DeserializeOwned>(url: &str) -> Result<T> {
+    retry(&format!("GET {url}"), || async {
+        let response = send_get(url).await?;
+        response.error_for_status_ref()?;
+        Ok(response.json().await?)
+    })
+    .await
+}
+
+/// The URL of the next page in a `Link` header, e.g. `<https://api.github.com/...&page=2>; rel="next"`
+fn next_page(link: &str) -> Option<String> {
+    link.split(',').find_map(|link| {
+        let (url, params) = link.split_once(';')?;
+        params
+            .split(';')
+            .any(|param| param.trim() == "rel=\"next\"")
+            .then(|| {
+                url.trim()
+                    .trim_start_matches('<')
+                    .trim_end_matches('>')
+                    .to_string()
+            })
+    })
+}
+
+/// GETs `url`, a JSON array split into pages, following the `rel="next"` links of the `Link` header to get every page
+pub async fn get_json_pages<T: DeserializeOwned>(url: &str) -> Result<Vec<T>> {
+    let mut items = Vec::new();
+    let mut next = Some(url.to_string());
+    while let Some(url) = next {
+        let (page, next_url): (Vec<T>, _) = retry(&format!("GET {url}"), || async {
+            let response = send_get(&url).await?;
+            response.error_for_status_ref()?;
+            let next_url = response
+                .headers()
+                .get(reqwest::header::LINK)
+                .and_then(|link| link.to_str().ok())
+                .and_then(next_page);
+            Ok((response.json().await?, next_url))
+        })
+        .await?;
+        items.extend(page);
+        next = next_url;
+    }
+    Ok(items)
+}
+
+/// GETs `url` as text, `None` if the server responds with 404
+pub async fn get_text(url: &str) -> Result<Option<String>> {
+    retry(&format!("GET {url}"), || async {
+        let response = send_get(url).await?;
+        if response.status() == StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+        response.error_for_status_ref()?;
+        Ok(Some(response.text().await?))
+    })
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_server::{self, Request, Response};
+
+    #[test]
+    fn finds_the_next_page() {
+        let link = "<https://api.github.com/repositories/1/releases?per_page=100&page=2>; rel=\"next\", \
+                    <https://api.github.com/repositories/1/releases?per_page=100&page=5>; rel=\"last\"";
+        assert_eq!(
+            next_page(link).as_deref(),
+            Some("https://api.github.com/repositories/1/releases?per_page=100&page=2")
+        );
+        let link = "<https://api.github.com/repositories/1/releases?per_page=100&page=4>; rel=\"prev\", \
+                    <https://api.github.com/repositories/1/releases?per_page=100&page=1>; rel=\"first\"";
+        assert_eq!(next_page(link), None);
+    }
+
+    #[tokio::test]
+    async fn gets_every_page() {
+        let port = test_server::serve(|request: &Request| {
+            let page: usize = request
+                .path
+                .rsplit_once("page=")
+                .and_then(|(_, page)| page.parse().ok())
+                .unwrap_or(1);
+            let response = Response::new(200, format!("[{}, {}]", page * 2 - 1, page * 2));
+            if page < 3 {
+                let host = &request.headers["host"];
+                response.header(
+                    "Link",
+                    &format!("<http://{host}/releases?page={}>; rel=\"next\"", page + 1),
+                )
+            } else {
+                response
+            }
+        });
+        let items: Vec<u32> = get_json_pages(&format!("http://127.0.0.1:{port}/releases"))
+            .await
+            .unwrap();
+        assert_eq!(items, [1, 2, 3, 4, 5, 6]);
+    }
+}
@@ -0,0 +1,258 @@
+use color_eyre::eyre::{eyre, Result};
+use serde::Serialize;
+use std::path::Path;
+use std::process::Command;
+
+use crate::run_as::{self, Account};
+
+/// Limits on the resources of Lodestone Core and the servers it runs, anything not set is left alone
+#[derive(Serialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct Limits {
+    /// From -20, the highest priority, to 19, the lowest
+    pub nice: Option<i32>,
+    pub memory_max_mb: Option<u64>,
+    /// 100 is one full CPU core
+    pub cpu_quota_percent: Option<u32>,
+    pub max_open_files: Option<u64>,
+}
+
+impl Limits {
+    pub fn is_empty(&self) -> bool {
+        self == &Limits::default()
+    }
+
+    /// The memory cap and the CPU quota can only be enforced by a cgroup
+    pub fn needs_cgroup(&self) -> bool {
+        self.memory_max_mb.is_some() || self.cpu_quota_percent.is_some()
+    }
+
+    /// A short description for logs and `status`, e.g. `nice 10, 4096 MB of memory, 150% CPU`
+    pub fn describe(&self) -> String {
+        let mut parts = Vec::new();
+        if let Some(nice) = self.nice {
+            parts.push(format!("nice {nice}"));
+        }
+        if let Some(memory) = self.memory_max_mb {
+            parts.push(format!("{memory} MB of memory"));
+        }
+        if let Some(quota) = self.cpu_quota_percent {
+            parts.push(format!("{quota}% CPU"));
+        }
+        if let Some(files) = self.max_open_files {
+            parts.push(format!("{files} open files"));
+        }
+        parts.join(", ")
+    }
+
+    /// The properties of the systemd scope enforcing the cgroup limits
+    fn scope_properties(&self) -> Vec<String> {
+        let mut properties = Vec::new();
+        if let Some(memory) = self.memory_max_mb {
+            properties.push(format!("MemoryMax={memory}M"));
+        }
+        if let Some(quota) = self.cpu_quota_percent {
+            properties.push(format!("CPUQuota={quota}%"));
+        }
+        properties
+    }
+}
+
+/// The arguments of `systemd-run` putting a command in a transient scope with `limits`, up to the command itself
+///
+/// The command is executed by `systemd-run` itself once the scope exists, so it keeps its PID and stays a child of
+/// the cli. With `run_as`, `systemd-run` switches to that user before executing it
+fn systemd_run_args(limits: &Limits, run_as: Option<&Account>) -> Vec<String> {
+    let mut args = Vec::new();
+    if !crate::service::is_root() {
+        args.push("--user".to_string());
+    }
+    args.extend(
+        [
+            "--scope",
+            "--quiet",
+            "--collect",
+            "--description=Lodestone Core",
+        ]
+        .map(str::to_string),
+    );
+    for property in limits.scope_properties() {
+        args.push("--property".to_string());
+        args.push(property);
+    }
+    if let Some(account) = run_as {
+        args.push(format!("--uid={}", account.name));
+        args.push(format!("--gid={}", account.gid));
+    }
+    args.push("--".to_string());
+    args
+}
+
+/// Whether a transient systemd scope can be created to enforce the cgroup limits, checked once by creating one
+#[cfg(target_os = "linux")]
+pub fn scope_available(limits: &Limits) -> bool {
+    use std::process::Stdio;
+    use std::sync::OnceLock;
+    static SCOPE_AVAILABLE: OnceLock<bool> = OnceLock::new();
+    *SCOPE_AVAILABLE.get_or_init(|| {
+        Path::new("/run/systemd/system").is_dir()
+            && Command::new("systemd-run")
+                .args(systemd_run_args(limits, None))
+                .arg("true")
+                .stdin(Stdio::null())
+                .stdout(Stdio::null())
+                .stderr(Stdio::null())
+                .status()
+                .is_ok_and(|status| status.success())
+    })
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn scope_available(_limits: &Limits) -> bool {
+    false
+}
+
+/// Checks that `limits` can be applied by the cli as it runs, before Lodestone Core is started
+#[cfg(unix)]
+pub fn check(limits: &Limits) -> Result<()> {
+    if crate::service::is_root() {
+        return Ok(());
+    }
+    if let Some(nice) = limits.nice {
+        // SAFETY: getpriority has no memory safety requirements, -1 is a valid priority so errno isn't checked
+        let current = unsafe { libc::getpriority(libc::PRIO_PROCESS, 0) };
+        if nice < current {
+            return Err(eyre!(
+                "Only root can lower the nice level of Lodestone Core below the one of the cli ({current})"
+            ));
+        }
+    }
+    if let Some(max_open_files) = limits.max_open_files {
+        let mut limit = libc::rlimit {
+            rlim_cur: 0,
+            rlim_max: 0,
+        };
+        // SAFETY: getrlimit only writes to `limit`
+        if unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut limit) } == 0
+            && limit.rlim_max != libc::RLIM_INFINITY
+            && max_open_files as libc::rlim_t > limit.rlim_max
+        {
+            return Err(eyre!(
+                "Only root can allow Lodestone Core more than {} open files",
+                limit.rlim_max
+            ));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn check(limits: &Limits) -> Result<()> {
+    if limits.is_empty() {
+        Ok(())
+    } else {
+        Err(eyre!(
+            "Resource limits for Lodestone Core are only supported on Linux and macOS"
+        ))
+    }
+}
+
+/// A command running `executable_path` with `limits`, as the user `run_as` if it is set
+///
+/// The nice level and the maximum of open files are set on the process before it executes, and inherited by every
+/// process it starts. The memory cap and the CPU quota are enforced by a transient systemd scope, they are left out
+/// if `scope_available` is false
+pub fn command(executable_path: &Path, limits: &Limits, run_as: Option<&Account>) -> Command {
+    let in_scope = limits.needs_cgroup() && scope_available(limits);
+    let mut command = if in_scope {
+        let mut command = Command::new("systemd-run");
+        command
+            .args(systemd_run_args(limits, run_as))
+            .arg(executable_path);
+        command
+    } else {
+        Command::new(executable_path)
+    };
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::CommandExt;
+        let Limits {
+            nice,
+            max_open_files,
+            ..
+        } = *limits;
+        if nice.is_some() || max_open_files.is_some() {
+            // SAFETY: setpriority and setrlimit are async-signal-safe
+            unsafe {
+                command.pre_exec(move || {
+                    if let Some(nice) = nice {
+                        if libc::setpriority(libc::PRIO_PROCESS, 0, nice) == -1 {
+                            return Err(std::io::Error::last_os_error());
+                        }
+                    }
+                    if let Some(max_open_files) = max_open_files {
+                        let limit = libc::rlimit {
+                            rlim_cur: max_open_files as libc::rlim_t,
+                            rlim_max: max_open_files as libc::rlim_t,
+                        };
+                        if libc::setrlimit(libc::RLIMIT_NOFILE, &limit) == -1 {
+                            return Err(std::io::Error::last_os_error());
+                        }
+                    }
+                    Ok(())
+                });
+            }
+        }
+    }
+    if let Some(account) = run_as {
+        run_as::set_environment(&mut command, account);
+        if !in_scope {
+            run_as::drop_privileges(&mut command, account);
+        }
+    }
+    command
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::ffi::OsStr;
+    use std::path::PathBuf;
+
+    #[test]
+    fn run_as_names_the_user_in_the_environment() {
+        let account = Account {
+            name: "steve".to_string(),
+            uid: 1000,
+            gid: 1000,
+            home: PathBuf::from("/home/steve"),
+        };
+        let command = command(
+            Path::new("/srv/lodestone/lodestone_core"),
+            &Limits::default(),
+            Some(&account),
+        );
+        let envs: Vec<_> = command.get_envs().collect();
+        assert!(envs.contains(&(OsStr::new("HOME"), Some(OsStr::new("/home/steve")))));
+        assert!(envs.contains(&(OsStr::new("USER"), Some(OsStr::new("steve")))));
+        assert!(envs.contains(&(OsStr::new("LOGNAME"), Some(OsStr::new("steve")))));
+    }
+
+    #[test]
+    fn scope_properties_only_hold_the_cgroup_limits() {
+        let limits = Limits {
+            nice: Some(10),
+            memory_max_mb: Some(4096),
+            cpu_quota_percent: Some(150),
+            max_open_files: Some(4096),
+        };
+        assert!(limits.needs_cgroup());
+        assert_eq!(
+            limits.scope_properties(),
+            ["MemoryMax=4096M", "CPUQuota=150%"]
+        );
+        assert_eq!(
+            limits.describe(),
+            "nice 10, 4096 MB of memory, 150% CPU, 4096 open files"
+        );
+    }
+}
@@ -1,18 +1,63 @@
+mod backup;
+mod bundle;
+mod config;
+mod container;
+mod core_logs;
+mod daemon;
+mod health;
+mod http;
+mod limits;
+mod output;
+mod registry;
+mod run_as;
+mod service;
+mod supervisor;
+#[cfg(test)]
+mod test_server;
 mod uninstall;
 mod util;
+mod verify;
 mod versions;
-use color_eyre::eyre::Result;
+use color_eyre::eyre::{Context, Result};
 use color_eyre::owo_colors::OwoColorize;
 use util::executable_name_without_version;
 
-use std::{env, fmt::Display, fs::File, io::Write, path::PathBuf, str::FromStr};
-use versions::VersionWithV;
+use http::RetryPolicy;
+use output::OutputFormat;
+use semver::VersionReq;
+use serde_json::{json, Value};
+use std::{
+    env,
+    fmt::Display,
+    fs::File,
+    io::{IsTerminal, Write},
+    path::{Path, PathBuf},
+    process::ExitStatus,
+    str::FromStr,
+    sync::OnceLock,
+    time::Duration,
+};
+use update_manager::metadata::{self, metadata_path, Metadata};
+use update_manager::source::ReleaseSource;
+use update_manager::UpdateOptions;
+use update_manager::{installed, rollback};
+use verify::{check_signature, VerifyOptions};
+use versions::{Channel, VersionSpec, VersionWithV};
 
 mod run_core;
 mod update_manager;
-use run_core::run_lodestone;
+use run_core::{run_lodestone, EnvVar, LaunchOptions};
 
-use clap::Parser;
+use clap::{
+    error::ErrorKind, parser::ValueSource, Arg, ArgAction, ArgMatches, CommandFactory,
+    FromArgMatches, Parser, Subcommand,
+};
+use config::Config;
+use core_logs::LogPolicy;
+use daemon::DaemonState;
+use health::HealthStatus;
+use limits::Limits;
+use run_as::Account;
 use serde::{Deserialize, Serialize};
 
 use versions::get_current_version;
@@ -25,7 +70,7 @@ thread_local! {
 // an info! macro that append the prefix "[i].green()" to the message
 macro_rules! info {
     ($($arg:tt)*) => ({
-        println!("{prefix} {}", format_args!($($arg)*), prefix = "[i]".green());
+        $crate::output::log($crate::output::Level::Info, format!($($arg)*));
     })
 }
 
@@ -33,106 +78,720 @@ macro_rules! info {
 // an warn! macro that append the prefix "[!!]".yellow() to the message
 macro_rules! warn {
     ($($arg:tt)*) => ({
-        println!("{prefix} {}", format_args!($($arg)*), prefix = "[!!]".yellow());
+        $crate::output::log($crate::output::Level::Warn, format!($($arg)*));
     })
 }
 
 // an error! macro that append the prefix "[!!!]".red() to the message
 macro_rules! error {
     ($($arg:tt)*) => ({
-        println!("{prefix} {}", format_args!($($arg)*), prefix = "[!!!]".red());
+        $crate::output::log($crate::output::Level::Error, format!($($arg)*));
     })
 }
 
 pub(crate) use {error, info};
 
+/// Exit codes returned by the cli, scripts can rely on these staying the same
+pub mod exit_code {
+    pub const SUCCESS: i32 = 0;
+    /// Something went wrong, the error is printed before exiting
+    pub const FAILURE: i32 = 1;
+    // 2 is what clap exits with when the command line could not be parsed
+    /// The user declined a confirmation prompt, no file changes were made
+    pub const ABORTED: i32 = 3;
+    /// The command requires an existing installation of Lodestone Core
+    pub const NOT_INSTALLED: i32 = 4;
+    /// `update --check` found a newer version of Lodestone Core
+    pub const UPDATE_AVAILABLE: i32 = 5;
+    /// A prompt needed an answer but the cli is not interactive, pass `--yes-all` to say yes
+    pub const INTERACTION_REQUIRED: i32 = 6;
+    /// Lodestone Core kept crashing under `--supervise` and was given up on
+    pub const CRASH_LOOP: i32 = 7;
+    /// `health` found the API of Lodestone Core answering with errors, something unexpected or slowly
+    pub const DEGRADED: i32 = 8;
+    /// `health` found nothing answering on the port of Lodestone Core
+    pub const UNREACHABLE: i32 = 9;
+
+    /// Stable name of `code`, used as the error code in machine readable output
+    pub fn name(code: i32) -> &'static str {
+        match code {
+            SUCCESS => "success",
+            ABORTED => "aborted",
+            NOT_INSTALLED => "not_installed",
+            UPDATE_AVAILABLE => "update_available",
+            INTERACTION_REQUIRED => "interaction_required",
+            CRASH_LOOP => "crash_loop",
+            DEGRADED => "degraded",
+            UNREACHABLE => "unreachable",
+            _ => "failure",
+        }
+    }
+
+    pub fn is_error(code: i32) -> bool {
+        code != SUCCESS && code != UPDATE_AVAILABLE
+    }
+}
+
 /// A simple CLI tool to install, update and run the Lodestone Core
-#[derive(Parser, Debug, Default, Serialize, Deserialize)]
-#[command(author, about, long_about = None)]
+#[derive(Parser, Debug, Default, Clone, Serialize, Deserialize)]
+#[command(
+    author,
+    about,
+    long_about = None,
+    after_help = "Exit codes:\n  0  success\n  1  failure\n  2  invalid usage\n  3  aborted at a prompt\n  4  Lodestone Core is not installed\n  5  an update is available (update --check)\n  6  a prompt needs an answer but the cli is not interactive\n  7  Lodestone Core kept crashing under --supervise\n  8  the API of Lodestone Core is degraded (health)\n  9  the API of Lodestone Core is unreachable (health)"
+)]
 struct Args {
-    /// Uninstall lodestone
-    #[clap(long, short)]
-    #[serde(default)]
-    pub uninstall: bool,
-    /// Install a specific version of lodestone.
-    ///
-    /// If not specified, the latest version will be installed
-    #[clap(long, short)]
-    pub version: Option<VersionWithV>,
+    #[command(subcommand)]
+    #[serde(skip)]
+    pub command: Option<Command>,
     /// Say yes to all prompts.
     ///
     /// Bypasses pre-release confirmation, downgrade confirmation, dirty installation confirmation, and uninstall confirmation
-    #[clap(long, short)]
+    #[clap(long, short, global = true)]
     #[serde(default)]
     pub yes_all: bool,
+    /// Never read from stdin, even when it is a terminal.
+    ///
+    /// Prompts are answered with their default instead, or the cli exits with code 6 if a prompt has no safe default.
+    /// This is the default when stdin is not a terminal, for example under systemd, Docker or CI
+    #[clap(long, global = true)]
+    #[serde(default)]
+    pub non_interactive: bool,
     /// Tells the cli where to install lodestone.
     ///
     /// If not specified, the cli will install lodestone in ~/.lodestone
     ///
     /// This will set the LODESTONE_PATH environment variable for the current running process
-    #[clap(long, short)]
+    #[clap(long, short, global = true)]
     pub install_path: Option<PathBuf>,
-    /// Skip ALL update check to GitHub, and try to use the local version of core if possible
+    /// How the cli reports what it does.
+    ///
+    /// 'json' prints a single JSON document once the command is done, 'ndjson' prints one JSON event per line as things happen.
+    /// Both turn off colours, progress bars and prompts, as if '--non-interactive' was passed
+    #[clap(long, global = true, value_enum, default_value_t = OutputFormat::Text)]
+    #[serde(default)]
+    pub output: OutputFormat,
+    /// Skip checksum verification of downloaded Lodestone Core executables.
+    ///
+    /// Only use this if you trust your connection and the release, a corrupted or tampered executable will be installed as is
+    #[clap(long, global = true)]
+    #[serde(default)]
+    pub skip_verify: bool,
+    /// Install Lodestone Core and cli updates even if they are unsigned or their signature is invalid.
+    ///
+    /// Signatures protect you from compromised releases, only use this if you know what you are doing
+    #[clap(long, global = true)]
+    #[serde(default)]
+    pub allow_unsigned: bool,
+    /// Public key used to check release signatures instead of the built-in one.
+    ///
+    /// Either a base64 minisign public key or a path to a minisign .pub file, for builds of your own fork
+    #[clap(long, global = true)]
+    pub public_key: Option<String>,
+    /// How many times a failed network request is retried before giving up.
+    ///
+    /// Only timeouts, connection errors and server errors are retried, with an exponential backoff between attempts
+    #[clap(long, global = true, value_name = "COUNT")]
+    pub retries: Option<u32>,
+    /// Timeout of a single network request in seconds, defaults to 30.
+    ///
+    /// For downloads, this is the longest the cli waits without receiving any data
+    #[clap(long, global = true, value_name = "SECONDS")]
+    pub timeout: Option<u64>,
+    /// Delete old versions of Lodestone Core after an install or update, keeping this many versions.
+    ///
+    /// If not specified, every downloaded version is kept until you run 'prune'
+    #[clap(long, global = true, value_name = "COUNT")]
+    pub keep_versions: Option<usize>,
+    /// Don't back up LODESTONE_PATH before changing the version of Lodestone Core.
+    ///
+    /// Backups let you undo a bad upgrade with 'backup restore', but can take a while with large instances
+    #[clap(long, global = true)]
+    #[serde(default)]
+    pub skip_backup: bool,
+    /// Where releases of Lodestone Core are fetched from, defaults to 'github'. Remembered by the installation.
+    ///
+    /// Either 'github', the URL of a mirror listing its releases in 'index.json', or a file:// URL of a local directory.
+    /// Both mirrors and directories keep the assets of a release under '<version>/', e.g. 'v0.5.1/lodestone_core_linux_x86_64_v0.5.1'
+    #[clap(long, global = true, value_name = "SOURCE")]
+    pub source: Option<ReleaseSource>,
+    /// Release channel to pick new versions of Lodestone Core from, remembered by the installation.
+    ///
+    /// 'stable' only gets full releases, 'beta' also gets betas and release candidates, 'nightly' gets every release.
+    /// Defaults to the channel the installation follows, or 'stable' for a new installation
+    #[clap(long, global = true, value_enum)]
+    pub channel: Option<Channel>,
+    /// Restart Lodestone Core when it crashes, for machines that should recover without anyone stepping in.
+    ///
+    /// Restarts back off exponentially from 1 second up to 5 minutes. Exiting with code 0 or stopping the cli with CTRL+C
+    /// is not a crash. The restart count and last exit are kept in '.lodestone_cli_supervisor.json' under LODESTONE_PATH
+    #[clap(long, global = true)]
+    #[serde(default)]
+    pub supervise: bool,
+    /// How many times in a row Lodestone Core may crash under '--supervise' before the cli gives up, defaults to 5.
+    ///
+    /// A crash after Lodestone Core ran for a minute starts a new count. The cli exits with code 7 when it gives up
+    #[clap(long, global = true, value_name = "COUNT")]
+    pub max_restarts: Option<u32>,
+    /// How long Lodestone Core gets to stop in seconds after CTRL+C, SIGTERM or SIGHUP, defaults to 30.
     ///
-    /// If the local version is not available, the cli will try to download the latest version from GitHub
-    #[clap(long, short)]
+    /// The signal is forwarded to Lodestone Core so it can stop its instances cleanly, it is killed if it is still running
+    /// after this long. Sending the signal a second time kills it straight away
+    #[clap(long, global = true, value_name = "SECONDS")]
+    pub shutdown_timeout: Option<u64>,
+    /// Also write the output of Lodestone Core to LODESTONE_PATH/logs, with timestamps, for 'logs' to show.
+    ///
+    /// The log is rotated daily or when it gets larger than '--log-max-size'
+    #[clap(long, global = true)]
+    #[serde(default)]
+    pub capture_logs: bool,
+    /// Size in megabytes at which the log of Lodestone Core is rotated, defaults to 10
+    #[clap(long, global = true, value_name = "MEGABYTES")]
+    pub log_max_size: Option<u64>,
+    /// How many rotated logs of Lodestone Core are kept, defaults to 5
+    #[clap(long, global = true, value_name = "COUNT")]
+    pub log_max_files: Option<usize>,
+    /// Rotated logs of Lodestone Core older than this many days are deleted, defaults to 30
+    #[clap(long, global = true, value_name = "DAYS")]
+    pub log_max_age: Option<u64>,
+    /// The port the API of Lodestone Core listens on, defaults to 16662.
+    ///
+    /// The cli probes it to know when Lodestone Core is ready, for 'health', 'status' and the start-up health check of new versions.
+    /// Under '--supervise', Lodestone Core is restarted when it stops answering
+    #[clap(long, global = true, value_name = "PORT")]
+    pub core_port: Option<u16>,
+    /// Set an environment variable for Lodestone Core, can be passed several times.
+    ///
+    /// Adds to the 'core_env' table of the config, overriding variables set there
+    #[clap(long, global = true, value_name = "KEY=VALUE")]
+    #[serde(skip)]
+    pub core_env: Vec<EnvVar>,
+    /// Run Lodestone Core and the servers it starts at this nice level, from -20 (highest priority) to 19 (lowest).
+    ///
+    /// Only root can go below the nice level of the cli
+    #[clap(
+        long,
+        global = true,
+        value_name = "LEVEL",
+        allow_negative_numbers = true
+    )]
+    pub core_nice: Option<i32>,
+    /// Cap the memory of Lodestone Core and the servers it starts, in megabytes.
+    ///
+    /// Only enforced on Linux with systemd, through a transient scope
+    #[clap(long, global = true, value_name = "MEGABYTES")]
+    pub core_memory_max: Option<u64>,
+    /// Cap the CPU time of Lodestone Core and the servers it starts, in percent of one CPU core, e.g. 200 for two cores.
+    ///
+    /// Only enforced on Linux with systemd, through a transient scope
+    #[clap(long, global = true, value_name = "PERCENT")]
+    pub core_cpu_quota: Option<u32>,
+    /// How many files Lodestone Core and each server it starts may have open at once
+    #[clap(long, global = true, value_name = "COUNT")]
+    pub core_max_open_files: Option<u64>,
+    /// Run Lodestone Core and the servers it starts as this user, on Unix when the cli runs as root.
+    ///
+    /// The cli drops its privileges right before starting Lodestone Core. The user needs to own LODESTONE_PATH and
+    /// everything Lodestone Core uses in it, the cli offers to change the owner of what it doesn't
+    #[clap(long, global = true, value_name = "USER")]
+    pub run_as: Option<String>,
+    /// The 'core_args' of the config followed by the arguments after '--'
+    #[clap(skip)]
+    #[serde(skip)]
+    pub core_args: Vec<String>,
+    /// Deprecated, use `uninstall` instead
+    #[clap(long, short, hide = true)]
+    #[serde(default)]
+    pub uninstall: bool,
+    /// Deprecated, use `install --version <VERSION>` instead
+    #[clap(long, short, hide = true)]
+    pub version: Option<VersionSpec>,
+    /// Deprecated, use `run --skip-update-check` instead
+    #[clap(long, short, hide = true)]
     #[serde(default)]
     pub skip_update_check: bool,
-    /// Run Lodestone Core automatically
-    #[clap(long, short)]
+    /// Deprecated, use `run` instead
+    #[clap(long, short, hide = true)]
     #[serde(default)]
     pub run_core: bool,
-    /// List all available versions of lodestone
-    #[clap(long, short)]
+    /// Deprecated, use `list` instead
+    #[clap(long, short, hide = true)]
     #[serde(default)]
     pub list_versions: bool,
 }
 
+#[derive(Subcommand, Debug, Clone)]
+enum Command {
+    /// Install Lodestone Core, or switch to a specific version of it
+    Install {
+        /// Install a specific version of lodestone, or the newest one matching a requirement.
+        ///
+        /// Either an exact version like 'v0.5.1', or a semver requirement like '^0.5', '~0.5.2' or '>=0.4, <0.6'.
+        /// A requirement is remembered, later updates only pick versions matching it, pass '*' to forget it.
+        /// If not specified, the latest version will be installed
+        #[clap(long, short)]
+        version: Option<VersionSpec>,
+        /// Install from a bundle made with 'export-bundle', or a Lodestone Core executable, without touching the network.
+        ///
+        /// An executable needs its checksum in a '.sha256' file next to it, its version is read from its name unless '--version' is passed
+        #[clap(long, value_name = "PATH")]
+        from: Option<PathBuf>,
+    },
+    /// Update an existing installation of Lodestone Core to the latest version
+    Update {
+        /// Only check if an update is available, exits with code 5 if there is one
+        #[clap(long, short)]
+        check: bool,
+    },
+    /// Run Lodestone Core, installing or updating it first if needed
+    Run {
+        /// Skip ALL update check to GitHub, and try to use the local version of core if possible
+        ///
+        /// If the local version is not available, the cli will try to download the latest version from GitHub
+        #[clap(long, short)]
+        skip_update_check: bool,
+        /// Arguments passed to Lodestone Core, after those in the 'core_args' of the config
+        #[clap(last = true, value_name = "CORE_ARGS")]
+        core_args: Vec<String>,
+    },
+    /// List all available versions of Lodestone Core
+    List,
+    /// List the versions of Lodestone Core downloaded under LODESTONE_PATH
+    Installed,
+    /// Switch to another version of Lodestone Core, only downloading it if it isn't already installed
+    Use {
+        /// The version to switch to
+        version: VersionWithV,
+    },
+    /// Delete old versions of Lodestone Core, the current version is always kept
+    Prune {
+        /// How many versions to keep, including the current one.
+        ///
+        /// Defaults to '--keep-versions', or 2 if that isn't set either
+        #[clap(long, short)]
+        keep: Option<usize>,
+    },
+    /// Uninstall lodestone, deleting LODESTONE_PATH and every file in it
+    Uninstall,
+    /// Show the installed version of Lodestone Core
+    Status,
+    /// Check whether the API of Lodestone Core is ready, exits with code 8 if it is degraded and 9 if it is unreachable
+    Health {
+        /// Keep probing for up to this many seconds until it is ready, for scripts that just started it
+        #[clap(long, value_name = "SECONDS")]
+        wait: Option<u64>,
+    },
+    /// Run Lodestone Core under the supervisor, installing or updating it first if needed
+    ///
+    /// 'stop', 'restart' and 'status' find it through the PID file under LODESTONE_PATH
+    Start {
+        /// Run in the background and return once Lodestone Core is started, its output is captured as with '--capture-logs'
+        #[clap(long, short)]
+        detach: bool,
+        /// Skip the update check and use the installed version of Lodestone Core
+        #[clap(long, short)]
+        skip_update_check: bool,
+        /// Arguments passed to Lodestone Core, after those in the 'core_args' of the config
+        #[clap(last = true, value_name = "CORE_ARGS")]
+        core_args: Vec<String>,
+    },
+    /// Stop Lodestone Core started with 'start'
+    Stop,
+    /// Stop Lodestone Core started with 'start', then start it again in the background
+    Restart {
+        /// Skip the update check and use the installed version of Lodestone Core
+        #[clap(long, short)]
+        skip_update_check: bool,
+        /// Arguments passed to Lodestone Core, after those in the 'core_args' of the config
+        #[clap(last = true, value_name = "CORE_ARGS")]
+        core_args: Vec<String>,
+    },
+    /// Show the output of Lodestone Core captured with '--capture-logs'
+    Logs {
+        /// How many of the last lines to show
+        #[clap(long, short = 'n', default_value_t = 50)]
+        lines: usize,
+        /// Keep showing new lines as Lodestone Core writes them, until CTRL+C
+        #[clap(long, short)]
+        follow: bool,
+    },
+    /// Manage backups of the data under LODESTONE_PATH, taken automatically before every version change
+    #[command(subcommand)]
+    Backup(BackupCommand),
+    /// Package an installed version of Lodestone Core into a bundle, to install it on another machine with 'install --from'
+    ExportBundle {
+        /// Where to write the bundle, defaults to '<executable name>.tar.gz' in the working directory
+        path: Option<PathBuf>,
+        /// The version to export, defaults to the current one
+        #[clap(long, short)]
+        version: Option<VersionWithV>,
+    },
+    /// Show the release channel Lodestone Core is updated from, or switch to another one
+    Channel {
+        /// The channel to switch to, takes effect on the next update
+        #[clap(value_enum)]
+        channel: Option<Channel>,
+    },
+    /// Update the cli itself to the latest release
+    SelfUpdate,
+    /// Inspect the configuration of the cli
+    #[command(subcommand)]
+    Config(ConfigCommand),
+    /// Run Lodestone Core as a systemd service on Linux or a launchd service on macOS
+    #[command(subcommand)]
+    Service(ServiceCommand),
+    /// Package Lodestone Core into a container image
+    #[command(subcommand)]
+    Container(ContainerCommand),
+}
+
+#[derive(Subcommand, Debug, Clone)]
+enum ContainerCommand {
+    /// Write a Docker build context running a version of Lodestone Core, with the cli and the executable baked in
+    ///
+    /// The image keeps LODESTONE_PATH in a volume at /var/lib/lodestone and runs 'run --skip-update-check'.
+    /// Only works on Linux, the image gets the cli and Lodestone Core built for this machine
+    Build {
+        /// Where to write the build context, must be empty or not exist yet
+        #[clap(default_value = "lodestone_container")]
+        dir: PathBuf,
+        /// The version of Lodestone Core to put in the image, or the newest one matching a requirement.
+        ///
+        /// Defaults to the latest version on '--channel'
+        #[clap(long, short)]
+        version: Option<VersionSpec>,
+        /// The image to build on, it needs glibc
+        #[clap(long, default_value = container::DEFAULT_BASE_IMAGE)]
+        base_image: String,
+        /// The name of the image, defaults to 'lodestone:<version>'
+        #[clap(long, short)]
+        tag: Option<String>,
+        /// Also build the image into an OCI archive at this path, pulling the base image without a Docker daemon.
+        ///
+        /// Load it with 'docker load -i <PATH>' or 'podman load -i <PATH>'. Only public base images can be pulled
+        #[clap(long, value_name = "PATH")]
+        oci: Option<PathBuf>,
+    },
+}
+
+#[derive(Subcommand, Debug, Clone)]
+enum ServiceCommand {
+    /// Install and start a service running Lodestone Core at login, or at boot with '--system'
+    ///
+    /// The service runs 'run --skip-update-check' and restarts Lodestone Core when it crashes
+    Install {
+        /// Install a system service started at boot, rather than a service of the current user. Needs root
+        #[clap(long)]
+        system: bool,
+        /// The user the system service runs as, defaults to the user running the cli, or the one running sudo
+        #[clap(long, requires = "system")]
+        user: Option<String>,
+        /// Only print the generated service, without installing it
+        #[clap(long)]
+        print: bool,
+    },
+    /// Stop and remove the service installed with 'service install'
+    Uninstall {
+        /// Remove the system service, rather than the service of the current user. Needs root
+        #[clap(long)]
+        system: bool,
+    },
+}
+
+#[derive(Subcommand, Debug, Clone)]
+enum ConfigCommand {
+    /// Print the configuration the cli runs with, as TOML
+    Show {
+        /// Also show where each value comes from, and which config files were looked at
+        #[clap(long)]
+        origin: bool,
+    },
+}
+
+#[derive(Subcommand, Debug, Clone)]
+enum BackupCommand {
+    /// List existing backups, newest first
+    List,
+    /// Back up the data under LODESTONE_PATH now
+    Create,
+    /// Replace the data under LODESTONE_PATH with a backup, the current data is backed up first
+    Restore {
+        /// The id of the backup, as shown by 'backup list'
+        id: String,
+    },
+}
+
+/// Boolean options that take an optional value, so a later layer can turn them back off with e.g. '--yes-all=false'
+const BOOL_OPTIONS: [&str; 7] = [
+    "yes_all",
+    "non_interactive",
+    "skip_verify",
+    "allow_unsigned",
+    "skip_backup",
+    "supervise",
+    "capture_logs",
+];
+
+/// Makes `arg` a boolean option that is `true` when given alone, like a flag, and takes `true` or `false` after a `=`
+fn bool_option(arg: Arg) -> Arg {
+    arg.action(ArgAction::Set)
+        .num_args(0..=1)
+        .require_equals(true)
+        .default_value("false")
+        .hide_default_value(true)
+        .default_missing_value("true")
+        .value_name("BOOL")
+}
+
 impl Args {
-    pub fn merge(&mut self, other: Self) {
-        if let Some(version) = other.version {
-            self.version = Some(version);
+    /// The command line parser, with the options of `BOOL_OPTIONS` made boolean options
+    fn cli() -> clap::Command {
+        BOOL_OPTIONS
+            .into_iter()
+            .fold(Args::command(), |command, id| {
+                command.mut_arg(id, bool_option)
+            })
+    }
+
+    /// The settings given on the command line, leaving out the defaults filled in by clap
+    fn command_line_config(&self, matches: &ArgMatches) -> Config {
+        let Ok(Value::Object(args)) = serde_json::to_value(self) else {
+            return Config::default();
+        };
+        let given = args
+            .into_iter()
+            .filter(|(key, _)| {
+                Config::keys().contains(key)
+                    && matches.value_source(key) == Some(ValueSource::CommandLine)
+            })
+            .collect();
+        serde_json::from_value(Value::Object(given)).unwrap_or_default()
+    }
+
+    fn apply_config(&mut self, config: Config) {
+        self.yes_all = config.yes_all.unwrap_or_default();
+        self.non_interactive = config.non_interactive.unwrap_or_default();
+        self.install_path = config.install_path;
+        self.output = config.output.unwrap_or_default();
+        self.skip_verify = config.skip_verify.unwrap_or_default();
+        self.allow_unsigned = config.allow_unsigned.unwrap_or_default();
+        self.public_key = config.public_key;
+        self.retries = config.retries;
+        self.timeout = config.timeout;
+        self.keep_versions = config.keep_versions;
+        self.skip_backup = config.skip_backup.unwrap_or_default();
+        self.source = config.source;
+        self.supervise = config.supervise.unwrap_or_default();
+        self.max_restarts = config.max_restarts;
+        self.shutdown_timeout = config.shutdown_timeout;
+        self.capture_logs = config.capture_logs.unwrap_or_default();
+        self.log_max_size = config.log_max_size;
+        self.log_max_files = config.log_max_files;
+        self.log_max_age = config.log_max_age;
+        self.core_port = config.core_port;
+        self.core_nice = config.core_nice;
+        self.core_memory_max = config.core_memory_max;
+        self.core_cpu_quota = config.core_cpu_quota;
+        self.core_max_open_files = config.core_max_open_files;
+        self.run_as = config.run_as;
+        self.core_args = config.core_args.unwrap_or_default();
+        // variables given with '--core-env' add to those of the config instead of replacing them all
+        let mut core_env = config.core_env.unwrap_or_default();
+        core_env.extend(
+            std::mem::take(&mut self.core_env)
+                .into_iter()
+                .map(|var| (var.key, var.value)),
+        );
+        self.core_env = core_env
+            .into_iter()
+            .map(|(key, value)| EnvVar { key, value })
+            .collect();
+    }
+
+    /// These settings with `core_args` passed to Lodestone Core after the ones from the config
+    fn with_core_args(&self, core_args: Vec<String>) -> Args {
+        let mut args = self.clone();
+        args.core_args.extend(core_args);
+        args
+    }
+
+    fn limits(&self) -> Limits {
+        Limits {
+            nice: self.core_nice,
+            memory_max_mb: self.core_memory_max,
+            cpu_quota_percent: self.core_cpu_quota,
+            max_open_files: self.core_max_open_files,
         }
-        if let Some(install_path) = other.install_path {
-            self.install_path = Some(install_path);
+    }
+
+    fn launch_options(&self, run_as: Option<Account>) -> LaunchOptions {
+        LaunchOptions {
+            limits: self.limits(),
+            run_as,
+            args: self.core_args.clone(),
+            env: self
+                .core_env
+                .iter()
+                .map(|var| (var.key.clone(), var.value.clone()))
+                .collect(),
+        }
+    }
+
+    /// Merges the deprecated action flags from `args.json`, the command line takes precedence
+    fn merge_legacy(&mut self, other: Self) {
+        if self.version.is_none() {
+            self.version = other.version;
         }
         self.uninstall |= other.uninstall;
-        self.yes_all |= other.yes_all;
         self.skip_update_check |= other.skip_update_check;
         self.run_core |= other.run_core;
         self.list_versions |= other.list_versions;
     }
+
+    fn retry_policy(&self) -> RetryPolicy {
+        let default = RetryPolicy::default();
+        RetryPolicy {
+            attempts: self.retries.map_or(default.attempts, |retries| retries + 1),
+            timeout: self.timeout.map_or(default.timeout, Duration::from_secs),
+        }
+    }
+
+    fn update_options(&self, skip_update_check: bool) -> UpdateOptions {
+        UpdateOptions {
+            yes_all: self.yes_all,
+            skip_update_check,
+            skip_backup: self.skip_backup,
+            channel: self.channel,
+            source: self.release_source(),
+            verify: self.verify_options(),
+        }
+    }
+
+    /// How the output of Lodestone Core is captured under `lodestone_path`, `None` without `--capture-logs`
+    fn log_policy(&self, lodestone_path: &Path) -> Option<LogPolicy> {
+        self.capture_logs.then(|| LogPolicy {
+            dir: core_logs::logs_dir(lodestone_path),
+            max_size: self
+                .log_max_size
+                .unwrap_or(core_logs::DEFAULT_MAX_SIZE_MB)
+                .saturating_mul(1024 * 1024),
+            max_files: self.log_max_files.unwrap_or(core_logs::DEFAULT_MAX_FILES),
+            max_age: Duration::from_secs(
+                self.log_max_age
+                    .unwrap_or(core_logs::DEFAULT_MAX_AGE_DAYS)
+                    .saturating_mul(24 * 60 * 60),
+            ),
+            // nobody is there to read it in the background, it would only fill up lodestone_cli.log
+            echo: !daemon::is_detached(),
+        })
+    }
+
+    fn core_port(&self) -> u16 {
+        self.core_port.unwrap_or(health::DEFAULT_CORE_PORT)
+    }
+
+    fn release_source(&self) -> ReleaseSource {
+        self.source.clone().unwrap_or_default()
+    }
+
+    fn verify_options(&self) -> VerifyOptions {
+        VerifyOptions {
+            skip_checksum: self.skip_verify,
+            allow_unsigned: self.allow_unsigned,
+            public_key: self.public_key.clone(),
+        }
+    }
+
+    /// Whether any of the deprecated action flags were used
+    fn uses_legacy_flags(&self) -> bool {
+        self.uninstall
+            || self.version.is_some()
+            || self.skip_update_check
+            || self.run_core
+            || self.list_versions
+    }
 }
 
-fn read_args_from_file() -> Option<Args> {
-    serde_json::from_reader(std::fs::File::open("args.json").ok()?).ok()
+static INTERACTIVE: OnceLock<bool> = OnceLock::new();
+
+/// Whether prompts can be answered, false with `--non-interactive`, machine readable output or when stdin is not a terminal
+fn is_interactive() -> bool {
+    *INTERACTIVE.get_or_init(|| !output::is_machine_readable() && std::io::stdin().is_terminal())
+}
+
+/// What a prompt resolves to when nobody can answer it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum PromptDefault {
+    /// The command carries on without doing what the prompt asked about
+    No,
+    /// Too risky to decide either way, exit with `exit_code::INTERACTION_REQUIRED`
+    Required,
 }
 
-fn prompt_for_confirmation(message: impl Display, predicate: impl FnOnce(String) -> bool) -> bool {
-    print!("{message}");
-    std::io::stdout().flush().unwrap();
-    let mut input = String::new();
-    std::io::stdin()
-        .read_line(&mut input)
-        .expect("Failed to read line");
-    predicate(input)
+fn prompt_for_confirmation(
+    message: impl Display,
+    predicate: impl FnOnce(String) -> bool,
+    default: PromptDefault,
+) -> bool {
+    if is_interactive() {
+        print!("{message}");
+        std::io::stdout().flush().unwrap();
+        let mut input = String::new();
+        match std::io::stdin().read_line(&mut input) {
+            Ok(0) => warn!("stdin was closed before the prompt was answered"),
+            Ok(_) => return predicate(input),
+            Err(e) => warn!("Failed to read the answer to the prompt: {e}"),
+        }
+    }
+    match default {
+        PromptDefault::No => {
+            info!(
+                "{} Assuming no since the cli is not interactive, pass '{}' to say yes",
+                message,
+                "--yes-all".bold().blue()
+            );
+            false
+        }
+        PromptDefault::Required => {
+            error!(
+                "{} This needs an answer but the cli is not interactive, pass '{}' to say yes",
+                message,
+                "--yes-all".bold().blue()
+            );
+            std::process::exit(output::finish(exit_code::INTERACTION_REQUIRED));
+        }
+    }
 }
 
-async fn self_update() -> Result<()> {
+async fn self_update(verify: &VerifyOptions) -> Result<()> {
     let bin_name = format!(
         "lodestone_cli_{os}_{aarch}",
         os = env::consts::OS,
         aarch = env::consts::ARCH
     );
+    let verify = verify.clone();
     tokio::task::spawn_blocking(move || {
-        let releases = self_update::backends::github::ReleaseList::configure()
-            .repo_owner("Lodestone-Team")
-            .repo_name("lodestone_cli")
-            .build()?
-            .fetch()?;
-        let latest = releases[0].asset_for(&bin_name, None).unwrap();
-        let latest_sem_ver: semver::Version = VersionWithV::from_str(&releases[0].version)?.into();
+        let releases = http::retry_blocking("Fetching cli releases", || {
+            Ok(self_update::backends::github::ReleaseList::configure()
+                .repo_owner("Lodestone-Team")
+                .repo_name("lodestone_cli")
+                .build()?
+                .fetch()?)
+        })?;
+        let latest_release = releases
+            .first()
+            .ok_or_else(|| color_eyre::eyre::eyre!("No cli releases were found"))?;
+        let latest_sem_ver: semver::Version =
+            VersionWithV::from_str(&latest_release.version)?.into();
         let current_sem_ver = VERSION.with(|v| v.clone());
+        output::result(
+            "cli",
+            json!({
+                "current_version": current_sem_ver.to_string(),
+                "latest_version": latest_sem_ver.to_string(),
+                "updated": latest_sem_ver > current_sem_ver,
+            }),
+        );
         if latest_sem_ver <= current_sem_ver {
             info!("CLI is up to date");
             return Ok(());
@@ -143,18 +802,55 @@ async fn self_update() -> Result<()> {
                 current = current_sem_ver
             );
         }
+        let latest = latest_release
+            .assets
+            .iter()
+            .find(|asset| asset.name == bin_name)
+            .ok_or_else(|| {
+                color_eyre::eyre::eyre!(
+                    "Cli release {} has no build for this platform ({})",
+                    latest_release.version,
+                    bin_name
+                )
+            })?;
 
         // create temp dir under the current directory
         let temp_dir = tempfile::tempdir_in(std::env::current_dir()?)?;
         let bin_path = temp_dir.path().join(&bin_name);
-        let bin_file = File::create(&bin_path)?;
-        self_update::Download::from_url(&latest.download_url)
-            .set_header(
-                reqwest::header::ACCEPT,
-                "application/octet-stream".parse().unwrap(),
-            )
-            .show_progress(true)
-            .download_to(bin_file)?;
+        http::retry_blocking("Downloading the cli", || {
+            let bin_file = File::create(&bin_path)?;
+            self_update::Download::from_url(&latest.download_url)
+                .set_header(
+                    reqwest::header::ACCEPT,
+                    "application/octet-stream".parse().unwrap(),
+                )
+                .show_progress(!output::is_machine_readable())
+                .download_to(bin_file)?;
+            Ok(())
+        })?;
+
+        let signature_name = format!("{bin_name}.minisig");
+        let signature = match latest_release
+            .assets
+            .iter()
+            .find(|asset| asset.name == signature_name)
+        {
+            Some(asset) => Some(http::retry_blocking(
+                "Downloading the cli signature",
+                || {
+                    let mut signature = Vec::new();
+                    self_update::Download::from_url(&asset.download_url)
+                        .set_header(
+                            reqwest::header::ACCEPT,
+                            "application/octet-stream".parse().unwrap(),
+                        )
+                        .download_to(&mut signature)?;
+                    Ok(String::from_utf8(signature)?)
+                },
+            )?),
+            None => None,
+        };
+        check_signature(&bin_path, &bin_name, signature.as_deref(), &verify)?;
         #[cfg(not(windows))]
         {
             use std::os::unix::prelude::PermissionsExt;
@@ -171,17 +867,7 @@ async fn self_update() -> Result<()> {
     .unwrap()
 }
 
-#[tokio::main]
-async fn main() {
-    // setup_tracing();
-    let _ = color_eyre::install().map_err(|e| error!("color eyre install error {e}"));
-
-    info!("Lodestone CLI v{}", VERSION.with(|v| v.to_string()));
-
-    if let Err(e) = self_update().await {
-        error!("Failed to self update: {e}");
-    }
-
+fn check_platform_support() {
     if executable_name_without_version().is_none() {
         error!(
             "{}",
@@ -198,179 +884,1192 @@ async fn main() {
                 "(y/n):".magenta().bold()
             ),
             |s| s.trim() == "y" || s.trim() == "yes",
+            PromptDefault::Required,
         ) {
             error!("cli will now exit");
-            std::process::exit(1);
+            std::process::exit(output::finish(exit_code::ABORTED));
         }
         info!("Proceeding with installation with unsupported system");
     }
-    let args = match read_args_from_file() {
-        Some(mut args) => {
-            info!(
-                "{}",
-                "Detected a valid args.json file. Performing a merge of command line args and args.json"
-            );
-            args.merge(Args::parse());
-            args
-        }
-        None => Args::parse(),
-    };
-
-    if args.list_versions {
-        versions::list_versions().await.unwrap();
-        return;
-    }
+}
 
-    if let Some(path) = args.install_path {
+fn resolve_lodestone_path(install_path: Option<&PathBuf>) -> PathBuf {
+    if let Some(path) = install_path {
         std::env::set_var("LODESTONE_PATH", path);
     }
     let lodestone_path = util::get_lodestone_path().ok_or_else(|| {
         error!("Could not find lodestone path. We couldn't find your home directory, and you didn't specify a path with the --install-path flag");
         error!("Please specify a path with the '{}' flag", "--install-path".bold().blue());
         error!("cli will now exit");
-        std::process::exit(1);
+        std::process::exit(output::finish(exit_code::FAILURE));
     }).unwrap();
     info!(
         "LODESTONE_PATH={}",
         lodestone_path.to_string_lossy().bold().blue()
     );
-    if let Some(v) = args.version.as_ref() {
-        info!(
-            "You chose to install a specific version of Lodestone Core ({}). {}",
-            v.bold().blue(),
-            get_current_version().await.ok().map_or_else(
-                || "".to_string(),
-                |current_version| {
-                    format!(
-                        "Current version : {}",
-                        current_version.to_string().blue().bold()
-                    )
+    output::result("lodestone_path", &lodestone_path);
+    lodestone_path
+}
+
+/// Warns about downgrades and pre-releases, and asks the user if they want to install `v` anyway
+async fn confirm_version_override(v: &VersionWithV, yes_all: bool) -> bool {
+    info!(
+        "You chose to install a specific version of Lodestone Core ({}). {}",
+        v.bold().blue(),
+        get_current_version().await.ok().map_or_else(
+            || "".to_string(),
+            |current_version| {
+                format!(
+                    "Current version : {}",
+                    current_version.to_string().blue().bold()
+                )
+            }
+        )
+    );
+    info!(
+        "If you want to install the latest version, run the command without the '{}' flag",
+        "--version".bold().blue()
+    );
+
+    let mut require_confirmation = true;
+    if let Ok(current_version) = get_current_version().await {
+        if current_version > *v {
+            error!(
+                "You are installing an older version of lodestone ({}) than the one you currently have installed ({})",
+                v.bold().blue(), current_version.bold().blue()
+            );
+            error!(
+                "Note that {} Doing so may cause {}",
+                "we do not support downgrading.".bold().red(),
+                "data loss or corruption".bold().red()
+            );
+            require_confirmation = true;
+        }
+    } else {
+        warn!(
+            "We couldn't find your current version of lodestone, so we can't check if you are downgrading",
+        );
+        warn!(
+            "Note that {} Doing so may cause {}",
+            "we do not support downgrading.".bold().yellow(),
+            "data loss or corruption".bold().red()
+        );
+    }
+    if !v.0.pre.is_empty() {
+        warn!(
+            "You are installing a pre-release version of lodestone {},",
+            "which may be unstable".bold().yellow()
+        );
+        require_confirmation = true;
+    }
+    yes_all
+        || !require_confirmation
+        || prompt_for_confirmation(
+            format!("Would you like to proceed? {}", "(y/n)".magenta().bold()),
+            |s| s.trim() == "y" || s.trim() == "yes",
+            PromptDefault::Required,
+        )
+}
+
+/// Runs `try_update` and maps its outcome to an exit code, returning the executable on success
+async fn install_or_update(
+    lodestone_path: &Path,
+    version: Option<VersionWithV>,
+    skip_update_check: bool,
+    args: &Args,
+) -> Result<PathBuf, i32> {
+    if let Err(e) = std::fs::create_dir_all(lodestone_path) {
+        error!("Failed to create {}: {}", lodestone_path.display(), e);
+        return Err(exit_code::FAILURE);
+    }
+    match update_manager::try_update(
+        lodestone_path,
+        version,
+        &args.update_options(skip_update_check),
+    )
+    .await
+    {
+        Ok(Some(executable_path)) => {
+            if let Some(channel) = args.channel {
+                if let Err(e) = metadata::set_channel(lodestone_path, channel).await {
+                    warn!("Failed to switch to the {} channel: {}", channel, e);
                 }
-            )
+            }
+            output::result(
+                "installed",
+                json!({
+                    "version": get_current_version().await.ok(),
+                    "executable": executable_path,
+                }),
+            );
+            if let Some(keep) = args.keep_versions {
+                prune_versions(lodestone_path, keep).await;
+            }
+            run_as_account(lodestone_path, args)?;
+            Ok(executable_path)
+        }
+        Ok(None) => {
+            info!("No Lodestone Core executable found, cli will now exit...");
+            Err(exit_code::ABORTED)
+        }
+        Err(e) => {
+            error!("{}: {}", "Error updating lodestone".bold().red(), e);
+            Err(exit_code::FAILURE)
+        }
+    }
+}
+
+/// Finds the user of '--run-as' and checks that Lodestone Core can run as it under `lodestone_path`, offering to give
+/// it what it doesn't own there
+///
+/// Returns the user if the cli has to switch to it to run Lodestone Core
+fn run_as_account(lodestone_path: &Path, args: &Args) -> Result<Option<Account>, i32> {
+    let Some(name) = &args.run_as else {
+        return Ok(None);
+    };
+    if !cfg!(unix) {
+        error!("'{}' is only supported on Unix", "--run-as".bold().blue());
+        return Err(exit_code::FAILURE);
+    }
+    let Some(account) = run_as::lookup(name) else {
+        error!("There is no user named {}", name.bold().blue());
+        return Err(exit_code::FAILURE);
+    };
+    let unowned = match run_as::check(lodestone_path, &account) {
+        Ok(unowned) => unowned,
+        Err(e) => {
+            error!("{:#}", e);
+            return Err(exit_code::FAILURE);
+        }
+    };
+    if !unowned.is_empty() {
+        warn!(
+            "{} file(s) under {} aren't owned by {}, Lodestone Core couldn't use them",
+            unowned.len(),
+            lodestone_path.display().bold().blue(),
+            name.bold().blue()
         );
+        if !(args.yes_all
+            || prompt_for_confirmation(
+                format!(
+                    "Would you like to make {} their owner? {}",
+                    name,
+                    "(y/n):".magenta().bold()
+                ),
+                |s| s.trim() == "y" || s.trim() == "yes",
+                PromptDefault::No,
+            ))
+        {
+            error!(
+                "Lodestone Core can't run as {} until it owns them, run '{}' to fix it",
+                name,
+                format!("chown -R {}: {}", name, lodestone_path.display())
+                    .bold()
+                    .blue()
+            );
+            return Err(exit_code::FAILURE);
+        }
+        if let Err(e) = run_as::change_owner(&unowned, &account) {
+            error!("{:#}", e);
+            return Err(exit_code::FAILURE);
+        }
         info!(
-            "If you want to install the latest version, run the command without the '{}' flag",
-            "--version".bold().blue()
+            "{} now owns everything Lodestone Core uses under {}",
+            name.bold().blue(),
+            lodestone_path.display().bold().blue()
         );
+    }
+    Ok(run_as::needs_switch(&account).then_some(account))
+}
 
-        let mut require_confirmation = true;
-        if let Ok(current_version) = get_current_version().await {
-            if current_version > *v {
-                error!(
-                    "You are installing an older version of lodestone ({}) than the one you currently have installed ({})",
-                    v.bold().blue(), current_version.bold().blue()
-                );
+/// Picks the newest release matching `req` on the channel of the installation
+async fn resolve_version_requirement(req: &VersionReq, args: &Args) -> Result<VersionWithV, i32> {
+    let channel = match args.channel {
+        Some(channel) => channel,
+        None => versions::get_current_channel().await,
+    };
+    match versions::get_latest_release(&args.release_source(), channel, Some(req)).await {
+        Ok(version) => {
+            info!(
+                "Lodestone Core {} is the newest release matching '{}'",
+                version.bold().blue(),
+                req.bold().blue()
+            );
+            Ok(version)
+        }
+        Err(e) => {
+            error!("Failed to resolve '{}': {}", req, e);
+            Err(exit_code::FAILURE)
+        }
+    }
+}
+
+/// Installs the version `spec` asks for, or the latest version, and remembers the requirement if `spec` is one
+async fn install_version(
+    lodestone_path: &Path,
+    spec: Option<VersionSpec>,
+    skip_update_check: bool,
+    args: &Args,
+) -> Result<PathBuf, i32> {
+    let (version, constraint) = match spec {
+        Some(VersionSpec::Exact(version)) => (Some(version), None),
+        Some(VersionSpec::Req(req)) => (
+            Some(resolve_version_requirement(&req, args).await?),
+            Some(req),
+        ),
+        None => (None, None),
+    };
+    if let Some(v) = version.as_ref() {
+        if !confirm_version_override(v, args.yes_all).await {
+            info!("Aborting installation, no file changes were made.",);
+            return Err(exit_code::ABORTED);
+        }
+    }
+    let executable_path =
+        install_or_update(lodestone_path, version, skip_update_check, args).await?;
+    if let Some(req) = constraint {
+        let constraint = (req != VersionReq::STAR).then_some(req);
+        match metadata::set_constraint(lodestone_path, constraint.clone()).await {
+            Ok(_) => match constraint {
+                Some(req) => info!(
+                    "Updates will only pick versions matching '{}'",
+                    req.bold().blue()
+                ),
+                None => info!("Updates will pick any version again"),
+            },
+            Err(e) => warn!("Failed to save the version requirement: {}", e),
+        }
+    }
+    Ok(executable_path)
+}
+
+/// Installs Lodestone Core from the bundle or executable at `from`, never touching the network
+async fn install_from(
+    lodestone_path: &Path,
+    from: &Path,
+    version: Option<VersionSpec>,
+    args: &Args,
+) -> i32 {
+    let version = match version {
+        Some(VersionSpec::Exact(version)) => Some(version),
+        Some(VersionSpec::Req(req)) => {
+            error!(
+                "'{}' takes an exact version, not the requirement '{}'",
+                "--from".bold().blue(),
+                req
+            );
+            return exit_code::FAILURE;
+        }
+        None => None,
+    };
+    if let Err(e) = std::fs::create_dir_all(lodestone_path) {
+        error!("Failed to create {}: {}", lodestone_path.display(), e);
+        return exit_code::FAILURE;
+    }
+    info!("Checking {}", from.display().bold().blue());
+    let (from_path, path, verify) = (
+        from.to_owned(),
+        lodestone_path.to_owned(),
+        args.verify_options(),
+    );
+    let imported = tokio::task::spawn_blocking(move || {
+        bundle::import(&from_path, &path, version.as_ref(), &verify)
+    })
+    .await
+    .map_err(Into::into)
+    .and_then(|result| result);
+    match imported {
+        Ok(version) => {
+            info!(
+                "{} holds Lodestone Core {}",
+                from.display().bold().blue(),
+                version.bold().blue()
+            );
+            // only ask before replacing another version, a fresh install or a reinstall is what the user asked for
+            if get_current_version()
+                .await
+                .is_ok_and(|current_version| current_version != version)
+                && !confirm_version_override(&version, args.yes_all).await
+            {
+                info!("Aborting installation, the installed version was left as it is.");
+                return exit_code::ABORTED;
+            }
+            // the executable is in place now, so switching to it doesn't download anything
+            match install_or_update(lodestone_path, Some(version), false, args).await {
+                Ok(_) => exit_code::SUCCESS,
+                Err(code) => code,
+            }
+        }
+        Err(e) => {
+            error!("Failed to install from {}: {:#}", from.display(), e);
+            exit_code::FAILURE
+        }
+    }
+}
+
+async fn export_bundle(
+    lodestone_path: &Path,
+    dest: Option<PathBuf>,
+    version: Option<VersionWithV>,
+) -> i32 {
+    let version = match version {
+        Some(version) => version,
+        None => match get_current_version().await {
+            Ok(version) => version,
+            Err(_) => {
                 error!(
-                    "Note that {} Doing so may cause {}",
-                    "we do not support downgrading.".bold().red(),
-                    "data loss or corruption".bold().red()
+                    "Lodestone Core is not installed under {}",
+                    lodestone_path.display().bold().blue()
                 );
-                require_confirmation = true;
+                return exit_code::NOT_INSTALLED;
             }
-        } else {
-            warn!(
-                "We couldn't find your current version of lodestone, so we can't check if you are downgrading",
+        },
+    };
+    let Some(executable_path) = installed::find_installed(lodestone_path, &version) else {
+        error!(
+            "Lodestone Core {} is not downloaded under {}",
+            version.bold().blue(),
+            lodestone_path.display().bold().blue()
+        );
+        return exit_code::NOT_INSTALLED;
+    };
+    let executable_name = util::get_executable_name(&version);
+    let dest = dest.unwrap_or_else(|| PathBuf::from(format!("{executable_name}.tar.gz")));
+    info!(
+        "Packaging {} into {}",
+        executable_path.display().bold().blue(),
+        dest.display().bold().blue()
+    );
+    let (path, bundle_dest) = (lodestone_path.to_owned(), dest.clone());
+    let result = tokio::task::spawn_blocking(move || {
+        bundle::export_bundle(&path, &version, &executable_name, &bundle_dest)
+    })
+    .await
+    .map_err(Into::into)
+    .and_then(|result| result);
+    match result {
+        Ok(manifest) => {
+            info!(
+                "Exported Lodestone Core {} to {}, install it with '{}'",
+                manifest.version.bold().blue(),
+                dest.display().bold().blue(),
+                format!("install --from {}", dest.display()).bold().blue()
             );
-            warn!(
-                "Note that {} Doing so may cause {}",
-                "we do not support downgrading.".bold().yellow(),
-                "data loss or corruption".bold().red()
+            output::result("bundle", json!({ "path": dest, "manifest": manifest }));
+            exit_code::SUCCESS
+        }
+        Err(e) => {
+            error!("Failed to export Lodestone Core: {:#}", e);
+            exit_code::FAILURE
+        }
+    }
+}
+
+async fn install(lodestone_path: &Path, version: Option<VersionSpec>, args: &Args) -> i32 {
+    match install_version(lodestone_path, version, false, args).await {
+        Ok(_) => exit_code::SUCCESS,
+        Err(code) => code,
+    }
+}
+
+async fn update(lodestone_path: &Path, check: bool, args: &Args) -> i32 {
+    let current_version = match get_current_version().await {
+        Ok(v) => v,
+        Err(_) => {
+            error!(
+                "Lodestone Core is not installed under {}",
+                lodestone_path.display().bold().blue()
             );
+            info!("Run '{}' to install it", "install".bold().blue());
+            return exit_code::NOT_INSTALLED;
         }
-        if !v.0.pre.is_empty() {
-            warn!(
-                "You are installing a pre-release version of lodestone {},",
-                "which may be unstable".bold().yellow()
+    };
+    if check {
+        let metadata = Metadata::read_metadata(&metadata_path(lodestone_path))
+            .await
+            .ok();
+        let channel = args
+            .channel
+            .or(metadata.as_ref().map(|metadata| metadata.channel))
+            .unwrap_or_default();
+        let constraint = metadata.and_then(|metadata| metadata.constraint);
+        let latest =
+            versions::get_latest_release(&args.release_source(), channel, constraint.as_ref())
+                .await;
+        if let Ok(latest) = &latest {
+            output::result(
+                "update",
+                json!({
+                    "channel": channel,
+                    "constraint": constraint,
+                    "current_version": current_version,
+                    "latest_version": latest,
+                    "update_available": *latest > current_version,
+                }),
             );
-            require_confirmation = true;
         }
-        if !args.yes_all
-            && require_confirmation
-            && !prompt_for_confirmation(
-                format!("Would you like to proceed? {}", "(y/n)".magenta().bold()),
-                |s| s.trim() == "y" || s.trim() == "yes",
-            )
-        {
-            info!("Aborting installation, no file changes were made.",);
-            return;
+        return match latest {
+            Ok(latest) if latest > current_version => {
+                info!(
+                    "An update is available: {} -> {}",
+                    current_version.bold().blue(),
+                    latest.bold().blue()
+                );
+                exit_code::UPDATE_AVAILABLE
+            }
+            Ok(_) => {
+                info!(
+                    "Lodestone Core {} is up to date",
+                    current_version.bold().blue()
+                );
+                exit_code::SUCCESS
+            }
+            Err(e) => {
+                error!("Failed to check for updates: {}", e);
+                exit_code::FAILURE
+            }
+        };
+    }
+    match install_or_update(lodestone_path, None, false, args).await {
+        Ok(_) => exit_code::SUCCESS,
+        Err(code) => code,
+    }
+}
+
+async fn run_core_blocking(executable_path: &Path) -> Result<ExitStatus> {
+    let executable_path = executable_path.to_owned();
+    tokio::task::spawn_blocking(move || run_lodestone(&executable_path))
+        .await
+        .unwrap()
+}
+
+fn exit_code_of(result: Result<ExitStatus>) -> i32 {
+    if let Ok(status) = &result {
+        output::result("core_exit_code", status.code());
+    }
+    match result {
+        Ok(status) if status.success() => exit_code::SUCCESS,
+        Ok(_) => exit_code::FAILURE,
+        Err(e) => {
+            error!("Error running lodestone: {}", e);
+            exit_code::FAILURE
         }
     }
+}
 
-    std::fs::create_dir_all(&lodestone_path).unwrap();
-    if args.uninstall {
-        warn!(
-            "{}",
-            format!(
-                "This will delete the directory and all files in it: {}",
-                lodestone_path.display()
-            )
+/// The source the installation was made from, `None` if it isn't installed
+async fn remembered_source(install_path: Option<&PathBuf>) -> Option<ReleaseSource> {
+    let lodestone_path = install_path.cloned().or_else(util::get_lodestone_path)?;
+    Metadata::read_metadata(&metadata_path(&lodestone_path))
+        .await
+        .ok()
+        .map(|metadata| metadata.source)
+}
+
+/// Rolls back to the version the current one replaced after it failed to start, and runs that instead
+async fn roll_back(lodestone_path: &Path) -> Result<ExitStatus> {
+    error!(
+        "{}",
+        "The new version of Lodestone Core failed to start, rolling back to the previous version"
             .bold()
             .red()
+    );
+    let previous_executable = rollback::rollback(lodestone_path)
+        .await
+        .context("Failed to roll back")?;
+    info!(
+        "Rolled back, automatic updates will skip the failed version. Starting {}",
+        previous_executable.display().bold().blue()
+    );
+    output::result("rolled_back_to", &previous_executable);
+    run_core_blocking(&previous_executable).await
+}
+
+/// Runs Lodestone Core once and waits for its API on `port` to be ready
+///
+/// A freshly installed version that exits, or whose API is still degraded once the start-up health check is over, is
+/// rolled back to the previous version. With `watchdog`, Lodestone Core is stopped when its API stops answering, so the
+/// supervisor restarts it
+async fn launch_core(
+    lodestone_path: &Path,
+    executable_path: &Path,
+    port: u16,
+    watchdog: bool,
+) -> Result<ExitStatus> {
+    let pending_health_check = rollback::is_pending_health_check(lodestone_path).await;
+    let run = run_core_blocking(executable_path);
+    tokio::pin!(run);
+    let probes = tokio::select! {
+        result = &mut run => {
+            let failed = rollback::failed_to_start(&result, run_core::stop_requested());
+            if !pending_health_check || !failed {
+                return result;
+            }
+            if let Err(e) = &result {
+                error!("Error running lodestone: {}", e);
+            }
+            return roll_back(lodestone_path).await;
+        }
+        probes = health::probe_until_ready(port, rollback::STARTUP_HEALTH_WINDOW) => probes,
+    };
+    let statuses: Vec<_> = probes.iter().map(|health| health.status).collect();
+    let health = probes.last().expect("the API is probed at least once");
+    output::result("health", health);
+    match health.status {
+        HealthStatus::Ready => info!("Lodestone Core is {}", health.describe().bold().blue()),
+        HealthStatus::Degraded => warn!("Lodestone Core is {}", health.describe()),
+        // the core may listen on another port, it is only known to be running
+        HealthStatus::Unreachable => warn!(
+            "After {}s, Lodestone Core is still {}. Pass '{}' if it listens on another port",
+            rollback::STARTUP_HEALTH_WINDOW.as_secs(),
+            health.describe(),
+            "--core-port".bold().blue()
+        ),
+    }
+
+    if pending_health_check {
+        if rollback::failed_startup(&statuses) {
+            run_core::terminate();
+            let _ = run.await;
+            return roll_back(lodestone_path).await;
+        }
+        if let Err(e) = rollback::mark_healthy(lodestone_path).await {
+            warn!(
+                "Failed to record that Lodestone Core started successfully: {}",
+                e
+            );
+        }
+    }
+    if !watchdog || health.status == HealthStatus::Unreachable {
+        return run.await;
+    }
+    tokio::select! {
+        result = &mut run => result,
+        health = health::watch(port) => {
+            error!(
+                "Lodestone Core stopped answering ({}), stopping it so it is restarted",
+                health.describe()
+            );
+            run_core::terminate();
+            // even if it stops cleanly, this is a crash to the supervisor
+            let status = run.await?;
+            Err(color_eyre::eyre::eyre!(
+                "stopped answering, stopped with {status}"
+            ))
+        }
+    }
+}
+
+/// Runs Lodestone Core, restarting it when it crashes with `--supervise`
+async fn start_core(lodestone_path: &Path, executable_path: &Path, args: &Args) -> i32 {
+    info!("Starting lodestone...");
+    let limits = args.limits();
+    if let Err(e) = limits::check(&limits) {
+        error!("{}", e);
+        return exit_code::FAILURE;
+    }
+    let run_as = match run_as_account(lodestone_path, args) {
+        Ok(run_as) => run_as,
+        Err(code) => return code,
+    };
+    if limits.needs_cgroup() && !limits::scope_available(&limits) {
+        warn!(
+            "The memory and CPU limits of Lodestone Core need a transient systemd scope, which can't be created here. They won't be enforced"
         );
-        if !args.yes_all
-            && prompt_for_confirmation(
-                format!(
-                    "Are you sure you want to uninstall lodestone? {}:",
-                    "(yes/n)".bold().magenta()
-                ),
-                |input| input.trim() == "yes",
-            )
-        {
-            info!("Uninstalling lodestone...");
-            if let Err(e) = uninstall::uninstall(&lodestone_path) {
-                error!(
-                    "Error uninstalling lodestone: {}, some files may need to be manually removed",
-                    e
+    }
+    run_core::configure(
+        args.shutdown_timeout
+            .map_or(run_core::DEFAULT_SHUTDOWN_TIMEOUT, Duration::from_secs),
+        args.launch_options(run_as),
+    );
+    run_core::handle_signals();
+    if let Some(policy) = args.log_policy(lodestone_path) {
+        info!(
+            "Capturing the output of Lodestone Core in {}",
+            policy.dir.display().bold().blue()
+        );
+        core_logs::configure(policy);
+    }
+    if !args.supervise {
+        return exit_code_of(
+            launch_core(lodestone_path, executable_path, args.core_port(), false).await,
+        );
+    }
+
+    let max_restarts = args
+        .max_restarts
+        .unwrap_or(supervisor::DEFAULT_MAX_RESTARTS);
+    let mut first_launch = Some(executable_path.to_owned());
+    let port = args.core_port();
+    let outcome = supervisor::supervise(lodestone_path, max_restarts, || {
+        let lodestone_path = lodestone_path.to_owned();
+        let first_launch = first_launch.take();
+        async move {
+            // a rollback may have changed the current version since the last launch
+            let executable_path = match first_launch {
+                Some(executable_path) => executable_path,
+                None => Metadata::read_metadata(&metadata_path(&lodestone_path))
+                    .await
+                    .map(|metadata| lodestone_path.join(metadata.executable_name))
+                    .context("Failed to read the installed version")?,
+            };
+            launch_core(&lodestone_path, &executable_path, port, true).await
+        }
+    })
+    .await;
+    match outcome {
+        supervisor::Outcome::Stopped(status) => {
+            output::result("core_exit_code", status.and_then(|status| status.code()));
+            exit_code::SUCCESS
+        }
+        supervisor::Outcome::CrashLoop => exit_code::CRASH_LOOP,
+    }
+}
+
+async fn run(lodestone_path: &Path, skip_update_check: bool, args: &Args) -> i32 {
+    match install_or_update(lodestone_path, None, skip_update_check, args).await {
+        Ok(executable_path) => start_core(lodestone_path, &executable_path, args).await,
+        Err(code) => code,
+    }
+}
+
+async fn list(source: &ReleaseSource, channel: Option<Channel>) -> i32 {
+    match versions::list_versions(source, channel).await {
+        Ok(_) => exit_code::SUCCESS,
+        Err(e) => {
+            error!("Failed to list versions: {}", e);
+            exit_code::FAILURE
+        }
+    }
+}
+
+async fn channel_command(lodestone_path: &Path, channel: Option<Channel>) -> i32 {
+    let path = metadata_path(lodestone_path);
+    let current = match Metadata::read_metadata(&path).await {
+        Ok(metadata) => metadata.channel,
+        Err(_) => {
+            error!(
+                "Lodestone Core is not installed under {}",
+                lodestone_path.display().bold().blue()
+            );
+            info!(
+                "Pass '{}' to '{}' to pick the channel of a new installation",
+                "--channel".bold().blue(),
+                "install".bold().blue()
+            );
+            return exit_code::NOT_INSTALLED;
+        }
+    };
+    let Some(channel) = channel else {
+        info!(
+            "Lodestone Core is updated from the {} channel",
+            current.bold().blue()
+        );
+        output::result("channel", current);
+        return exit_code::SUCCESS;
+    };
+    if let Err(e) = metadata::set_channel(lodestone_path, channel).await {
+        error!("Failed to switch to the {} channel: {}", channel, e);
+        return exit_code::FAILURE;
+    }
+    info!(
+        "Switched from the {} channel to the {} channel, run '{}' to get its latest release",
+        current.bold().blue(),
+        channel.bold().blue(),
+        "update".bold().blue()
+    );
+    output::result("channel", channel);
+    exit_code::SUCCESS
+}
+
+async fn installed(lodestone_path: &Path) -> i32 {
+    let installed = match installed::installed_versions(lodestone_path) {
+        Ok(installed) => installed,
+        Err(e) => {
+            error!("Failed to list installed versions: {}", e);
+            return exit_code::FAILURE;
+        }
+    };
+    if installed.is_empty() {
+        info!(
+            "No version of Lodestone Core is installed under {}",
+            lodestone_path.display().bold().blue()
+        );
+        return exit_code::NOT_INSTALLED;
+    }
+    let current_version = get_current_version().await.ok();
+    if output::is_machine_readable() {
+        output::result(
+            "installed",
+            installed
+                .iter()
+                .map(|installed| {
+                    json!({
+                        "version": installed.version,
+                        "executable": lodestone_path.join(&installed.executable_name),
+                        "current": Some(&installed.version) == current_version.as_ref(),
+                    })
+                })
+                .collect::<Vec<_>>(),
+        );
+        return exit_code::SUCCESS;
+    }
+    println!("Installed versions:");
+    for installed in installed {
+        if Some(&installed.version) == current_version.as_ref() {
+            println!("  {} (current)", installed.version.on_blue());
+        } else {
+            println!("  {}", installed.version);
+        }
+    }
+    exit_code::SUCCESS
+}
+
+/// Deletes all but the `keep` newest versions, returns whether it succeeded
+///
+/// The current version and the one it replaced are always kept, so a rollback stays possible
+async fn prune_versions(lodestone_path: &Path, keep: usize) -> bool {
+    let protected = match Metadata::read_metadata(&metadata_path(lodestone_path)).await {
+        Ok(metadata) => std::iter::once(metadata.current_version)
+            .chain(metadata.previous.map(|previous| previous.current_version))
+            .collect(),
+        Err(_) => Vec::new(),
+    };
+    match installed::prune(lodestone_path, keep, &protected) {
+        Ok(pruned) => {
+            for version in &pruned {
+                info!("Deleted Lodestone Core {}", version.bold().blue());
+            }
+            output::result("pruned", pruned);
+            true
+        }
+        Err(e) => {
+            error!("Failed to delete old versions of Lodestone Core: {}", e);
+            false
+        }
+    }
+}
+
+async fn status(lodestone_path: &Path, port: u16, limits: &Limits) -> i32 {
+    match Metadata::read_metadata(&metadata_path(lodestone_path)).await {
+        Ok(metadata) => {
+            info!(
+                "Lodestone Core {} is installed",
+                metadata.current_version.bold().blue()
+            );
+            info!(
+                "Executable: {}",
+                lodestone_path
+                    .join(&metadata.executable_name)
+                    .display()
+                    .bold()
+                    .blue()
+            );
+            info!("Last updated: {}", metadata.last_updated);
+            info!("Release channel: {}", metadata.channel.bold().blue());
+            info!("Release source: {}", metadata.source.bold().blue());
+            if let Some(constraint) = &metadata.constraint {
+                info!("Version requirement: {}", constraint.bold().blue());
+            }
+            if let Some(previous) = &metadata.previous {
+                info!(
+                    "Previous version: {}{}",
+                    previous.current_version.bold().blue(),
+                    if metadata.pending_health_check {
+                        ", the current version hasn't started successfully yet"
+                    } else {
+                        ""
+                    }
+                );
+            }
+            for version in &metadata.failed_versions {
+                warn!(
+                    "Lodestone Core {} was rolled back after failing to start",
+                    version.bold().blue()
                 );
+            }
+            let process = match daemon::state(lodestone_path) {
+                Ok(DaemonState::Running(pid_file)) => {
+                    info!(
+                        "Running {} with PID {} since {}",
+                        if pid_file.detached {
+                            "in the background"
+                        } else {
+                            "in the foreground"
+                        },
+                        pid_file.pid.bold().blue(),
+                        pid_file.started_at
+                    );
+                    json!({ "state": "running", "pid": pid_file.pid, "detached": pid_file.detached, "started_at": pid_file.started_at })
+                }
+                Ok(DaemonState::Stale(pid_file)) => {
+                    warn!(
+                        "Not running, process {} stopped without cleaning up its PID file, probably because of a reboot",
+                        pid_file.pid
+                    );
+                    json!({ "state": "stale", "pid": pid_file.pid })
+                }
+                Ok(DaemonState::NotRunning) => json!({ "state": "stopped" }),
+                Err(e) => {
+                    warn!("{}", e);
+                    Value::Null
+                }
+            };
+            let supervisor = supervisor::SupervisorState::read(lodestone_path).ok();
+            let health = health::probe(port).await;
+            info!("API: {}", health.describe().bold().blue());
+            let cgroup = limits.needs_cgroup() && limits::scope_available(limits);
+            if !limits.is_empty() {
+                info!(
+                    "Limits: {}{}",
+                    limits.describe().bold().blue(),
+                    match (limits.needs_cgroup(), cgroup) {
+                        (false, _) => "",
+                        (true, true) => ", memory and CPU through a transient systemd scope",
+                        (true, false) => ", memory and CPU aren't enforced without systemd",
+                    }
+                );
+            }
+            if let Some(supervisor) = &supervisor {
+                info!(
+                    "Supervisor: {}, started at {}, {} restart(s)",
+                    supervisor.status.bold().blue(),
+                    supervisor.started_at,
+                    supervisor.restarts
+                );
+                if let Some(last_exit) = &supervisor.last_exit {
+                    info!(
+                        "Last exit: {} at {}",
+                        last_exit.describe().bold().blue(),
+                        last_exit.at
+                    );
+                }
+            }
+            output::result(
+                "status",
+                json!({
+                    "installed": true,
+                    "version": metadata.current_version,
+                    "executable": lodestone_path.join(&metadata.executable_name),
+                    "last_updated": metadata.last_updated,
+                    "channel": metadata.channel,
+                    "constraint": metadata.constraint,
+                    "source": metadata.source,
+                    "previous_version": metadata.previous.as_ref().map(|previous| &previous.current_version),
+                    "pending_health_check": metadata.pending_health_check,
+                    "failed_versions": metadata.failed_versions,
+                    "process": process,
+                    "supervisor": supervisor,
+                    "health": health,
+                    "limits": json!({
+                        "nice": limits.nice,
+                        "memory_max_mb": limits.memory_max_mb,
+                        "cpu_quota_percent": limits.cpu_quota_percent,
+                        "max_open_files": limits.max_open_files,
+                        "cgroup": cgroup,
+                    }),
+                }),
+            );
+            exit_code::SUCCESS
+        }
+        Err(_) => {
+            info!(
+                "Lodestone Core is not installed under {}",
+                lodestone_path.display().bold().blue()
+            );
+            output::result("status", json!({ "installed": false }));
+            exit_code::NOT_INSTALLED
+        }
+    }
+}
+
+async fn run_command(command: Command, args: &Args, resolved: &config::Resolved) -> i32 {
+    match command {
+        Command::Config(ConfigCommand::Show { origin }) => config::show_command(resolved, origin),
+        Command::List => list(&args.release_source(), args.channel).await,
+        Command::Channel { channel } => {
+            channel_command(&resolve_lodestone_path(args.install_path.as_ref()), channel).await
+        }
+        Command::SelfUpdate => match self_update(&args.verify_options()).await {
+            Ok(_) => exit_code::SUCCESS,
+            Err(e) => {
+                error!("Failed to self update: {e}");
+                exit_code::FAILURE
+            }
+        },
+        Command::Status => {
+            status(
+                &resolve_lodestone_path(args.install_path.as_ref()),
+                args.core_port(),
+                &args.limits(),
+            )
+            .await
+        }
+        Command::Health { wait } => health::health_command(args.core_port(), wait).await,
+        Command::Start {
+            detach,
+            skip_update_check,
+            core_args,
+        } => {
+            check_platform_support();
+            let lodestone_path = resolve_lodestone_path(args.install_path.as_ref());
+            let args = args.with_core_args(core_args);
+            daemon::start(&lodestone_path, detach, skip_update_check, &args).await
+        }
+        Command::Stop => {
+            daemon::stop(&resolve_lodestone_path(args.install_path.as_ref()), args).await
+        }
+        Command::Restart {
+            skip_update_check,
+            core_args,
+        } => {
+            check_platform_support();
+            let lodestone_path = resolve_lodestone_path(args.install_path.as_ref());
+            let args = args.with_core_args(core_args);
+            daemon::restart(&lodestone_path, skip_update_check, &args).await
+        }
+        Command::Service(ServiceCommand::Install {
+            system,
+            user,
+            print,
+        }) => service::install_command(system, user, print, args),
+        Command::Service(ServiceCommand::Uninstall { system }) => {
+            service::uninstall_command(system)
+        }
+        Command::Container(ContainerCommand::Build {
+            dir,
+            version,
+            base_image,
+            tag,
+            oci,
+        }) => container::build_command(&dir, version, base_image, tag, oci.as_deref(), args).await,
+        Command::Logs { lines, follow } => {
+            core_logs::logs_command(
+                &resolve_lodestone_path(args.install_path.as_ref()),
+                lines,
+                follow,
+            )
+            .await
+        }
+        Command::Backup(command) => {
+            backup::backup_command(
+                command,
+                &resolve_lodestone_path(args.install_path.as_ref()),
+                args.yes_all,
+            )
+            .await
+        }
+        Command::Installed => installed(&resolve_lodestone_path(args.install_path.as_ref())).await,
+        Command::Prune { keep } => {
+            let keep = keep.or(args.keep_versions).unwrap_or(2);
+            if prune_versions(&resolve_lodestone_path(args.install_path.as_ref()), keep).await {
+                exit_code::SUCCESS
             } else {
-                info!("Uninstalled lodestone successfully");
+                exit_code::FAILURE
             }
-        } else {
-            info!("Aborting uninstall, no file changes were made.");
         }
-        return;
+        Command::Use { version } => {
+            check_platform_support();
+            let lodestone_path = resolve_lodestone_path(args.install_path.as_ref());
+            install(&lodestone_path, Some(VersionSpec::Exact(version)), args).await
+        }
+        Command::Uninstall => uninstall::uninstall_command(
+            &resolve_lodestone_path(args.install_path.as_ref()),
+            args.yes_all,
+        ),
+        Command::Install {
+            version,
+            from: Some(from),
+        } => {
+            check_platform_support();
+            let lodestone_path = resolve_lodestone_path(args.install_path.as_ref());
+            install_from(&lodestone_path, &from, version, args).await
+        }
+        Command::Install {
+            version,
+            from: None,
+        } => {
+            check_platform_support();
+            let lodestone_path = resolve_lodestone_path(args.install_path.as_ref());
+            install(&lodestone_path, version, args).await
+        }
+        Command::ExportBundle { path, version } => {
+            export_bundle(
+                &resolve_lodestone_path(args.install_path.as_ref()),
+                path,
+                version,
+            )
+            .await
+        }
+        Command::Update { check } => {
+            check_platform_support();
+            let lodestone_path = resolve_lodestone_path(args.install_path.as_ref());
+            update(&lodestone_path, check, args).await
+        }
+        Command::Run {
+            skip_update_check,
+            core_args,
+        } => {
+            check_platform_support();
+            let lodestone_path = resolve_lodestone_path(args.install_path.as_ref());
+            let args = args.with_core_args(core_args);
+            run(&lodestone_path, skip_update_check, &args).await
+        }
+    }
+}
+
+/// The flag-only invocation from before subcommands existed, kept so existing scripts don't break
+async fn run_legacy(args: &Args) -> i32 {
+    if args.uses_legacy_flags() {
+        warn!(
+            "Passing actions as flags is deprecated and will be removed in a future release, see '{}' for the new subcommands",
+            "lodestone_cli --help".bold().blue()
+        );
+    }
+
+    if let Err(e) = self_update(&args.verify_options()).await {
+        error!("Failed to self update: {e}");
+    }
+
+    check_platform_support();
+
+    if args.list_versions {
+        return list(&args.release_source(), args.channel).await;
     }
-    let executable_path = update_manager::try_update(
+
+    let lodestone_path = resolve_lodestone_path(args.install_path.as_ref());
+
+    if args.uninstall {
+        return uninstall::uninstall_command(&lodestone_path, args.yes_all);
+    }
+
+    let executable_path = match install_version(
         &lodestone_path,
-        args.version,
-        args.yes_all,
+        args.version.clone(),
         args.skip_update_check,
+        args,
     )
     .await
-    .map_err(|e| {
-        error!(
-            "{}: {}, cli will now crash...",
-            "Error updating lodestone".bold().red(),
-            e
-        );
-        e
-    })
-    .unwrap();
-    if let Some(executable_path) = executable_path {
-        if args.run_core
-            || prompt_for_confirmation(
-                format!(
-                    "Would you like to run Lodestone Core right now? {}:",
-                    "(y/n)".magenta().bold()
-                ),
-                |input| input.trim() == "y" || input.trim() == "yes",
-            )
-        {
-            info!("Starting lodestone...");
-            if !args.run_core {
-                info!(
-                    "If you would like to run lodestone automatically, pass in the '{}' flag",
-                    "run-core".bold().blue()
-                );
-            }
-            run_lodestone(&executable_path)
-                .map_err(|e| {
-                    error!("Error running lodestone: {}, cli will now crash...", e);
-                    e
-                })
-                .unwrap()
+    {
+        Ok(executable_path) => executable_path,
+        Err(code) => return code,
+    };
+    if args.run_core
+        || prompt_for_confirmation(
+            format!(
+                "Would you like to run Lodestone Core right now? {}:",
+                "(y/n)".magenta().bold()
+            ),
+            |input| input.trim() == "y" || input.trim() == "yes",
+            PromptDefault::No,
+        )
+    {
+        if !args.run_core {
+            info!(
+                "If you would like to run lodestone automatically, use the '{}' subcommand",
+                "run".bold().blue()
+            );
         }
+        start_core(&lodestone_path, &executable_path, args).await
     } else {
-        info!("No Lodestone Core executable found, cli will now exit...")
+        exit_code::SUCCESS
+    }
+}
+
+#[tokio::main]
+async fn main() {
+    // setup_tracing();
+    let _ = color_eyre::install().map_err(|e| error!("color eyre install error {e}"));
+
+    let matches = Args::cli().get_matches();
+    let mut args = Args::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+    let loaded = config::read_args_json().and_then(|args_json| {
+        let resolved = config::load(args_json.as_ref(), args.command_line_config(&matches))?;
+        args.apply_config(resolved.config()?);
+        let file_args = args_json
+            .map(|args_json| serde_json::from_value::<Args>(Value::Object(args_json)))
+            .transpose()
+            .map_err(|e| color_eyre::eyre::eyre!("Failed to parse args.json: {e}"))?;
+        Ok((resolved, file_args))
+    });
+    let (resolved, file_args) = match loaded {
+        Ok(loaded) => loaded,
+        Err(e) => {
+            output::configure(args.output);
+            error!("{e:#}");
+            std::process::exit(output::finish(exit_code::FAILURE));
+        }
+    };
+    output::configure(args.output);
+    if args.non_interactive {
+        let _ = INTERACTIVE.set(false);
+    }
+
+    info!("Lodestone CLI v{}", VERSION.with(|v| v.to_string()));
+    if let Some(file_args) = file_args {
+        warn!(
+            "Reading options from args.json is deprecated, move them to {} instead",
+            config::user_config_path()
+                .map_or_else(
+                    || "a config file".to_string(),
+                    |path| path.display().to_string()
+                )
+                .bold()
+                .blue()
+        );
+        args.merge_legacy(file_args);
+    }
+
+    if args.command.is_some() && args.uses_legacy_flags() {
+        Args::cli()
+            .error(
+                ErrorKind::ArgumentConflict,
+                "the deprecated action flags cannot be used together with a subcommand",
+            )
+            .exit();
+    }
+
+    http::configure(args.retry_policy());
+    if args.source.is_none() {
+        args.source = remembered_source(args.install_path.as_ref()).await;
+    }
+
+    let code = match args.command.take() {
+        Some(command) => run_command(command, &args, &resolved).await,
+        None => run_legacy(&args).await,
+    };
+    std::process::exit(output::finish(code));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Args {
+        let matches = Args::cli()
+            .try_get_matches_from(std::iter::once("lodestone_cli").chain(args.iter().copied()))
+            .unwrap();
+        Args::from_arg_matches(&matches).unwrap()
+    }
+
+    #[test]
+    fn cli_is_valid() {
+        Args::cli().debug_assert();
+    }
+
+    #[test]
+    fn bool_options_take_an_optional_value() {
+        let args = parse(&["status"]);
+        assert!(!args.yes_all && !args.supervise);
+        let args = parse(&["--yes-all", "status", "--supervise"]);
+        assert!(args.yes_all && args.supervise);
+        let args = parse(&["-y", "--capture-logs=true", "status"]);
+        assert!(args.yes_all && args.capture_logs);
+        let args = parse(&["--yes-all=false", "status", "--skip-backup=false"]);
+        assert!(!args.yes_all && !args.skip_backup);
+        // the value has to follow a '=', so it isn't mistaken for a subcommand
+        assert!(Args::cli()
+            .try_get_matches_from(["lodestone_cli", "--yes-all", "false", "status"])
+            .is_err());
     }
 }
@@ -0,0 +1,171 @@
+use std::io::Write;
+use std::sync::{Mutex, OnceLock};
+
+use clap::ValueEnum;
+use color_eyre::owo_colors::OwoColorize;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+
+use crate::exit_code;
+
+/// How the cli reports what it does
+#[derive(ValueEnum, Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputFormat {
+    /// Coloured, human readable text
+    #[default]
+    Text,
+    /// A single JSON document on stdout once the command is done, logs are printed to stderr
+    Json,
+    /// One JSON event per line on stdout as things happen
+    Ndjson,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Level {
+    Info,
+    Warn,
+    Error,
+}
+
+impl Level {
+    fn as_str(self) -> &'static str {
+        match self {
+            Level::Info => "info",
+            Level::Warn => "warn",
+            Level::Error => "error",
+        }
+    }
+}
+
+#[derive(Default)]
+struct State {
+    result: Map<String, Value>,
+    logs: Vec<Value>,
+    last_error: Option<String>,
+}
+
+static FORMAT: OnceLock<OutputFormat> = OnceLock::new();
+static STATE: Mutex<Option<State>> = Mutex::new(None);
+
+/// Sets the output format, must be called before anything is printed
+pub fn configure(format: OutputFormat) {
+    let _ = FORMAT.set(format);
+}
+
+pub fn format() -> OutputFormat {
+    *FORMAT.get_or_init(OutputFormat::default)
+}
+
+/// Whether output is meant for other programs, in which case there are no colours, progress bars or prompts
+pub fn is_machine_readable() -> bool {
+    format() != OutputFormat::Text
+}
+
+/// Removes the ANSI escape sequences `OwoColorize` adds to messages
+pub fn strip_ansi(message: &str) -> String {
+    let mut stripped = String::with_capacity(message.len());
+    let mut chars = message.chars();
+    while let Some(c) = chars.next() {
+        if c == '\x1b' {
+            // skip until the final byte of the escape sequence
+            for c in chars.by_ref() {
+                if c.is_ascii_alphabetic() {
+                    break;
+                }
+            }
+        } else {
+            stripped.push(c);
+        }
+    }
+    stripped
+}
+
+fn with_state<T>(f: impl FnOnce(&mut State) -> T) -> T {
+    f(STATE.lock().unwrap().get_or_insert_with(State::default))
+}
+
+fn print_event(event: Value) {
+    let mut stdout = std::io::stdout().lock();
+    let _ = writeln!(stdout, "{event}");
+    let _ = stdout.flush();
+}
+
+/// Prints a log line, what the `info!`, `warn!` and `error!` macros expand to
+pub fn log(level: Level, message: String) {
+    match format() {
+        OutputFormat::Text => match level {
+            Level::Info => println!("{} {}", "[i]".green(), message),
+            Level::Warn => println!("{} {}", "[!!]".yellow(), message),
+            Level::Error => println!("{} {}", "[!!!]".red(), message),
+        },
+        OutputFormat::Json => {
+            let message = strip_ansi(&message);
+            eprintln!("[{}] {}", level.as_str(), message);
+            with_state(|state| {
+                if level == Level::Error {
+                    state.last_error = Some(message.clone());
+                }
+                state
+                    .logs
+                    .push(json!({ "level": level.as_str(), "message": message }));
+            });
+        }
+        OutputFormat::Ndjson => {
+            let message = strip_ansi(&message);
+            if level == Level::Error {
+                with_state(|state| state.last_error = Some(message.clone()));
+            }
+            print_event(json!({ "type": "log", "level": level.as_str(), "message": message }));
+        }
+    }
+}
+
+/// Reports part of the result of the current command under `key`, ignored in text mode
+pub fn result(key: &str, value: impl Serialize) {
+    let value = serde_json::to_value(value).unwrap_or(Value::Null);
+    match format() {
+        OutputFormat::Text => {}
+        OutputFormat::Json => with_state(|state| {
+            state.result.insert(key.to_string(), value);
+        }),
+        OutputFormat::Ndjson => print_event(json!({ "type": "result", key: value })),
+    }
+}
+
+/// Prints the final report for `code` and returns it, to be passed to `std::process::exit`
+pub fn finish(code: i32) -> i32 {
+    if !is_machine_readable() {
+        return code;
+    }
+    let (result, logs, last_error) = with_state(|state| {
+        (
+            std::mem::take(&mut state.result),
+            std::mem::take(&mut state.logs),
+            state.last_error.take(),
+        )
+    });
+    let error = exit_code::is_error(code).then(|| {
+        json!({
+            "code": exit_code::name(code),
+            "message": last_error.unwrap_or_else(|| exit_code::name(code).replace('_', " ")),
+        })
+    });
+    match format() {
+        OutputFormat::Json => print_event(json!({
+            "status": exit_code::name(code),
+            "exit_code": code,
+            "result": result,
+            "error": error,
+            "logs": logs,
+        })),
+        OutputFormat::Ndjson => print_event(json!({
+            "type": "exit",
+            "status": exit_code::name(code),
+            "exit_code": code,
+            "error": error,
+        })),
+        OutputFormat::Text => {}
+    }
+    code
+}
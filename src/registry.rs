@@ -0,0 +1,546 @@
+use color_eyre::eyre::{eyre, Context, Result};
+use futures_util::StreamExt;
+use indicatif::HumanBytes;
+use reqwest::header::{ACCEPT, AUTHORIZATION, WWW_AUTHENTICATE};
+use reqwest::{Response, StatusCode};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::fmt::Display;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Mutex;
+use tokio::io::AsyncWriteExt;
+
+use crate::{http, info, util};
+
+pub const OCI_INDEX: &str = "application/vnd.oci.image.index.v1+json";
+pub const OCI_MANIFEST: &str = "application/vnd.oci.image.manifest.v1+json";
+pub const OCI_CONFIG: &str = "application/vnd.oci.image.config.v1+json";
+pub const OCI_LAYER_GZIP: &str = "application/vnd.oci.image.layer.v1.tar+gzip";
+const DOCKER_MANIFEST_LIST: &str = "application/vnd.docker.distribution.manifest.list.v2+json";
+const DOCKER_MANIFEST: &str = "application/vnd.docker.distribution.manifest.v2+json";
+const DOCKER_LAYER_GZIP: &str = "application/vnd.docker.image.rootfs.diff.tar.gzip";
+
+/// Where images without a registry in their name come from
+const DOCKER_HUB: &str = "docker.io";
+const DOCKER_HUB_API: &str = "registry-1.docker.io";
+
+/// A content descriptor, pointing at a blob by its digest
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct Descriptor {
+    pub media_type: String,
+    pub digest: String,
+    pub size: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub platform: Option<Platform>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub annotations: BTreeMap<String, String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Platform {
+    pub architecture: String,
+    pub os: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub variant: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct ImageManifest {
+    pub schema_version: u32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub media_type: Option<String>,
+    pub config: Descriptor,
+    pub layers: Vec<Descriptor>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+struct ImageIndex {
+    manifests: Vec<Descriptor>,
+}
+
+/// The name of an image, e.g. `debian:bookworm-slim` or `ghcr.io/owner/image@sha256:...`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Reference {
+    pub registry: String,
+    pub repository: String,
+    /// A tag or a digest
+    pub reference: String,
+}
+
+impl FromStr for Reference {
+    type Err = color_eyre::eyre::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (name, reference) = match s.split_once('@') {
+            Some((name, digest)) => (name, digest.to_string()),
+            // a colon after the last slash separates the tag, one before it a registry port
+            None => match s.rsplit_once(':').filter(|(_, tag)| !tag.contains('/')) {
+                Some((name, tag)) => (name, tag.to_string()),
+                None => (s, "latest".to_string()),
+            },
+        };
+        let (registry, repository) = match name.split_once('/') {
+            Some((host, path))
+                if host.contains('.') || host.contains(':') || host == "localhost" =>
+            {
+                (host.to_string(), path.to_string())
+            }
+            _ => (DOCKER_HUB.to_string(), name.to_string()),
+        };
+        if repository.is_empty() || reference.is_empty() {
+            return Err(eyre!("'{s}' is not a valid image name"));
+        }
+        let repository = if registry == DOCKER_HUB && !repository.contains('/') {
+            format!("library/{repository}")
+        } else {
+            repository
+        };
+        Ok(Reference {
+            registry,
+            repository,
+            reference,
+        })
+    }
+}
+
+impl Display for Reference {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let separator = if self.reference.contains(':') {
+            '@'
+        } else {
+            ':'
+        };
+        write!(
+            f,
+            "{}/{}{}{}",
+            self.registry, self.repository, separator, self.reference
+        )
+    }
+}
+
+/// The OCI name of the architecture the cli was built for
+pub fn architecture() -> &'static str {
+    match std::env::consts::ARCH {
+        "x86_64" => "amd64",
+        "aarch64" => "arm64",
+        "x86" => "386",
+        arch => arch,
+    }
+}
+
+/// An image pulled into a directory of blobs
+#[derive(Debug)]
+pub struct PulledImage {
+    /// The image config, to be extended before it is written back
+    pub config: serde_json::Value,
+    /// The layers, saved as `<blobs dir>/<hex digest>`
+    pub layers: Vec<Descriptor>,
+}
+
+/// Pulls images anonymously from an OCI distribution registry, such as Docker Hub or GHCR
+pub struct Registry {
+    image: Reference,
+    token: Mutex<Option<String>>,
+}
+
+/// Splits the parameters of a `WWW-Authenticate: Bearer` challenge, values can be quoted and contain commas
+fn challenge_params(challenge: &str) -> BTreeMap<String, String> {
+    let mut params = BTreeMap::new();
+    let mut rest = challenge.trim();
+    while let Some((key, after)) = rest.split_once('=') {
+        let key = key.trim().trim_start_matches(',').trim().to_lowercase();
+        let (value, after) = match after.strip_prefix('"') {
+            Some(quoted) => match quoted.split_once('"') {
+                Some((value, after)) => (value, after),
+                None => (quoted, ""),
+            },
+            None => after.split_once(',').unwrap_or((after, "")),
+        };
+        params.insert(key, value.to_string());
+        rest = after.trim_start_matches(',');
+    }
+    params
+}
+
+impl Registry {
+    pub fn new(image: Reference) -> Registry {
+        Registry {
+            image,
+            token: Mutex::new(None),
+        }
+    }
+
+    fn url(&self, path: &str) -> String {
+        let host = if self.image.registry == DOCKER_HUB {
+            DOCKER_HUB_API
+        } else {
+            &self.image.registry
+        };
+        // local registries usually don't have a certificate
+        let scheme = if host.starts_with("localhost") || host.starts_with("127.0.0.1") {
+            "http"
+        } else {
+            "https"
+        };
+        format!("{scheme}://{host}/v2/{}/{path}", self.image.repository)
+    }
+
+    /// Gets an anonymous pull token from the auth server named in a `WWW-Authenticate` challenge
+    async fn authenticate(&self, challenge: &str) -> Result<()> {
+        let Some(params) = challenge
+            .strip_prefix("Bearer ")
+            .map(challenge_params)
+            .filter(|params| params.contains_key("realm"))
+        else {
+            return Err(eyre!(
+                "{} needs credentials, only public images can be pulled",
+                self.image.registry
+            ));
+        };
+        let mut request = http::client()
+            .get(&params["realm"])
+            .timeout(http::policy().timeout);
+        let scope = params
+            .get("scope")
+            .cloned()
+            .unwrap_or_else(|| format!("repository:{}:pull", self.image.repository));
+        request = request.query(&[("scope", scope)]);
+        if let Some(service) = params.get("service") {
+            request = request.query(&[("service", service)]);
+        }
+        let response: serde_json::Value = request.send().await?.error_for_status()?.json().await?;
+        let token = response["token"]
+            .as_str()
+            .or(response["access_token"].as_str())
+            .ok_or_else(|| eyre!("The auth server of {} sent no token", self.image.registry))?;
+        *self.token.lock().unwrap() = Some(token.to_string());
+        Ok(())
+    }
+
+    /// GETs `path` of the repository, authenticating if the registry asks for it
+    async fn get(&self, path: &str, accept: &[&str], timeout: bool) -> Result<Response> {
+        let send = || {
+            let mut request = http::client().get(self.url(path));
+            if !accept.is_empty() {
+                request = request.header(ACCEPT, accept.join(", "));
+            }
+            if let Some(token) = self.token.lock().unwrap().as_ref() {
+                request = request.header(AUTHORIZATION, format!("Bearer {token}"));
+            }
+            if timeout {
+                request = request.timeout(http::policy().timeout);
+            }
+            request.send()
+        };
+        let mut response = send().await?;
+        if response.status() == StatusCode::UNAUTHORIZED {
+            let challenge = response
+                .headers()
+                .get(WWW_AUTHENTICATE)
+                .and_then(|value| value.to_str().ok())
+                .unwrap_or_default()
+                .to_string();
+            self.authenticate(&challenge).await?;
+            response = send().await?;
+        }
+        if response.status() == StatusCode::NOT_FOUND {
+            return Err(eyre!("{} was not found", self.image));
+        }
+        Ok(response.error_for_status()?)
+    }
+
+    /// Fetches the manifest `reference`, checking it against its digest if it is one
+    async fn manifest_bytes(&self, reference: &str) -> Result<(String, Vec<u8>)> {
+        let accept = [
+            OCI_INDEX,
+            OCI_MANIFEST,
+            DOCKER_MANIFEST_LIST,
+            DOCKER_MANIFEST,
+        ];
+        http::retry(&format!("GET manifest of {}", self.image), || async {
+            let response = self
+                .get(&format!("manifests/{reference}"), &accept, true)
+                .await?;
+            let media_type = response
+                .headers()
+                .get(reqwest::header::CONTENT_TYPE)
+                .and_then(|value| value.to_str().ok())
+                .unwrap_or_default()
+                .to_string();
+            let bytes = response.bytes().await?.to_vec();
+            if let Some(expected) = reference.strip_prefix("sha256:") {
+                let actual = util::to_hex(&Sha256::digest(&bytes));
+                if actual != expected {
+                    return Err(eyre!("The manifest {reference} doesn't match its digest"));
+                }
+            }
+            Ok((media_type, bytes))
+        })
+        .await
+    }
+
+    /// Finds the manifest of the image for Linux on this architecture, following an index if the image has one
+    async fn platform_manifest(&self) -> Result<ImageManifest> {
+        let (media_type, bytes) = self.manifest_bytes(&self.image.reference).await?;
+        let value: serde_json::Value =
+            serde_json::from_slice(&bytes).context("Malformed image manifest")?;
+        let media_type = value["mediaType"]
+            .as_str()
+            .unwrap_or(&media_type)
+            .to_string();
+        if media_type != OCI_INDEX
+            && media_type != DOCKER_MANIFEST_LIST
+            && value.get("manifests").is_none()
+        {
+            return serde_json::from_value(value).context("Malformed image manifest");
+        }
+        let index: ImageIndex = serde_json::from_value(value).context("Malformed image index")?;
+        let descriptor = index
+            .manifests
+            .iter()
+            .find(|descriptor| {
+                descriptor.platform.as_ref().is_some_and(|platform| {
+                    platform.os == "linux" && platform.architecture == architecture()
+                })
+            })
+            .ok_or_else(|| eyre!("{} has no linux/{} image", self.image, architecture()))?;
+        let (_, bytes) = self.manifest_bytes(&descriptor.digest).await?;
+        serde_json::from_slice(&bytes).context("Malformed image manifest")
+    }
+
+    /// Downloads the blob `descriptor` to `dest`, checking its digest
+    async fn blob(&self, descriptor: &Descriptor, dest: &Path) -> Result<()> {
+        let expected = descriptor
+            .digest
+            .strip_prefix("sha256:")
+            .ok_or_else(|| eyre!("Unsupported digest {}", descriptor.digest))?;
+        let partial = dest.with_extension("partial");
+        let downloaded = http::retry(&format!("Download of {}", descriptor.digest), || async {
+            let response = self
+                .get(&format!("blobs/{}", descriptor.digest), &[], false)
+                .await?;
+            let mut file = tokio::fs::File::create(&partial).await?;
+            let mut hasher = Sha256::new();
+            let mut stream = response.bytes_stream();
+            loop {
+                // a blob can take longer than the timeout, only waiting for the next chunk is limited
+                let chunk = match tokio::time::timeout(http::policy().timeout, stream.next()).await
+                {
+                    Ok(Some(chunk)) => chunk.context("Download interrupted")?,
+                    Ok(None) => break,
+                    Err(e) => return Err(e).context("Download timed out"),
+                };
+                hasher.update(&chunk);
+                file.write_all(&chunk).await?;
+            }
+            file.flush().await?;
+            if util::to_hex(&hasher.finalize()) != expected {
+                return Err(eyre!("{} doesn't match its digest", descriptor.digest));
+            }
+            Ok(())
+        })
+        .await;
+        if let Err(e) = downloaded {
+            let _ = std::fs::remove_file(&partial);
+            return Err(e);
+        }
+        tokio::fs::rename(&partial, dest).await?;
+        Ok(())
+    }
+
+    /// Pulls the config and layers of the image for Linux on this architecture into `blobs_dir`
+    ///
+    /// Docker layer types are renamed to their OCI equivalent, the blobs themselves are the same
+    pub async fn pull(&self, blobs_dir: &Path) -> Result<PulledImage> {
+        info!("Pulling {} for linux/{}", self.image, architecture());
+        let manifest = self.platform_manifest().await?;
+        let config_path = blob_path(blobs_dir, &manifest.config.digest)?;
+        self.blob(&manifest.config, &config_path).await?;
+        let config = serde_json::from_slice(&std::fs::read(&config_path)?)
+            .context("Malformed image config")?;
+        // only the new config is part of the image
+        std::fs::remove_file(&config_path)?;
+
+        let mut layers = Vec::new();
+        for layer in manifest.layers {
+            info!(
+                "Pulling layer {} ({})",
+                layer.digest,
+                HumanBytes(layer.size)
+            );
+            self.blob(&layer, &blob_path(blobs_dir, &layer.digest)?)
+                .await?;
+            let media_type = if layer.media_type == DOCKER_LAYER_GZIP {
+                OCI_LAYER_GZIP.to_string()
+            } else {
+                layer.media_type
+            };
+            layers.push(Descriptor {
+                media_type,
+                platform: None,
+                annotations: BTreeMap::new(),
+                ..layer
+            });
+        }
+        Ok(PulledImage { config, layers })
+    }
+}
+
+/// Where the blob `digest` goes in `blobs_dir`, the `blobs/sha256` directory of an OCI layout
+pub fn blob_path(blobs_dir: &Path, digest: &str) -> Result<PathBuf> {
+    let hex = digest
+        .strip_prefix("sha256:")
+        .filter(|hex| hex.len() == 64 && hex.chars().all(|c| c.is_ascii_hexdigit()))
+        .ok_or_else(|| eyre!("Unsupported digest {digest}"))?;
+    Ok(blobs_dir.join(hex))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn reference(s: &str) -> (String, String, String) {
+        let reference: Reference = s.parse().unwrap();
+        (
+            reference.registry,
+            reference.repository,
+            reference.reference,
+        )
+    }
+
+    fn owned(registry: &str, repository: &str, reference: &str) -> (String, String, String) {
+        (
+            registry.to_string(),
+            repository.to_string(),
+            reference.to_string(),
+        )
+    }
+
+    #[test]
+    fn official_images_are_on_docker_hub_under_library() {
+        assert_eq!(
+            reference("debian:bookworm-slim"),
+            owned("docker.io", "library/debian", "bookworm-slim")
+        );
+        assert_eq!(
+            reference("debian"),
+            owned("docker.io", "library/debian", "latest")
+        );
+        assert_eq!(
+            reference("docker.io/debian:12"),
+            owned("docker.io", "library/debian", "12")
+        );
+        // images of users and organisations don't get the prefix
+        assert_eq!(
+            reference("owner/image:1.0"),
+            owned("docker.io", "owner/image", "1.0")
+        );
+    }
+
+    #[test]
+    fn a_colon_before_the_last_slash_is_a_registry_port() {
+        assert_eq!(
+            reference("localhost:5000/app"),
+            owned("localhost:5000", "app", "latest")
+        );
+        assert_eq!(
+            reference("localhost:5000/team/app:v2"),
+            owned("localhost:5000", "team/app", "v2")
+        );
+        assert_eq!(
+            reference("localhost/app"),
+            owned("localhost", "app", "latest")
+        );
+        assert_eq!(
+            reference("registry.example.com:443/a/b:tag"),
+            owned("registry.example.com:443", "a/b", "tag")
+        );
+    }
+
+    #[test]
+    fn digests_are_kept_whole() {
+        let digest = format!("sha256:{}", "ab".repeat(32));
+        assert_eq!(
+            reference(&format!("ghcr.io/owner/image@{digest}")),
+            owned("ghcr.io", "owner/image", &digest)
+        );
+        let reference: Reference = format!("localhost:5000/app@{digest}").parse().unwrap();
+        assert_eq!(
+            reference.to_string(),
+            format!("localhost:5000/app@{digest}")
+        );
+    }
+
+    #[test]
+    fn display_names_the_registry() {
+        let reference: Reference = "debian:bookworm-slim".parse().unwrap();
+        assert_eq!(
+            reference.to_string(),
+            "docker.io/library/debian:bookworm-slim"
+        );
+    }
+
+    #[test]
+    fn invalid_references_are_rejected() {
+        assert!("".parse::<Reference>().is_err());
+        assert!("debian:".parse::<Reference>().is_err());
+        assert!("debian@".parse::<Reference>().is_err());
+    }
+
+    fn params(challenge: &str) -> Vec<(String, String)> {
+        challenge_params(challenge).into_iter().collect()
+    }
+
+    fn pairs(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+        pairs
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn challenge_params_of_docker_hub() {
+        assert_eq!(
+            params(
+                r#"realm="https://auth.docker.io/token",service="registry.docker.io",scope="repository:library/debian:pull""#
+            ),
+            pairs(&[
+                ("realm", "https://auth.docker.io/token"),
+                ("scope", "repository:library/debian:pull"),
+                ("service", "registry.docker.io"),
+            ])
+        );
+    }
+
+    #[test]
+    fn challenge_params_keep_commas_in_quotes() {
+        assert_eq!(
+            params(r#"realm="https://auth.example.com/token", scope="repository:app:pull,push""#),
+            pairs(&[
+                ("realm", "https://auth.example.com/token"),
+                ("scope", "repository:app:pull,push"),
+            ])
+        );
+    }
+
+    #[test]
+    fn challenge_params_without_quotes() {
+        assert_eq!(
+            params("Realm=http://localhost:5555/token, Service=local"),
+            pairs(&[
+                ("realm", "http://localhost:5555/token"),
+                ("service", "local"),
+            ])
+        );
+        // an unterminated quote runs to the end
+        assert_eq!(
+            params(r#"realm="http://localhost/token"#),
+            pairs(&[("realm", "http://localhost/token")])
+        );
+        assert!(params("").is_empty());
+    }
+}
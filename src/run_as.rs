@@ -0,0 +1,210 @@
+use color_eyre::eyre::{eyre, Context, Result};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use crate::{backup, config, core_logs};
+
+/// A user Lodestone Core can be run as with `--run-as`
+#[derive(Debug, Clone)]
+pub struct Account {
+    pub name: String,
+    pub uid: u32,
+    /// The primary group, the only group Lodestone Core keeps
+    pub gid: u32,
+    pub home: PathBuf,
+}
+
+/// Finds the user named `name`
+#[cfg(unix)]
+pub fn lookup(name: &str) -> Option<Account> {
+    use std::ffi::{CStr, CString};
+    use std::os::unix::ffi::OsStrExt;
+    let c_name = CString::new(name).ok()?;
+    // SAFETY: getpwnam returns null or a pointer to a valid passwd, which is copied before any other call
+    unsafe {
+        let passwd = libc::getpwnam(c_name.as_ptr());
+        if passwd.is_null() || (*passwd).pw_dir.is_null() {
+            return None;
+        }
+        let home = CStr::from_ptr((*passwd).pw_dir);
+        Some(Account {
+            name: name.to_string(),
+            uid: (*passwd).pw_uid,
+            gid: (*passwd).pw_gid,
+            home: PathBuf::from(std::ffi::OsStr::from_bytes(home.to_bytes())),
+        })
+    }
+}
+
+#[cfg(not(unix))]
+pub fn lookup(_name: &str) -> Option<Account> {
+    None
+}
+
+#[cfg(unix)]
+fn effective_uid() -> u32 {
+    // SAFETY: geteuid has no memory safety requirements
+    unsafe { libc::geteuid() }
+}
+
+/// Whether the cli has to drop its privileges to run Lodestone Core as `account`
+#[cfg(unix)]
+pub fn needs_switch(account: &Account) -> bool {
+    account.uid != effective_uid()
+}
+
+#[cfg(not(unix))]
+pub fn needs_switch(_account: &Account) -> bool {
+    false
+}
+
+/// Whether the top level entry `name` of `LODESTONE_PATH` is only used by the cli, so Lodestone Core doesn't need to own it
+pub fn is_cli_entry(name: &str) -> bool {
+    name == "tmp"
+        || name == backup::BACKUP_DIR_NAME
+        || name == core_logs::LOGS_DIR_NAME
+        || name == config::INSTALL_CONFIG_FILE_NAME
+        || name.starts_with(".lodestone_cli")
+}
+
+/// Whether `account` may enter the directory with `metadata`, going by its mode bits
+///
+/// Only the primary group of `account` is considered, the only group Lodestone Core keeps, and ACLs are ignored
+#[cfg(unix)]
+fn can_search(metadata: &std::fs::Metadata, account: &Account) -> bool {
+    use std::os::unix::fs::MetadataExt;
+    let mode = metadata.mode();
+    if account.uid == 0 {
+        true
+    } else if metadata.uid() == account.uid {
+        mode & 0o100 != 0
+    } else if metadata.gid() == account.gid {
+        mode & 0o010 != 0
+    } else {
+        mode & 0o001 != 0
+    }
+}
+
+/// Adds `path` and everything under it not owned by `uid` to `unowned`, without following symbolic links
+#[cfg(unix)]
+fn find_unowned(path: &Path, uid: u32, top_level: bool, unowned: &mut Vec<PathBuf>) -> Result<()> {
+    use std::os::unix::fs::MetadataExt;
+    let metadata = std::fs::symlink_metadata(path)
+        .with_context(|| format!("Failed to read {}", path.display()))?;
+    if metadata.uid() != uid {
+        unowned.push(path.to_owned());
+    }
+    if !metadata.is_dir() {
+        return Ok(());
+    }
+    for entry in
+        std::fs::read_dir(path).with_context(|| format!("Failed to read {}", path.display()))?
+    {
+        let entry = entry?;
+        if top_level && is_cli_entry(&entry.file_name().to_string_lossy()) {
+            continue;
+        }
+        find_unowned(&entry.path(), uid, false, unowned)?;
+    }
+    Ok(())
+}
+
+/// Checks that Lodestone Core can run as `account` under `lodestone_path`, returning what under it `account`
+/// doesn't own
+///
+/// Fails if the cli can't switch to `account`, or if `account` can't reach `lodestone_path` at all, which changing the
+/// owner of what is under it doesn't fix
+#[cfg(unix)]
+pub fn check(lodestone_path: &Path, account: &Account) -> Result<Vec<PathBuf>> {
+    if !needs_switch(account) {
+        return Ok(Vec::new());
+    }
+    if !crate::service::is_root() {
+        return Err(eyre!(
+            "Only root can run Lodestone Core as {}, the cli runs as uid {}",
+            account.name,
+            effective_uid()
+        ));
+    }
+    let lodestone_path = std::fs::canonicalize(lodestone_path)
+        .with_context(|| format!("Failed to read {}", lodestone_path.display()))?;
+    for ancestor in lodestone_path.ancestors().skip(1) {
+        let metadata = std::fs::metadata(ancestor)
+            .with_context(|| format!("Failed to read {}", ancestor.display()))?;
+        if !can_search(&metadata, account) {
+            return Err(eyre!(
+                "{} can't reach {} since it can't enter {}, install Lodestone Core somewhere else or give {} access to it. \
+                 Only the owner, the primary group of {} and the mode bits are checked, access granted by an ACL isn't seen",
+                account.name,
+                lodestone_path.display(),
+                ancestor.display(),
+                account.name,
+                account.name
+            ));
+        }
+    }
+    let mut unowned = Vec::new();
+    find_unowned(&lodestone_path, account.uid, true, &mut unowned)?;
+    Ok(unowned)
+}
+
+#[cfg(not(unix))]
+pub fn check(_lodestone_path: &Path, _account: &Account) -> Result<Vec<PathBuf>> {
+    Err(eyre!(
+        "Running Lodestone Core as another user is only supported on Unix"
+    ))
+}
+
+/// Gives `paths` to `account` and its primary group
+#[cfg(unix)]
+pub fn change_owner(paths: &[PathBuf], account: &Account) -> Result<()> {
+    for path in paths {
+        std::os::unix::fs::lchown(path, Some(account.uid), Some(account.gid))
+            .with_context(|| format!("Failed to change the owner of {}", path.display()))?;
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn change_owner(_paths: &[PathBuf], _account: &Account) -> Result<()> {
+    Err(eyre!(
+        "Running Lodestone Core as another user is only supported on Unix"
+    ))
+}
+
+/// Sets the environment variables naming the user of `command` to the ones of `account`
+///
+/// Neither `drop_privileges` nor `systemd-run --uid` change them, so Lodestone Core would otherwise see the ones of
+/// root
+pub fn set_environment(command: &mut Command, account: &Account) {
+    command
+        .env("HOME", &account.home)
+        .env("USER", &account.name)
+        .env("LOGNAME", &account.name);
+}
+
+/// Makes `command` run as `account`, with only its primary group
+///
+/// The privileges are dropped right before the command executes, after anything else set up with `pre_exec` that
+/// still needs them
+#[cfg(unix)]
+pub fn drop_privileges(command: &mut Command, account: &Account) {
+    use std::os::unix::process::CommandExt;
+    let (uid, gid) = (account.uid, account.gid);
+    // SAFETY: setgroups, setgid and setuid are async-signal-safe
+    unsafe {
+        command.pre_exec(move || {
+            let groups = [gid as libc::gid_t];
+            if libc::setgroups(1, groups.as_ptr()) == -1
+                || libc::setgid(gid) == -1
+                || libc::setuid(uid) == -1
+            {
+                return Err(std::io::Error::last_os_error());
+            }
+            Ok(())
+        });
+    }
+}
+
+#[cfg(not(unix))]
+pub fn drop_privileges(_command: &mut Command, _account: &Account) {}
@@ -1,10 +1,229 @@
-use crate::{error, info};
-use color_eyre::eyre::Result;
-use color_eyre::owo_colors::OwoColorize;
-use ctrlc::set_handler;
-use std::sync::{Arc, Mutex};
-use std::{path::Path, process::Command};
-pub fn run_lodestone(executable_path: &Path) -> Result<()> {
+use crate::core_logs::{self, LogFile, Stream};
+use crate::limits::{self, Limits};
+use crate::run_as::Account;
+use crate::{error, info, output, warn};
+use color_eyre::eyre::{eyre, Result};
+use std::collections::BTreeMap;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{mpsc, Arc, Mutex, OnceLock};
+use std::time::Duration;
+use std::{
+    path::Path,
+    process::{Child, ExitStatus, Stdio},
+};
+
+/// How long Lodestone Core gets to stop after being asked to, when `--shutdown-timeout` isn't set
+pub const DEFAULT_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How often the running core is checked for having exited
+const WAIT_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// How long the last lines of output of the core are waited for after it exits
+///
+/// Processes started by the core can keep its output open, they shouldn't keep the cli from exiting
+const LOG_DRAIN_TIMEOUT: Duration = Duration::from_secs(1);
+
+static RUNNING_PROCESS: Mutex<Option<Child>> = Mutex::new(None);
+static STOP_REQUESTED: AtomicBool = AtomicBool::new(false);
+/// Counts the cores started, so a delayed kill never hits a core started after the one it was meant for
+static LAUNCHES: AtomicU64 = AtomicU64::new(0);
+static SHUTDOWN_TIMEOUT: OnceLock<Duration> = OnceLock::new();
+static LAUNCH_OPTIONS: OnceLock<LaunchOptions> = OnceLock::new();
+
+/// What Lodestone Core is launched with besides `--is-cli`, the same on every launch, restarts included
+#[derive(Debug, Clone, Default)]
+pub struct LaunchOptions {
+    /// Passed to Lodestone Core after `--is-cli`
+    pub args: Vec<String>,
+    /// Set for Lodestone Core on top of the environment of the cli
+    pub env: BTreeMap<String, String>,
+    pub limits: Limits,
+    /// The user Lodestone Core runs as, if the cli has to switch to it
+    pub run_as: Option<Account>,
+}
+
+/// An environment variable for Lodestone Core, given as `KEY=VALUE`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EnvVar {
+    pub key: String,
+    pub value: String,
+}
+
+impl FromStr for EnvVar {
+    type Err = color_eyre::eyre::Report;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once('=') {
+            Some((key, value)) if !key.is_empty() && !s.contains('\0') => Ok(EnvVar {
+                key: key.to_string(),
+                value: value.to_string(),
+            }),
+            _ => Err(eyre!(
+                "invalid environment variable '{s}', expected KEY=VALUE"
+            )),
+        }
+    }
+}
+
+/// Sets how long Lodestone Core gets to stop before it is killed and what it is launched with, must be called
+/// before the core is started to take effect
+pub fn configure(shutdown_timeout: Duration, launch_options: LaunchOptions) {
+    let _ = SHUTDOWN_TIMEOUT.set(shutdown_timeout);
+    let _ = LAUNCH_OPTIONS.set(launch_options);
+}
+
+fn shutdown_timeout() -> Duration {
+    *SHUTDOWN_TIMEOUT.get_or_init(|| DEFAULT_SHUTDOWN_TIMEOUT)
+}
+
+/// Whether Lodestone Core was stopped with CTRL+C or a signal, rather than exiting on its own
+pub fn stop_requested() -> bool {
+    STOP_REQUESTED.load(Ordering::SeqCst)
+}
+
+/// A signal asking the cli to stop
+#[derive(Debug, Clone, Copy)]
+enum StopSignal {
+    Interrupt,
+    Terminate,
+    /// The terminal of the cli was closed
+    Hangup,
+}
+
+impl StopSignal {
+    fn name(self) -> &'static str {
+        match self {
+            StopSignal::Interrupt => "SIGINT",
+            StopSignal::Terminate => "SIGTERM",
+            StopSignal::Hangup => "SIGHUP",
+        }
+    }
+}
+
+/// Asks the running core to stop, the way the cli itself was asked to
+///
+/// SIGINT and SIGTERM are forwarded as is. The core doesn't share the terminal of the cli, so a hangup is
+/// forwarded as SIGTERM
+#[cfg(unix)]
+fn forward(signal: StopSignal) {
+    let signal = match signal {
+        StopSignal::Interrupt => libc::SIGINT,
+        StopSignal::Terminate | StopSignal::Hangup => libc::SIGTERM,
+    };
+    if let Some(process) = RUNNING_PROCESS.lock().unwrap().as_mut() {
+        // the process can't be reaped while the lock is held, so its pid can't have been reused
+        if let Ok(None) = process.try_wait() {
+            // SAFETY: kill has no memory safety requirements
+            unsafe { libc::kill(process.id() as libc::pid_t, signal) };
+        }
+    }
+}
+
+/// The console delivers CTRL+C to the core as well, there is nothing to forward on Windows
+#[cfg(not(unix))]
+fn forward(_signal: StopSignal) {}
+
+fn kill() {
+    if let Some(process) = RUNNING_PROCESS.lock().unwrap().as_mut() {
+        let _ = process.kill(); //unlikely to fail
+    }
+}
+
+#[cfg(unix)]
+async fn stop_signals(mut on_signal: impl FnMut(StopSignal)) -> Result<()> {
+    use tokio::signal::unix::{signal, SignalKind};
+    let mut interrupt = signal(SignalKind::interrupt())?;
+    let mut terminate = signal(SignalKind::terminate())?;
+    let mut hangup = signal(SignalKind::hangup())?;
+    loop {
+        tokio::select! {
+            _ = interrupt.recv() => on_signal(StopSignal::Interrupt),
+            _ = terminate.recv() => on_signal(StopSignal::Terminate),
+            _ = hangup.recv() => on_signal(StopSignal::Hangup),
+        }
+    }
+}
+
+#[cfg(not(unix))]
+async fn stop_signals(mut on_signal: impl FnMut(StopSignal)) -> Result<()> {
+    loop {
+        tokio::signal::ctrl_c().await?;
+        on_signal(StopSignal::Interrupt);
+    }
+}
+
+/// Stops Lodestone Core gracefully when the cli receives SIGINT, SIGTERM or SIGHUP, for as long as the cli runs
+///
+/// The first signal is forwarded to the core, which is killed if it is still running after the shutdown timeout.
+/// A second signal kills it straight away
+pub fn handle_signals() {
+    tokio::spawn(async {
+        let result = stop_signals(|signal| {
+            if STOP_REQUESTED.swap(true, Ordering::SeqCst) {
+                warn!("Received {} while stopping, killing Lodestone Core", signal.name());
+                kill();
+                return;
+            }
+            let timeout = shutdown_timeout();
+            info!(
+                "Received {}, stopping Lodestone Core. It will be killed if it is still running in {}s, send the signal again to kill it now",
+                signal.name(),
+                timeout.as_secs()
+            );
+            let launch = LAUNCHES.load(Ordering::SeqCst);
+            forward(signal);
+            tokio::spawn(async move {
+                tokio::time::sleep(timeout).await;
+                let mut running = RUNNING_PROCESS.lock().unwrap();
+                if let Some(process) = running.as_mut() {
+                    // a core launched since the signal, e.g. by a rollback, isn't the one asked to stop
+                    if LAUNCHES.load(Ordering::SeqCst) == launch
+                        && matches!(process.try_wait(), Ok(None))
+                    {
+                        warn!(
+                            "Lodestone Core didn't stop within {}s, killing it",
+                            timeout.as_secs()
+                        );
+                        let _ = process.kill();
+                    }
+                }
+            });
+        })
+        .await;
+        if let Err(e) = result {
+            error!("Error setting up signal handler: {}", e);
+        }
+    });
+}
+
+/// Stops the running core without it counting as a requested stop, for example because it stopped answering
+///
+/// It gets the shutdown timeout to stop, like after SIGTERM, and is killed if it is still running after that
+pub fn terminate() {
+    let launch = LAUNCHES.load(Ordering::SeqCst);
+    if cfg!(unix) {
+        forward(StopSignal::Terminate);
+    } else {
+        kill();
+        return;
+    }
+    tokio::spawn(async move {
+        tokio::time::sleep(shutdown_timeout()).await;
+        let mut running = RUNNING_PROCESS.lock().unwrap();
+        if let Some(process) = running.as_mut() {
+            if LAUNCHES.load(Ordering::SeqCst) == launch && matches!(process.try_wait(), Ok(None)) {
+                warn!(
+                    "Lodestone Core didn't stop within {}s, killing it",
+                    shutdown_timeout().as_secs()
+                );
+                let _ = process.kill();
+            }
+        }
+    });
+}
+
+pub fn run_lodestone(executable_path: &Path) -> Result<ExitStatus> {
     info!("Running Lodestone Core at {}", &executable_path.display());
 
     #[cfg(unix)]
@@ -15,28 +234,139 @@ pub fn run_lodestone(executable_path: &Path) -> Result<()> {
         std::fs::set_permissions(executable_path, permissions)?;
     }
 
-    let process = Command::new(executable_path).arg("--is-cli").spawn()?;
+    let launch_options = LAUNCH_OPTIONS.get_or_init(LaunchOptions::default);
+    if !launch_options.args.is_empty() {
+        info!("Passing {:?} to Lodestone Core", launch_options.args);
+    }
+    if !launch_options.env.is_empty() {
+        // only the names, the values can be secrets
+        info!(
+            "Setting {} for Lodestone Core",
+            launch_options
+                .env
+                .keys()
+                .map(String::as_str)
+                .collect::<Vec<_>>()
+                .join(", ")
+        );
+    }
 
-    // let pid = process.id();
+    if !launch_options.limits.is_empty() {
+        info!(
+            "Limiting Lodestone Core to {}",
+            launch_options.limits.describe()
+        );
+    }
 
-    // Set up signal handler for CTRL+C
-    let process = Arc::new(Mutex::new(process));
-    let signal_process = process.clone();
-    match set_handler(move || {
-        info!("Killing Lodestone Core");
-        let _ = signal_process.lock().unwrap().kill(); //unlikely to fail
-    }) {
-        Ok(_) => {}
+    if let Some(account) = &launch_options.run_as {
+        info!("Running Lodestone Core as {}", account.name);
+    }
+    let mut command = limits::command(
+        executable_path,
+        &launch_options.limits,
+        launch_options.run_as.as_ref(),
+    );
+    command
+        .arg("--is-cli")
+        .args(&launch_options.args)
+        .envs(&launch_options.env);
+    let log = core_logs::policy().and_then(|policy| match LogFile::open(policy) {
+        Ok(log) => Some(Arc::new(Mutex::new(log))),
         Err(e) => {
-            error!("Error setting up signal handler: {}", e);
+            warn!("Failed to open the log of Lodestone Core, its output won't be captured: {e:#}");
+            None
+        }
+    });
+    if log.is_some() {
+        command.stdout(Stdio::piped()).stderr(Stdio::piped());
+    } else if output::is_machine_readable() {
+        // keep stdout for the cli's own JSON output
+        command.stdout(Stdio::from(std::io::stderr()));
+    }
+    #[cfg(unix)]
+    {
+        // keep the core out of the terminal's process group, so CTRL+C only reaches it once, through the cli
+        use std::os::unix::process::CommandExt;
+        command.process_group(0).stdin(Stdio::null());
+    }
+
+    {
+        let mut running = RUNNING_PROCESS.lock().unwrap();
+        // checked under the lock, so a stop requested from now on finds the process
+        if stop_requested() {
+            return Err(eyre!("Stopped before Lodestone Core started"));
+        }
+        *running = Some(command.spawn()?);
+        LAUNCHES.fetch_add(1, Ordering::SeqCst);
+    }
+    let (drained_sender, drained) = mpsc::channel();
+    if let Some(log) = &log {
+        let mut running = RUNNING_PROCESS.lock().unwrap();
+        let process = running.as_mut().expect("the core is running");
+        let stdout = process.stdout.take().expect("stdout is piped");
+        let stderr = process.stderr.take().expect("stderr is piped");
+        let echo = core_logs::policy().is_some_and(|policy| policy.echo);
+        let echo_stdout: Box<dyn std::io::Write + Send> = if !echo {
+            Box::new(std::io::sink())
+        } else if output::is_machine_readable() {
+            // keep stdout for the cli's own JSON output
+            Box::new(std::io::stderr())
+        } else {
+            Box::new(std::io::stdout())
+        };
+        let echo_stderr: Box<dyn std::io::Write + Send> = if echo {
+            Box::new(std::io::stderr())
+        } else {
+            Box::new(std::io::sink())
+        };
+        for (reader, echo, stream) in [
+            (
+                Box::new(stdout) as Box<dyn std::io::Read + Send>,
+                echo_stdout,
+                Stream::Stdout,
+            ),
+            (Box::new(stderr), echo_stderr, Stream::Stderr),
+        ] {
+            let log = log.clone();
+            let drained_sender = drained_sender.clone();
+            std::thread::spawn(move || {
+                if let Err(e) = core_logs::tee(reader, echo, stream, &log) {
+                    warn!("Failed to read the output of Lodestone Core: {}", e);
+                }
+                let _ = drained_sender.send(());
+            });
         }
     }
+    drop(drained_sender);
 
-    // Wait for the process to terminate
-    let status = process.lock().unwrap().wait()?;
-    if !status.success() {
+    // Wait for the process to terminate, without holding the lock so it can be signalled meanwhile
+    let status = loop {
+        let status = RUNNING_PROCESS
+            .lock()
+            .unwrap()
+            .as_mut()
+            .expect("the core is running")
+            .try_wait();
+        match status {
+            Ok(Some(status)) => break status,
+            Ok(None) => std::thread::sleep(WAIT_POLL_INTERVAL),
+            Err(e) => {
+                *RUNNING_PROCESS.lock().unwrap() = None;
+                return Err(e.into());
+            }
+        }
+    };
+    *RUNNING_PROCESS.lock().unwrap() = None;
+    let deadline = std::time::Instant::now() + LOG_DRAIN_TIMEOUT;
+    while drained
+        .recv_timeout(deadline.saturating_duration_since(std::time::Instant::now()))
+        .is_ok()
+    {}
+    if stop_requested() {
+        info!("Lodestone Core stopped with {}", status);
+    } else if !status.success() {
         error!("Process exited with status code: {}", status);
     }
 
-    Ok(())
+    Ok(status)
 }
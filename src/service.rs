@@ -0,0 +1,495 @@
+use color_eyre::eyre::{eyre, Context, Result};
+use std::env;
+use std::fmt::Write;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::time::Duration;
+
+use color_eyre::owo_colors::OwoColorize;
+use serde_json::json;
+
+use crate::{daemon, error, exit_code, info, output, run_core, util, warn, Args};
+
+/// Name of the systemd unit, `lodestone.service`
+const SYSTEMD_UNIT_NAME: &str = "lodestone.service";
+/// Label of the launchd job, also the name of its plist
+const LAUNCHD_LABEL: &str = "dev.lodestone.cli";
+
+/// How long the service manager waits after asking the cli to stop, beyond the shutdown timeout of the core
+const STOP_MARGIN: Duration = daemon::STOP_MARGIN;
+
+/// Whether the service runs for the current user only, or for the whole machine
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Scope {
+    /// Started when the user logs in, with their permissions
+    User,
+    /// Started at boot, installing it needs root
+    System,
+}
+
+/// What the generated service runs
+#[derive(Debug, Clone)]
+pub struct ServiceSpec {
+    pub scope: Scope,
+    /// The user a system service runs as
+    pub user: Option<String>,
+    pub lodestone_path: PathBuf,
+    pub cli_path: PathBuf,
+    pub shutdown_timeout: Duration,
+}
+
+impl ServiceSpec {
+    /// The command the service runs, the cli updating nothing and running Lodestone Core in the foreground
+    fn command(&self) -> Vec<String> {
+        vec![
+            self.cli_path.to_string_lossy().into_owned(),
+            "run".to_string(),
+            "--skip-update-check".to_string(),
+        ]
+    }
+}
+
+/// A service definition, and where it is installed
+#[derive(Debug, Clone)]
+pub struct ServiceFile {
+    pub path: PathBuf,
+    pub contents: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Manager {
+    Systemd,
+    Launchd,
+}
+
+fn manager() -> Result<Manager> {
+    match std::env::consts::OS {
+        "linux" => Ok(Manager::Systemd),
+        "macos" => Ok(Manager::Launchd),
+        os => Err(eyre!(
+            "Services are only supported with systemd on Linux and launchd on macOS, not on {os}"
+        )),
+    }
+}
+
+/// Where the service definition of `scope` goes
+pub fn service_path(scope: Scope) -> Result<PathBuf> {
+    let home = || dirs::home_dir().ok_or_else(|| eyre!("Couldn't find your home directory"));
+    Ok(match (manager()?, scope) {
+        (Manager::Systemd, Scope::User) => dirs::config_dir()
+            .ok_or_else(|| eyre!("Couldn't find your config directory"))?
+            .join("systemd")
+            .join("user")
+            .join(SYSTEMD_UNIT_NAME),
+        (Manager::Systemd, Scope::System) => {
+            Path::new("/etc/systemd/system").join(SYSTEMD_UNIT_NAME)
+        }
+        (Manager::Launchd, Scope::User) => home()?
+            .join("Library")
+            .join("LaunchAgents")
+            .join(format!("{LAUNCHD_LABEL}.plist")),
+        (Manager::Launchd, Scope::System) => {
+            Path::new("/Library/LaunchDaemons").join(format!("{LAUNCHD_LABEL}.plist"))
+        }
+    })
+}
+
+/// Quotes a word of a systemd command line or setting, escaping specifiers
+fn systemd_quote(word: &str) -> String {
+    let escaped = word
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('%', "%%");
+    if escaped.contains(char::is_whitespace) || escaped.is_empty() {
+        format!("\"{escaped}\"")
+    } else {
+        escaped
+    }
+}
+
+fn systemd_unit(spec: &ServiceSpec) -> String {
+    let exec_start = spec
+        .command()
+        .iter()
+        .map(|word| systemd_quote(word))
+        .collect::<Vec<_>>()
+        .join(" ");
+    let user = match (&spec.user, spec.scope) {
+        (Some(user), Scope::System) => format!("User={}\n", systemd_quote(user)),
+        _ => String::new(),
+    };
+    let wanted_by = match spec.scope {
+        Scope::User => "default.target",
+        Scope::System => "multi-user.target",
+    };
+    format!(
+        "# Generated by lodestone_cli service install\n\
+        [Unit]\n\
+        Description=Lodestone Core\n\
+        Wants=network-online.target\n\
+        After=network-online.target\n\
+        \n\
+        [Service]\n\
+        Type=simple\n\
+        {user}\
+        Environment={environment}\n\
+        ExecStart={exec_start}\n\
+        Restart=on-failure\n\
+        RestartSec=5\n\
+        # only the cli gets SIGTERM, it stops Lodestone Core gracefully\n\
+        KillMode=mixed\n\
+        TimeoutStopSec={timeout}\n\
+        \n\
+        [Install]\n\
+        WantedBy={wanted_by}\n",
+        environment = systemd_quote(&format!("LODESTONE_PATH={}", spec.lodestone_path.display())),
+        timeout = (spec.shutdown_timeout + STOP_MARGIN).as_secs(),
+    )
+}
+
+fn xml_escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+fn launchd_plist(spec: &ServiceSpec) -> String {
+    let arguments = spec
+        .command()
+        .iter()
+        .fold(String::new(), |mut arguments, word| {
+            let _ = writeln!(arguments, "        <string>{}</string>", xml_escape(word));
+            arguments
+        });
+    let user = match (&spec.user, spec.scope) {
+        (Some(user), Scope::System) => format!(
+            "    <key>UserName</key>\n    <string>{}</string>\n",
+            xml_escape(user)
+        ),
+        _ => String::new(),
+    };
+    let log_path = xml_escape(
+        &crate::core_logs::logs_dir(&spec.lodestone_path)
+            .join(daemon::DETACHED_LOG_NAME)
+            .to_string_lossy(),
+    );
+    format!(
+        r#"<?xml version="1.0" encoding="UTF-8"?>
+<!DOCTYPE plist PUBLIC "-//Apple//DTD PLIST 1.0//EN" "http://www.apple.com/DTDs/PropertyList-1.0.dtd">
+<!-- Generated by lodestone_cli service install -->
+<plist version="1.0">
+<dict>
+    <key>Label</key>
+    <string>{LAUNCHD_LABEL}</string>
+    <key>ProgramArguments</key>
+    <array>
+{arguments}    </array>
+    <key>EnvironmentVariables</key>
+    <dict>
+        <key>LODESTONE_PATH</key>
+        <string>{lodestone_path}</string>
+    </dict>
+{user}    <key>RunAtLoad</key>
+    <true/>
+    <!-- restart Lodestone Core unless it was stopped on purpose -->
+    <key>KeepAlive</key>
+    <dict>
+        <key>SuccessfulExit</key>
+        <false/>
+    </dict>
+    <key>ThrottleInterval</key>
+    <integer>5</integer>
+    <key>ExitTimeOut</key>
+    <integer>{timeout}</integer>
+    <key>StandardOutPath</key>
+    <string>{log_path}</string>
+    <key>StandardErrorPath</key>
+    <string>{log_path}</string>
+</dict>
+</plist>
+"#,
+        lodestone_path = xml_escape(&spec.lodestone_path.to_string_lossy()),
+        timeout = (spec.shutdown_timeout + STOP_MARGIN).as_secs(),
+    )
+}
+
+/// Generates the service definition for this platform's service manager
+pub fn generate(spec: &ServiceSpec) -> Result<ServiceFile> {
+    let contents = match manager()? {
+        Manager::Systemd => systemd_unit(spec),
+        Manager::Launchd => launchd_plist(spec),
+    };
+    Ok(ServiceFile {
+        path: service_path(spec.scope)?,
+        contents,
+    })
+}
+
+/// Runs a command of the service manager, failing if it exits with an error
+fn run_manager_command(program: &str, args: &[&str]) -> Result<()> {
+    info!("Running '{} {}'", program, args.join(" "));
+    let status = Command::new(program)
+        .args(args)
+        .status()
+        .with_context(|| format!("Failed to run {program}"))?;
+    if !status.success() {
+        return Err(eyre!(
+            "'{} {}' exited with {status}",
+            program,
+            args.join(" ")
+        ));
+    }
+    Ok(())
+}
+
+fn systemctl(scope: Scope, args: &[&str]) -> Result<()> {
+    let mut full_args = Vec::new();
+    if scope == Scope::User {
+        full_args.push("--user");
+    }
+    full_args.extend_from_slice(args);
+    run_manager_command("systemctl", &full_args)
+}
+
+/// Writes `file` and has the service manager start it now and at every boot or login
+pub fn install(file: &ServiceFile, scope: Scope) -> Result<()> {
+    if let Some(parent) = file.path.parent() {
+        std::fs::create_dir_all(parent)
+            .with_context(|| format!("Failed to create {}", parent.display()))?;
+    }
+    std::fs::write(&file.path, &file.contents)
+        .with_context(|| format!("Failed to write {}", file.path.display()))?;
+    let path = file.path.to_string_lossy();
+    match manager()? {
+        Manager::Systemd => {
+            systemctl(scope, &["daemon-reload"])?;
+            systemctl(scope, &["enable", "--now", SYSTEMD_UNIT_NAME])
+        }
+        Manager::Launchd => run_manager_command("launchctl", &["load", "-w", &path]),
+    }
+}
+
+/// Stops the service of `scope` and deletes its definition, returning where it was, `None` if it isn't installed
+pub fn uninstall(scope: Scope) -> Result<Option<PathBuf>> {
+    let path = service_path(scope)?;
+    if !path.exists() {
+        return Ok(None);
+    }
+    let path_str = path.to_string_lossy();
+    let result = match manager()? {
+        Manager::Systemd => systemctl(scope, &["disable", "--now", SYSTEMD_UNIT_NAME]),
+        Manager::Launchd => run_manager_command("launchctl", &["unload", "-w", &path_str]),
+    };
+    if let Err(e) = result {
+        warn!("{}, removing the service anyway", e);
+    }
+    std::fs::remove_file(&path).with_context(|| format!("Failed to delete {}", path.display()))?;
+    if manager()? == Manager::Systemd {
+        systemctl(scope, &["daemon-reload"])?;
+    }
+    Ok(Some(path))
+}
+
+/// The name of the user running the cli, the one who ran `sudo` if it runs under it
+pub fn invoking_user() -> Option<String> {
+    ["SUDO_USER", "USER", "USERNAME"]
+        .iter()
+        .find_map(|var| std::env::var(var).ok().filter(|user| !user.is_empty()))
+}
+
+/// The home directory of `user`, to find their `LODESTONE_PATH`
+pub fn home_dir_of(user: &str) -> Option<PathBuf> {
+    crate::run_as::lookup(user).map(|account| account.home)
+}
+
+/// Whether the cli runs as root, which installing a system service needs
+#[cfg(unix)]
+pub fn is_root() -> bool {
+    // SAFETY: geteuid has no memory safety requirements
+    unsafe { libc::geteuid() == 0 }
+}
+
+#[cfg(not(unix))]
+pub fn is_root() -> bool {
+    false
+}
+
+pub fn install_command(system: bool, user: Option<String>, print: bool, args: &Args) -> i32 {
+    let scope = if system { Scope::System } else { Scope::User };
+    let user = user.or_else(|| system.then(invoking_user).flatten());
+    // a system service installed with sudo runs Lodestone Core from the home of its user, not root's
+    let lodestone_path = args
+        .install_path
+        .clone()
+        .or_else(|| std::env::var_os("LODESTONE_PATH").map(PathBuf::from))
+        .or_else(|| {
+            let user = user.as_ref()?;
+            Some(home_dir_of(user)?.join(".lodestone"))
+        })
+        .or_else(util::get_lodestone_path)
+        // the service doesn't run in the working directory of the cli
+        .map(|path| match env::current_dir() {
+            Ok(dir) if path.is_relative() => dir.join(path),
+            _ => path,
+        });
+    let Some(lodestone_path) = lodestone_path else {
+        error!(
+            "Could not find lodestone path, please specify it with the '{}' flag",
+            "--install-path".bold().blue()
+        );
+        return exit_code::FAILURE;
+    };
+    let spec = ServiceSpec {
+        scope,
+        user,
+        lodestone_path,
+        cli_path: match env::current_exe() {
+            Ok(path) => path,
+            Err(e) => {
+                error!("Failed to find the cli executable: {}", e);
+                return exit_code::FAILURE;
+            }
+        },
+        shutdown_timeout: args
+            .shutdown_timeout
+            .map_or(run_core::DEFAULT_SHUTDOWN_TIMEOUT, Duration::from_secs),
+    };
+    let file = match generate(&spec) {
+        Ok(file) => file,
+        Err(e) => {
+            error!("{}", e);
+            return exit_code::FAILURE;
+        }
+    };
+    output::result(
+        "service",
+        json!({ "path": file.path, "lodestone_path": spec.lodestone_path, "user": spec.user }),
+    );
+    if print {
+        if output::is_machine_readable() {
+            output::result("contents", &file.contents);
+        } else {
+            print!("{}", file.contents);
+        }
+        return exit_code::SUCCESS;
+    }
+    if system && !is_root() {
+        error!(
+            "Installing a system service needs root, run the cli again with sudo, or pass '{}' to only see the service",
+            "--print".bold().blue()
+        );
+        return exit_code::FAILURE;
+    }
+
+    info!(
+        "Installing the service at {} for LODESTONE_PATH={}",
+        file.path.display().bold().blue(),
+        spec.lodestone_path.display().bold().blue()
+    );
+    if let Err(e) = install(&file, scope) {
+        error!("Failed to install the service: {e:#}");
+        return exit_code::FAILURE;
+    }
+    info!("Installed and started the service");
+    if !system && env::consts::OS == "linux" {
+        info!(
+            "User services stop when you log out, run '{}' to keep Lodestone Core running",
+            "loginctl enable-linger".bold().blue()
+        );
+    }
+    exit_code::SUCCESS
+}
+
+pub fn uninstall_command(system: bool) -> i32 {
+    let scope = if system { Scope::System } else { Scope::User };
+    if system && !is_root() {
+        error!("Removing a system service needs root, run the cli again with sudo");
+        return exit_code::FAILURE;
+    }
+    match uninstall(scope) {
+        Ok(Some(path)) => {
+            info!("Removed the service at {}", path.display().bold().blue());
+            output::result("removed", path);
+            exit_code::SUCCESS
+        }
+        Ok(None) => {
+            info!("The service is not installed");
+            exit_code::SUCCESS
+        }
+        Err(e) => {
+            error!("Failed to remove the service: {e:#}");
+            exit_code::FAILURE
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn spec(scope: Scope, user: Option<&str>) -> ServiceSpec {
+        ServiceSpec {
+            scope,
+            user: user.map(str::to_string),
+            lodestone_path: PathBuf::from("/srv/lodestone data"),
+            cli_path: PathBuf::from("/usr/local/bin/lodestone_cli"),
+            shutdown_timeout: Duration::from_secs(30),
+        }
+    }
+
+    #[test]
+    fn systemd_unit_of_a_user_service() {
+        let unit = systemd_unit(&spec(Scope::User, Some("steve")));
+        assert!(unit.contains("ExecStart=/usr/local/bin/lodestone_cli run --skip-update-check\n"));
+        assert!(unit.contains("Environment=\"LODESTONE_PATH=/srv/lodestone data\"\n"));
+        // a user service always runs as its user
+        assert!(!unit.contains("User="));
+        assert!(unit.contains("KillMode=mixed\n"));
+        assert!(unit.contains("TimeoutStopSec=35\n"));
+        assert!(unit.contains("WantedBy=default.target\n"));
+    }
+
+    #[test]
+    fn systemd_unit_of_a_system_service() {
+        let unit = systemd_unit(&spec(Scope::System, Some("steve")));
+        assert!(unit.contains("[Service]\nType=simple\nUser=steve\n"));
+        assert!(unit.contains("WantedBy=multi-user.target\n"));
+    }
+
+    #[test]
+    fn systemd_quote_escapes_specifiers() {
+        assert_eq!(systemd_quote("plain"), "plain");
+        assert_eq!(systemd_quote("50%"), "50%%");
+        assert_eq!(systemd_quote("with space"), "\"with space\"");
+        assert_eq!(systemd_quote(r#"say "hi""#), r#""say \"hi\"""#);
+        assert_eq!(systemd_quote(r"C:\dir"), r"C:\\dir");
+        assert_eq!(systemd_quote(""), "\"\"");
+    }
+
+    #[test]
+    fn launchd_plist_of_a_system_service() {
+        let mut spec = spec(Scope::System, Some("steve & co"));
+        spec.lodestone_path = PathBuf::from("/srv/<lodestone>");
+        let plist = launchd_plist(&spec);
+        assert!(plist.contains(&format!("<string>{LAUNCHD_LABEL}</string>")));
+        assert!(plist.contains(
+            "        <string>/usr/local/bin/lodestone_cli</string>\n        <string>run</string>\n        <string>--skip-update-check</string>\n"
+        ));
+        assert!(plist.contains(
+            "<key>LODESTONE_PATH</key>\n        <string>/srv/&lt;lodestone&gt;</string>"
+        ));
+        assert!(plist.contains("<key>UserName</key>\n    <string>steve &amp; co</string>"));
+        assert!(plist.contains("<key>ExitTimeOut</key>\n    <integer>35</integer>"));
+        assert!(plist.contains(
+            "<key>StandardOutPath</key>\n    <string>/srv/&lt;lodestone&gt;/logs/lodestone_cli.log</string>"
+        ));
+    }
+
+    #[test]
+    fn launchd_plist_of_a_user_service() {
+        let plist = launchd_plist(&spec(Scope::User, Some("steve")));
+        assert!(!plist.contains("UserName"));
+        assert!(plist.contains("<key>SuccessfulExit</key>\n        <false/>"));
+    }
+}
@@ -0,0 +1,339 @@
+use chrono::Utc;
+use color_eyre::eyre::Result;
+use color_eyre::owo_colors::OwoColorize;
+use serde::{Deserialize, Serialize};
+use std::fmt::Display;
+use std::future::Future;
+use std::path::{Path, PathBuf};
+use std::process::ExitStatus;
+use std::time::{Duration, Instant};
+
+use crate::{error, info, run_core, warn};
+
+/// Delay before the first restart, doubled after every crash in a row
+const BASE_RESTART_DELAY: Duration = Duration::from_secs(1);
+const MAX_RESTART_DELAY: Duration = Duration::from_secs(5 * 60);
+
+/// How many crashes in a row are restarted when `--max-restarts` isn't set
+pub const DEFAULT_MAX_RESTARTS: u32 = 5;
+
+/// How often a pending restart checks whether a stop was requested
+const STOP_POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+/// A core that stays up this long is considered healthy again, resetting the backoff and the crash loop counter
+pub const STABLE_UPTIME: Duration = Duration::from_secs(60);
+
+/// Name of the file the supervisor keeps its state in, next to the metadata
+pub const STATE_FILE_NAME: &str = ".lodestone_cli_supervisor.json";
+
+pub fn state_path(lodestone_path: &Path) -> PathBuf {
+    lodestone_path.join(STATE_FILE_NAME)
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum SupervisorStatus {
+    /// Lodestone Core is running
+    Running,
+    /// Lodestone Core crashed and is about to be restarted
+    Restarting,
+    /// Lodestone Core was stopped on purpose, it isn't restarted
+    Stopped,
+    /// Lodestone Core crashed too many times in a row, the supervisor gave up
+    CrashLoop,
+}
+
+impl Display for SupervisorStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SupervisorStatus::Running => write!(f, "running"),
+            SupervisorStatus::Restarting => write!(f, "restarting"),
+            SupervisorStatus::Stopped => write!(f, "stopped"),
+            SupervisorStatus::CrashLoop => write!(f, "gave up after a crash loop"),
+        }
+    }
+}
+
+/// How a run of Lodestone Core ended
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct LastExit {
+    pub at: String,
+    /// Exit code of the core, `None` if it was killed by a signal or failed to start
+    pub code: Option<i32>,
+    /// Signal that killed the core, on Unix
+    pub signal: Option<i32>,
+    /// Whether the core was stopped on purpose, rather than crashing
+    pub intentional: bool,
+    /// Why the core couldn't be started, or why the cli stopped it
+    pub error: Option<String>,
+}
+
+impl LastExit {
+    /// A short description for `status`, e.g. `crashed with code 1`
+    pub fn describe(&self) -> String {
+        let how = if self.intentional {
+            "stopped"
+        } else {
+            "crashed"
+        };
+        match (self.code, self.signal, &self.error) {
+            (_, _, Some(error)) => format!("failed: {error}"),
+            (Some(code), _, _) => format!("{how} with code {code}"),
+            (_, Some(signal), _) => format!("{how} by signal {signal}"),
+            _ => how.to_string(),
+        }
+    }
+}
+
+/// What the supervisor writes to its state file, for `status` and monitoring scripts
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SupervisorState {
+    pub status: SupervisorStatus,
+    pub pid: u32,
+    pub started_at: String,
+    /// How many times the core was restarted since the supervisor started
+    pub restarts: u32,
+    /// Crashes in a row without the core staying up for `STABLE_UPTIME`
+    pub consecutive_crashes: u32,
+    pub last_exit: Option<LastExit>,
+}
+
+impl SupervisorState {
+    pub fn read(lodestone_path: &Path) -> Result<SupervisorState> {
+        let contents = std::fs::read_to_string(state_path(lodestone_path))?;
+        Ok(serde_json::from_str(&contents)?)
+    }
+
+    fn write(&self, lodestone_path: &Path) {
+        let result = serde_json::to_vec_pretty(self)
+            .map_err(Into::into)
+            .and_then(|json| std::fs::write(state_path(lodestone_path), json));
+        if let Err(e) = result {
+            warn!("Failed to write the supervisor state: {}", e);
+        }
+    }
+}
+
+/// How the supervisor ended
+#[derive(Debug)]
+pub enum Outcome {
+    /// The core was stopped on purpose, with its exit status if it was running
+    Stopped(Option<ExitStatus>),
+    /// The core crashed more than the allowed number of times in a row
+    CrashLoop,
+}
+
+fn last_exit(result: &Result<ExitStatus>, intentional: bool) -> LastExit {
+    #[cfg(unix)]
+    let signal = result.as_ref().ok().and_then(|status| {
+        use std::os::unix::process::ExitStatusExt;
+        status.signal()
+    });
+    #[cfg(not(unix))]
+    let signal = None;
+    LastExit {
+        at: Utc::now().to_string(),
+        code: result.as_ref().ok().and_then(|status| status.code()),
+        signal,
+        intentional,
+        error: result.as_ref().err().map(|e| format!("{e:#}")),
+    }
+}
+
+fn restart_delay(consecutive_crashes: u32) -> Duration {
+    BASE_RESTART_DELAY
+        .saturating_mul(1 << consecutive_crashes.saturating_sub(1).min(16))
+        .min(MAX_RESTART_DELAY)
+}
+
+/// Runs Lodestone Core with `launch` until it is stopped on purpose, restarting it when it crashes
+///
+/// A clean exit or a stop requested through a signal is intentional, anything else is a crash.
+/// Restarts back off exponentially, and the supervisor gives up after `max_restarts` crashes in a row
+pub async fn supervise<F, Fut>(lodestone_path: &Path, max_restarts: u32, mut launch: F) -> Outcome
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<ExitStatus>>,
+{
+    let mut state = SupervisorState {
+        status: SupervisorStatus::Running,
+        pid: std::process::id(),
+        started_at: Utc::now().to_string(),
+        restarts: 0,
+        consecutive_crashes: 0,
+        last_exit: None,
+    };
+    loop {
+        state.status = SupervisorStatus::Running;
+        state.write(lodestone_path);
+
+        let started = Instant::now();
+        let result = launch().await;
+        let intentional =
+            run_core::stop_requested() || matches!(&result, Ok(status) if status.success());
+        state.last_exit = Some(last_exit(&result, intentional));
+
+        if intentional {
+            info!("Lodestone Core was stopped, the supervisor is exiting");
+            state.status = SupervisorStatus::Stopped;
+            state.write(lodestone_path);
+            return Outcome::Stopped(result.ok());
+        }
+
+        if started.elapsed() >= STABLE_UPTIME {
+            state.consecutive_crashes = 0;
+        }
+        state.consecutive_crashes += 1;
+        match &result {
+            Ok(status) => error!("Lodestone Core crashed ({})", status),
+            Err(e) => error!("Lodestone Core failed: {}", e),
+        }
+        if state.consecutive_crashes > max_restarts {
+            error!(
+                "{}",
+                format!(
+                    "Lodestone Core crashed {} times in a row, giving up",
+                    state.consecutive_crashes
+                )
+                .bold()
+                .red()
+            );
+            state.status = SupervisorStatus::CrashLoop;
+            state.write(lodestone_path);
+            return Outcome::CrashLoop;
+        }
+
+        let delay = restart_delay(state.consecutive_crashes);
+        warn!(
+            "Restarting Lodestone Core in {:.1}s (crash {} of {} in a row)",
+            delay.as_secs_f64(),
+            state.consecutive_crashes,
+            max_restarts
+        );
+        state.status = SupervisorStatus::Restarting;
+        state.write(lodestone_path);
+        let deadline = Instant::now() + delay;
+        while !run_core::stop_requested() && Instant::now() < deadline {
+            tokio::time::sleep(STOP_POLL_INTERVAL.min(deadline - Instant::now())).await;
+        }
+        if run_core::stop_requested() {
+            info!("Stop requested while waiting to restart, the supervisor is exiting");
+            state.status = SupervisorStatus::Stopped;
+            state.write(lodestone_path);
+            return Outcome::Stopped(None);
+        }
+        state.restarts += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+
+    #[cfg(unix)]
+    fn exit_status(code: i32) -> ExitStatus {
+        use std::os::unix::process::ExitStatusExt;
+        ExitStatus::from_raw(code << 8)
+    }
+
+    #[cfg(windows)]
+    fn exit_status(code: i32) -> ExitStatus {
+        use std::os::windows::process::ExitStatusExt;
+        ExitStatus::from_raw(code as u32)
+    }
+
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir()
+            .join(format!("lodestone_cli_test_{}", std::process::id()))
+            .join(name);
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn restart_delay_backs_off_exponentially_up_to_a_limit() {
+        assert_eq!(restart_delay(1), Duration::from_secs(1));
+        assert_eq!(restart_delay(2), Duration::from_secs(2));
+        assert_eq!(restart_delay(5), Duration::from_secs(16));
+        assert_eq!(restart_delay(9), Duration::from_secs(256));
+        assert_eq!(restart_delay(10), MAX_RESTART_DELAY);
+        assert_eq!(restart_delay(u32::MAX), MAX_RESTART_DELAY);
+    }
+
+    #[tokio::test]
+    async fn does_not_restart_a_core_that_was_stopped() {
+        let lodestone_path = test_dir("supervise_stopped");
+        let launches = Cell::new(0);
+
+        let outcome = supervise(&lodestone_path, 3, || {
+            launches.set(launches.get() + 1);
+            std::future::ready(Ok(exit_status(0)))
+        })
+        .await;
+
+        assert!(matches!(outcome, Outcome::Stopped(Some(status)) if status.success()));
+        assert_eq!(launches.get(), 1);
+        let state = SupervisorState::read(&lodestone_path).unwrap();
+        assert_eq!(state.status, SupervisorStatus::Stopped);
+        assert_eq!(state.restarts, 0);
+        assert!(state.last_exit.unwrap().intentional);
+    }
+
+    #[tokio::test]
+    async fn restarts_a_crashed_core() {
+        let lodestone_path = test_dir("supervise_restart");
+        let launches = Cell::new(0);
+
+        let outcome = supervise(&lodestone_path, 3, || {
+            launches.set(launches.get() + 1);
+            let code = if launches.get() == 1 { 1 } else { 0 };
+            std::future::ready(Ok(exit_status(code)))
+        })
+        .await;
+
+        assert!(matches!(outcome, Outcome::Stopped(Some(_))));
+        assert_eq!(launches.get(), 2);
+        let state = SupervisorState::read(&lodestone_path).unwrap();
+        assert_eq!(state.restarts, 1);
+        assert_eq!(state.consecutive_crashes, 1);
+    }
+
+    #[tokio::test]
+    async fn gives_up_after_too_many_crashes_in_a_row() {
+        let lodestone_path = test_dir("supervise_crash_loop");
+        let launches = Cell::new(0);
+
+        let outcome = supervise(&lodestone_path, 1, || {
+            launches.set(launches.get() + 1);
+            std::future::ready(Err(color_eyre::eyre::eyre!("missing executable")))
+        })
+        .await;
+
+        assert!(matches!(outcome, Outcome::CrashLoop));
+        // the first crash is restarted, the second one is one too many
+        assert_eq!(launches.get(), 2);
+        let state = SupervisorState::read(&lodestone_path).unwrap();
+        assert_eq!(state.status, SupervisorStatus::CrashLoop);
+        assert_eq!(state.consecutive_crashes, 2);
+        let last_exit = state.last_exit.unwrap();
+        assert!(!last_exit.intentional);
+        assert_eq!(last_exit.error.as_deref(), Some("missing executable"));
+    }
+
+    #[tokio::test]
+    async fn gives_up_right_away_without_restarts() {
+        let lodestone_path = test_dir("supervise_no_restarts");
+        let launches = Cell::new(0);
+
+        let outcome = supervise(&lodestone_path, 0, || {
+            launches.set(launches.get() + 1);
+            std::future::ready(Ok(exit_status(1)))
+        })
+        .await;
+
+        assert!(matches!(outcome, Outcome::CrashLoop));
+        assert_eq!(launches.get(), 1);
+    }
+}
@@ -1,8 +1,55 @@
 use std::path::Path;
 
 use color_eyre::eyre::Result;
+use color_eyre::owo_colors::OwoColorize;
+
+use crate::{error, exit_code, info, output, prompt_for_confirmation, warn, PromptDefault};
 
 pub fn uninstall(lodestone_path: &Path) -> Result<()> {
     std::fs::remove_dir_all(lodestone_path)?;
     Ok(())
 }
+
+pub fn uninstall_command(lodestone_path: &Path, yes_all: bool) -> i32 {
+    if !lodestone_path.exists() {
+        info!(
+            "Nothing to uninstall, {} does not exist",
+            lodestone_path.display().bold().blue()
+        );
+        return exit_code::NOT_INSTALLED;
+    }
+    warn!(
+        "{}",
+        format!(
+            "This will delete the directory and all files in it: {}",
+            lodestone_path.display()
+        )
+        .bold()
+        .red()
+    );
+    if !yes_all
+        && !prompt_for_confirmation(
+            format!(
+                "Are you sure you want to uninstall lodestone? {}:",
+                "(yes/n)".bold().magenta()
+            ),
+            |input| input.trim() == "yes",
+            PromptDefault::Required,
+        )
+    {
+        info!("Aborting uninstall, no file changes were made.");
+        return exit_code::ABORTED;
+    }
+    info!("Uninstalling lodestone...");
+    if let Err(e) = uninstall(lodestone_path) {
+        error!(
+            "Error uninstalling lodestone: {}, some files may need to be manually removed",
+            e
+        );
+        exit_code::FAILURE
+    } else {
+        info!("Uninstalled lodestone successfully");
+        output::result("uninstalled", lodestone_path);
+        exit_code::SUCCESS
+    }
+}
@@ -1,37 +1,267 @@
-use color_eyre::eyre::Result;
+use color_eyre::eyre::{eyre, Result};
+use color_eyre::owo_colors::OwoColorize;
 
 use std::path::{Path, PathBuf};
 
-use crate::util;
+use crate::verify::{check_signature, VerifyOptions};
+use crate::{util, warn};
 
+use super::installed;
+use super::source::ReleaseSource;
 use super::versions::VersionWithV;
 
-fn get_release_url(version: &VersionWithV) -> Result<String> {
-    // Get the target architecture and operating system
-    let github_repo_url = "https://github.com/Lodestone-Team/lodestone_core/";
-    let executable_name = util::get_executable_name(version);
+/// Name of the checksum manifest attached to a release, in the format `sha256sum` outputs
+const CHECKSUM_MANIFEST_NAME: &str = "SHA256SUMS";
+
+/// Parses a checksum in the format `sha256sum` outputs (`<hash>  <file name>`)
+///
+/// If `file_name` is `None` the first checksum is returned, regardless of the file name
+fn parse_checksum(contents: &str, file_name: Option<&str>) -> Option<String> {
+    contents.lines().find_map(|line| {
+        let mut parts = line.split_whitespace();
+        let hash = parts.next()?;
+        if hash.len() != 64 || !hash.chars().all(|c| c.is_ascii_hexdigit()) {
+            return None;
+        }
+        match (file_name, parts.next()) {
+            (None, _) => Some(hash.to_ascii_lowercase()),
+            (Some(file_name), Some(name)) if name.trim_start_matches('*') == file_name => {
+                Some(hash.to_ascii_lowercase())
+            }
+            _ => None,
+        }
+    })
+}
 
-    Ok(format!(
-        "{}releases/download/{}/{}",
-        github_repo_url, version, executable_name
+/// Fetches the expected SHA-256 checksum of the core executable from the release
+///
+/// A `<executable name>.sha256` sidecar asset is preferred, the `SHA256SUMS` manifest is used otherwise
+async fn get_release_checksum(source: &ReleaseSource, version: &VersionWithV) -> Result<String> {
+    let executable_name = util::get_executable_name(version);
+    let sidecar_name = format!("{executable_name}.sha256");
+    if let Some(contents) = source.get_asset_text(version, &sidecar_name).await? {
+        return parse_checksum(&contents, None)
+            .ok_or_else(|| eyre!("Malformed checksum file {sidecar_name} in release {version}"));
+    }
+    if let Some(contents) = source
+        .get_asset_text(version, CHECKSUM_MANIFEST_NAME)
+        .await?
+    {
+        return parse_checksum(&contents, Some(&executable_name)).ok_or_else(|| {
+            eyre!("{CHECKSUM_MANIFEST_NAME} of release {version} has no checksum for {executable_name}")
+        });
+    }
+    Err(eyre!(
+        "Release {version} does not publish a checksum for {executable_name}"
     ))
 }
 
 pub async fn download_release(
+    source: &ReleaseSource,
     version: &VersionWithV,
     lodestone_path: &Path,
+    verify: &VerifyOptions,
 ) -> Result<(PathBuf, String)> {
-    // we try to backup the current core before downloading the new one
-    // let lodestone_path = util::get_lodestone_path();
-    // TODO: implement backup
-    // let dest_dir = lodestone_path.join(PathBuf::from(".core_backup"));
-    // copy_dir(&lodestone_path, &dest_dir)?;
-
     let executable_name = util::get_executable_name(version);
-    let release_url = get_release_url(version)?;
+    let expected_checksum = if verify.skip_checksum {
+        warn!(
+            "{}",
+            "Skipping checksum verification! The downloaded Lodestone Core executable will NOT be checked for corruption or tampering"
+                .bold()
+                .red()
+        );
+        None
+    } else {
+        Some(get_release_checksum(source, version).await.map_err(|e| {
+            eyre!(
+                "{e}. Refusing to install an unverified executable, pass '--skip-verify' to install it anyway"
+            )
+        })?)
+    };
+
+    let signature = source
+        .get_asset_text(version, &format!("{executable_name}.minisig"))
+        .await?;
+
     let executable_path = lodestone_path.join(&executable_name);
     tokio::fs::create_dir_all(lodestone_path).await?;
-    util::download_file(&release_url, &executable_path, lodestone_path).await?;
+    source
+        .download_asset(
+            version,
+            &executable_name,
+            &executable_path,
+            lodestone_path,
+            |path| {
+                if let Some(expected) = expected_checksum.as_deref() {
+                    util::verify_sha256(path, expected)?;
+                }
+                check_signature(path, &executable_name, signature.as_deref(), verify)
+            },
+        )
+        .await?;
+
+    // keep the signature around, so the executable can be exported in a verifiable bundle
+    if let Some(signature) = signature {
+        tokio::fs::write(
+            lodestone_path.join(installed::signature_name(&executable_name)),
+            signature,
+        )
+        .await?;
+    }
 
     Ok((executable_path, executable_name))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_server::{self, Response};
+    use std::collections::BTreeMap;
+    use std::str::FromStr;
+
+    const EXECUTABLE: &[u8] = b"#!/bin/sh\necho lodestone\n";
+
+    fn version() -> VersionWithV {
+        VersionWithV::from_str("v0.5.1").unwrap()
+    }
+
+    fn unsigned() -> VerifyOptions {
+        VerifyOptions {
+            allow_unsigned: true,
+            ..Default::default()
+        }
+    }
+
+    /// The assets of `v0.5.1`, with `checksum` for the executable in `SHA256SUMS`
+    fn release_assets(checksum: &str) -> BTreeMap<String, Vec<u8>> {
+        let executable_name = util::get_executable_name(&version());
+        let other = "0".repeat(64);
+        let sums = format!("{other}  lodestone_core_other_v0.5.1\n{checksum}  {executable_name}\n");
+        BTreeMap::from([
+            (executable_name, EXECUTABLE.to_vec()),
+            (CHECKSUM_MANIFEST_NAME.to_string(), sums.into_bytes()),
+        ])
+    }
+
+    fn valid_checksum() -> String {
+        use sha2::{Digest, Sha256};
+        util::to_hex(&Sha256::digest(EXECUTABLE))
+    }
+
+    /// A mirror with `assets` in `v0.5.1/`
+    fn serve_mirror(assets: BTreeMap<String, Vec<u8>>) -> ReleaseSource {
+        let port = test_server::serve(move |request| {
+            if request.path == "/index.json" {
+                return Response::new(
+                    200,
+                    r#"{"releases": [{"version": "v0.5.1"}, {"version": "nightly"}]}"#,
+                );
+            }
+            match request
+                .path
+                .strip_prefix("/v0.5.1/")
+                .and_then(|name| assets.get(name))
+            {
+                Some(contents) => Response::new(200, contents.clone()),
+                None => Response::new(404, ""),
+            }
+        });
+        ReleaseSource::from_str(&format!("http://127.0.0.1:{port}/")).unwrap()
+    }
+
+    fn test_dir(name: &str) -> PathBuf {
+        let path = std::env::temp_dir()
+            .join(format!("lodestone_cli_test_{}", std::process::id()))
+            .join(name);
+        let _ = std::fs::remove_dir_all(&path);
+        path
+    }
+
+    #[tokio::test]
+    async fn downloads_a_release_from_a_mirror() {
+        let source = serve_mirror(release_assets(&valid_checksum()));
+        assert_eq!(source.releases().await.unwrap(), [version()]);
+
+        let lodestone_path = test_dir("mirror");
+        let (executable_path, executable_name) =
+            download_release(&source, &version(), &lodestone_path, &unsigned())
+                .await
+                .unwrap();
+        assert_eq!(executable_path, lodestone_path.join(executable_name));
+        assert_eq!(std::fs::read(&executable_path).unwrap(), EXECUTABLE);
+    }
+
+    #[tokio::test]
+    async fn rejects_a_download_not_matching_sha256sums() {
+        let source = serve_mirror(release_assets(&"ab".repeat(32)));
+        let lodestone_path = test_dir("mirror_mismatch");
+        let error = download_release(&source, &version(), &lodestone_path, &unsigned())
+            .await
+            .unwrap_err();
+        assert!(format!("{error:#}").contains("Checksum mismatch"));
+        assert!(!lodestone_path
+            .join(util::get_executable_name(&version()))
+            .exists());
+        // the rejected download isn't kept around to be resumed
+        assert_eq!(
+            std::fs::read_dir(lodestone_path.join("tmp"))
+                .unwrap()
+                .count(),
+            0
+        );
+    }
+
+    #[tokio::test]
+    async fn refuses_a_release_without_its_checksum() {
+        let mut assets = release_assets(&valid_checksum());
+        assets.remove(CHECKSUM_MANIFEST_NAME);
+        let source = serve_mirror(assets);
+        let error = download_release(
+            &source,
+            &version(),
+            &test_dir("mirror_unverified"),
+            &unsigned(),
+        )
+        .await
+        .unwrap_err();
+        assert!(error.to_string().contains("does not publish a checksum"));
+    }
+
+    #[tokio::test]
+    async fn copies_a_release_from_a_directory() {
+        let releases = test_dir("releases");
+        std::fs::create_dir_all(releases.join("v0.5.1")).unwrap();
+        std::fs::create_dir_all(releases.join("not a version")).unwrap();
+        for (name, contents) in release_assets(&valid_checksum()) {
+            std::fs::write(releases.join("v0.5.1").join(name), contents).unwrap();
+        }
+        let source = ReleaseSource::from_str(&format!("file://{}", releases.display())).unwrap();
+        assert_eq!(source.releases().await.unwrap(), [version()]);
+
+        let lodestone_path = test_dir("directory");
+        let (executable_path, _) =
+            download_release(&source, &version(), &lodestone_path, &unsigned())
+                .await
+                .unwrap();
+        assert_eq!(std::fs::read(executable_path).unwrap(), EXECUTABLE);
+
+        std::fs::write(
+            releases.join("v0.5.1").join(CHECKSUM_MANIFEST_NAME),
+            format!(
+                "{}  {}\n",
+                "ab".repeat(32),
+                util::get_executable_name(&version())
+            ),
+        )
+        .unwrap();
+        let error = download_release(
+            &source,
+            &version(),
+            &test_dir("directory_mismatch"),
+            &unsigned(),
+        )
+        .await
+        .unwrap_err();
+        assert!(format!("{error:#}").contains("Checksum mismatch"));
+    }
+}
@@ -0,0 +1,94 @@
+use color_eyre::eyre::Result;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use crate::util;
+use crate::versions::VersionWithV;
+
+/// A Lodestone Core executable present under `LODESTONE_PATH`
+#[derive(Debug, Clone)]
+pub struct InstalledVersion {
+    pub version: VersionWithV,
+    pub executable_name: String,
+}
+
+fn parse_executable_name(file_name: &str, prefix: &str) -> Option<VersionWithV> {
+    let version = file_name.strip_prefix(prefix)?.strip_prefix('_')?;
+    let version = version.strip_suffix(".exe").unwrap_or(version);
+    VersionWithV::from_str(version).ok()
+}
+
+/// Name of the file the signature of `executable_name` is kept in, next to the executable
+pub fn signature_name(executable_name: &str) -> String {
+    format!("{executable_name}.minisig")
+}
+
+/// Whether `file_name` is the name of a Lodestone Core executable for this platform
+pub fn is_core_executable(file_name: &str) -> bool {
+    util::executable_name_without_version()
+        .is_some_and(|prefix| parse_executable_name(file_name, &prefix).is_some())
+}
+
+/// Every Lodestone Core executable for this platform under `lodestone_path`, newest first
+pub fn installed_versions(lodestone_path: &Path) -> Result<Vec<InstalledVersion>> {
+    let Some(prefix) = util::executable_name_without_version() else {
+        return Ok(Vec::new());
+    };
+    if !lodestone_path.exists() {
+        return Ok(Vec::new());
+    }
+    let mut installed = Vec::new();
+    for entry in std::fs::read_dir(lodestone_path)? {
+        let entry = entry?;
+        if !entry.file_type()?.is_file() {
+            continue;
+        }
+        let file_name = entry.file_name().to_string_lossy().into_owned();
+        if let Some(version) = parse_executable_name(&file_name, &prefix) {
+            installed.push(InstalledVersion {
+                version,
+                executable_name: file_name,
+            });
+        }
+    }
+    installed.sort_by(|a, b| b.version.cmp(&a.version));
+    Ok(installed)
+}
+
+/// Path to the executable of `version` if it is already downloaded
+pub fn find_installed(lodestone_path: &Path, version: &VersionWithV) -> Option<PathBuf> {
+    let executable_path = lodestone_path.join(util::get_executable_name(version));
+    executable_path.is_file().then_some(executable_path)
+}
+
+/// Deletes all but the `keep` newest executables
+///
+/// `protected` versions are never deleted, but count towards `keep`. Returns the versions that were deleted
+pub fn prune(
+    lodestone_path: &Path,
+    keep: usize,
+    protected: &[VersionWithV],
+) -> Result<Vec<VersionWithV>> {
+    let installed = installed_versions(lodestone_path)?;
+    let mut slots = keep.saturating_sub(
+        installed
+            .iter()
+            .filter(|installed| protected.contains(&installed.version))
+            .count(),
+    );
+    let mut pruned = Vec::new();
+    for installed in installed {
+        if protected.contains(&installed.version) {
+            continue;
+        }
+        if slots > 0 {
+            slots -= 1;
+            continue;
+        }
+        std::fs::remove_file(lodestone_path.join(&installed.executable_name))?;
+        let _ =
+            std::fs::remove_file(lodestone_path.join(signature_name(&installed.executable_name)));
+        pruned.push(installed.version);
+    }
+    Ok(pruned)
+}
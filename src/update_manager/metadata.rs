@@ -1,17 +1,46 @@
 use color_eyre::eyre::Result;
 use serde::{Deserialize, Serialize};
 use serde_json;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use tokio::fs;
 use tokio::io::AsyncWriteExt;
 
-use crate::versions::VersionWithV;
+use semver::VersionReq;
+
+use super::source::ReleaseSource;
+use crate::versions::{Channel, VersionWithV};
+
+/// Where the metadata of the Lodestone Core installation under `lodestone_path` is stored
+/// Name of the file under `LODESTONE_PATH` the installation is described in
+pub const METADATA_FILE_NAME: &str = ".lodestone_cli_metadata.json";
+
+pub fn metadata_path(lodestone_path: &Path) -> PathBuf {
+    lodestone_path.join(METADATA_FILE_NAME)
+}
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Metadata {
     pub current_version: VersionWithV,
     pub last_updated: String,
     pub executable_name: String,
+    /// The installation this one replaced, restored if the current version fails to start
+    #[serde(default)]
+    pub previous: Option<Box<Metadata>>,
+    /// Set when a new version is installed, until it passes its first start-up health check
+    #[serde(default)]
+    pub pending_health_check: bool,
+    /// Versions that were rolled back after failing to start, never picked again by automatic updates
+    #[serde(default)]
+    pub failed_versions: Vec<VersionWithV>,
+    /// The release channel automatic updates pick the newest version from
+    #[serde(default)]
+    pub channel: Channel,
+    /// The requirement passed to `--version`, automatic updates only pick versions matching it
+    #[serde(default)]
+    pub constraint: Option<VersionReq>,
+    /// Where the installation was made from, later commands fetch releases from there unless `--source` is set
+    #[serde(default)]
+    pub source: ReleaseSource,
 }
 
 impl Metadata {
@@ -19,6 +48,8 @@ impl Metadata {
         let mut file = fs::File::create(path).await?;
         let json = serde_json::to_string(self)?;
         file.write_all(json.as_bytes()).await?;
+        // tokio only guarantees the write has reached the file after a flush
+        file.flush().await?;
         Ok(())
     }
 
@@ -28,3 +59,25 @@ impl Metadata {
         Ok(metadata)
     }
 }
+
+/// Saves the requirement automatic updates of the installation under `lodestone_path` must match, `None` to remove it
+pub async fn set_constraint(lodestone_path: &Path, constraint: Option<VersionReq>) -> Result<()> {
+    let path = metadata_path(lodestone_path);
+    let mut metadata = Metadata::read_metadata(&path).await?;
+    if metadata.constraint != constraint {
+        metadata.constraint = constraint;
+        metadata.write_metadata(&path).await?;
+    }
+    Ok(())
+}
+
+/// Switches the installation under `lodestone_path` to `channel`
+pub async fn set_channel(lodestone_path: &Path, channel: Channel) -> Result<()> {
+    let path = metadata_path(lodestone_path);
+    let mut metadata = Metadata::read_metadata(&path).await?;
+    if metadata.channel != channel {
+        metadata.channel = channel;
+        metadata.write_metadata(&path).await?;
+    }
+    Ok(())
+}
@@ -1,25 +1,78 @@
 use chrono::Utc;
-use color_eyre::{eyre::Result, owo_colors::OwoColorize};
+use color_eyre::{
+    eyre::{Result, WrapErr},
+    owo_colors::OwoColorize,
+};
+use indicatif::HumanBytes;
 
 use std::path::{Path, PathBuf};
 
 pub mod download;
+pub mod installed;
 pub mod metadata;
+pub mod rollback;
+pub mod source;
 use crate::util::get_lodestone_path;
 use crate::{
     info, prompt_for_confirmation, update_manager::download::download_release, util, warn,
+    PromptDefault,
 };
 
-use crate::versions::{self, get_current_version, VersionWithV};
+use crate::backup;
+use crate::verify::VerifyOptions;
+use crate::versions::{self, get_current_version, Channel, VersionWithV};
+
+/// How `try_update` behaves, built from the command line
+#[derive(Debug, Clone, Default)]
+pub struct UpdateOptions {
+    /// Say yes to all prompts
+    pub yes_all: bool,
+    /// Use the local version of Lodestone Core without asking GitHub for a newer one
+    pub skip_update_check: bool,
+    /// Don't back up LODESTONE_PATH before changing versions
+    pub skip_backup: bool,
+    /// Switch to this release channel, otherwise the installation keeps the one it follows
+    pub channel: Option<Channel>,
+    /// Where releases are fetched from
+    pub source: source::ReleaseSource,
+    pub verify: VerifyOptions,
+}
+
+/// Backs up `lodestone_path` before `current_version` is replaced
+async fn backup_before_update(lodestone_path: &Path, current_version: &VersionWithV) -> Result<()> {
+    info!(
+        "Backing up {} before changing versions, this may take a while",
+        lodestone_path.display().bold().blue()
+    );
+    let path = lodestone_path.to_owned();
+    let version = current_version.clone();
+    let (backup, pruned) = tokio::task::spawn_blocking(move || -> Result<_> {
+        let backup = backup::create_backup(&path, Some(&version), true)?;
+        let pruned = backup::prune_automatic_backups(&path, backup::AUTOMATIC_BACKUPS_KEPT)?;
+        Ok((backup, pruned))
+    })
+    .await??;
+    info!(
+        "Created backup {} ({}), restore it with '{}' if something goes wrong",
+        backup.id.bold().blue(),
+        HumanBytes(backup.size),
+        format!("backup restore {}", backup.id).bold().blue()
+    );
+    for backup in pruned {
+        info!("Deleted old backup {}", backup.id);
+    }
+    Ok(())
+}
 
 /// Updates the Lodestone Core to the latest release if needed
 /// Returns the path to the new (or old) executable
 pub async fn try_update(
     lodestone_path: &Path,
     version_override: Option<VersionWithV>,
-    yes_all: bool,
-    skip_update_check: bool,
+    options: &UpdateOptions,
 ) -> Result<Option<PathBuf>> {
+    let yes_all = options.yes_all;
+    let skip_update_check = options.skip_update_check;
     let current_version = get_current_version().await;
 
     match current_version {
@@ -50,17 +103,51 @@ pub async fn try_update(
         }
     }
 
+    let previous_metadata =
+        metadata::Metadata::read_metadata(&metadata::metadata_path(lodestone_path))
+            .await
+            .ok();
+
+    let channel = options
+        .channel
+        .or(previous_metadata.as_ref().map(|metadata| metadata.channel))
+        .unwrap_or_default();
+
+    let constraint = previous_metadata
+        .as_ref()
+        .and_then(|metadata| metadata.constraint.clone());
+
     let new_version = if let Some(ref v) = version_override {
         v.clone()
     } else {
-        versions::get_latest_release().await?
+        match &constraint {
+            Some(constraint) => info!(
+                "Looking for the latest release matching '{}' on the {} channel",
+                constraint.bold().blue(),
+                channel.bold().blue()
+            ),
+            None => info!(
+                "Looking for the latest release on the {} channel",
+                channel.bold().blue()
+            ),
+        }
+        versions::get_latest_release(&options.source, channel, constraint.as_ref()).await?
     };
 
     let current_version = match current_version {
         Ok(v) => Some(v),
         Err(_e) => {
-            // if lodestone_path is not empty, exit
-            if lodestone_path.read_dir()?.next().is_some() {
+            // if lodestone_path has anything but our own files, exit
+            let has_data = lodestone_path.read_dir()?.any(|entry| {
+                entry.is_ok_and(|entry| {
+                    let name = entry.file_name().to_string_lossy().into_owned();
+                    name != "tmp"
+                        && !installed::is_core_executable(
+                            name.strip_suffix(".minisig").unwrap_or(&name),
+                        )
+                })
+            });
+            if has_data {
                 warn!(
                     "{}, this is normal if you ran an older version of Lodestone Core",
                     format!(
@@ -78,6 +165,7 @@ pub async fn try_update(
                             "(yes/n)".magenta().bold()
                         ),
                         |s| s.trim() == "yes",
+                        PromptDefault::Required,
                     )
                 {
                     info!("User chose not to install Lodestone Core, exiting");
@@ -115,6 +203,23 @@ pub async fn try_update(
                     ));
                 }
 
+                if previous_metadata
+                    .as_ref()
+                    .is_some_and(|m| m.failed_versions.contains(&new_version))
+                {
+                    warn!(
+                        "Lodestone Core {} was rolled back after failing to start, skipping update",
+                        new_version.bold().blue()
+                    );
+                    info!(
+                        "You can still install it with '{}'",
+                        format!("install --version {new_version}").bold().blue()
+                    );
+                    return Ok(Some(
+                        lodestone_path.join(util::get_executable_name(&current_version)),
+                    ));
+                }
+
                 // Otherwise we need to update
                 // ask the user if they want to update in the terminal
 
@@ -126,6 +231,7 @@ pub async fn try_update(
                             "(y/n)".magenta().bold()
                         ),
                         |s| s.trim() == "y" || s.trim() == "yes",
+                        PromptDefault::No,
                     )
                 {
                     info!(
@@ -141,16 +247,80 @@ pub async fn try_update(
         Some(v) => info!("Version override: {}", v.bold().yellow()),
     }
 
-    let (executable_path, exe_file) = download_release(&new_version, lodestone_path).await?;
+    if let Some(previous) = previous_metadata
+        .as_ref()
+        .filter(|previous| previous.current_version != new_version)
+    {
+        if options.skip_backup {
+            warn!(
+                "Skipping the backup of {}",
+                lodestone_path.display().bold().blue()
+            );
+        } else {
+            backup_before_update(lodestone_path, &previous.current_version)
+                .await
+                .wrap_err("Failed to back up before changing versions, pass '--skip-backup' to change versions anyway")?;
+        }
+    }
 
+    let (executable_path, exe_file) = match installed::find_installed(lodestone_path, &new_version)
+    {
+        Some(executable_path) => {
+            info!(
+                "Lodestone Core {} is already downloaded, switching to it",
+                new_version.bold().blue()
+            );
+            (executable_path, util::get_executable_name(&new_version))
+        }
+        None => {
+            download_release(
+                &options.source,
+                &new_version,
+                lodestone_path,
+                &options.verify,
+            )
+            .await?
+        }
+    };
+
+    // keep the installation we are replacing around, so we can roll back to it if the new version fails to start
+    let (previous, failed_versions, pending_health_check) = match previous_metadata {
+        Some(previous) if previous.current_version != new_version => {
+            let failed_versions = previous
+                .failed_versions
+                .iter()
+                .filter(|v| **v != new_version)
+                .cloned()
+                .collect();
+            let previous = metadata::Metadata {
+                previous: None,
+                pending_health_check: false,
+                failed_versions: Vec::new(),
+                ..previous
+            };
+            (Some(Box::new(previous)), failed_versions, true)
+        }
+        Some(reinstalled) => (
+            reinstalled.previous,
+            reinstalled.failed_versions,
+            reinstalled.pending_health_check,
+        ),
+        None => (None, Vec::new(), false),
+    };
     let new_metadata = metadata::Metadata {
         current_version: new_version.clone(),
         last_updated: Utc::now().to_string(), //TODO Standardize this
         executable_name: exe_file,
+        previous,
+        pending_health_check,
+        failed_versions,
+        channel,
+        constraint,
+        source: options.source.clone(),
     };
 
     new_metadata
-        .write_metadata(&lodestone_path.join(".lodestone_cli_metadata.json"))
+        .write_metadata(&metadata::metadata_path(lodestone_path))
         .await?;
 
     info!(
@@ -0,0 +1,164 @@
+use chrono::Utc;
+use color_eyre::eyre::{eyre, Result};
+use std::path::{Path, PathBuf};
+use std::process::ExitStatus;
+use std::time::Duration;
+
+use super::metadata::{metadata_path, Metadata};
+use crate::health::HealthStatus;
+
+/// How long a freshly installed Lodestone Core has to pass its start-up health check
+///
+/// It passes once its API is ready, or if it stays up this long without its API failing on every probe
+pub const STARTUP_HEALTH_WINDOW: Duration = Duration::from_secs(60);
+
+/// How many failed probes in a row it takes for a freshly installed Lodestone Core to fail its start-up health check
+const STARTUP_FAILED_PROBES: usize = 3;
+
+/// Whether a freshly installed Lodestone Core that is still running failed its start-up health check, given the
+/// statuses of its probes during `STARTUP_HEALTH_WINDOW` in order
+///
+/// It fails if it was never ready and, once its API answered, it was degraded on every probe, at least
+/// `STARTUP_FAILED_PROBES` times. One slow answer isn't enough, nor is an API that can't be reached at all since
+/// Lodestone Core may listen on another port
+pub fn failed_startup(statuses: &[HealthStatus]) -> bool {
+    let answered: Vec<_> = statuses
+        .iter()
+        .skip_while(|status| **status == HealthStatus::Unreachable)
+        .collect();
+    answered.len() >= STARTUP_FAILED_PROBES
+        && answered
+            .iter()
+            .all(|status| **status == HealthStatus::Degraded)
+}
+
+/// Whether a freshly installed Lodestone Core that exited within `STARTUP_HEALTH_WINDOW` failed to start
+///
+/// Exiting successfully or being stopped, by CTRL+C for example, doesn't count as a failure
+pub fn failed_to_start(result: &Result<ExitStatus>, stop_requested: bool) -> bool {
+    !stop_requested && !matches!(result, Ok(status) if status.success())
+}
+
+/// Whether the current version was just installed and hasn't passed its start-up health check yet
+pub async fn is_pending_health_check(lodestone_path: &Path) -> bool {
+    Metadata::read_metadata(&metadata_path(lodestone_path))
+        .await
+        .is_ok_and(|metadata| metadata.pending_health_check && metadata.previous.is_some())
+}
+
+/// Records that the current version started successfully
+pub async fn mark_healthy(lodestone_path: &Path) -> Result<()> {
+    let path = metadata_path(lodestone_path);
+    let mut metadata = Metadata::read_metadata(&path).await?;
+    if metadata.pending_health_check {
+        metadata.pending_health_check = false;
+        metadata.write_metadata(&path).await?;
+    }
+    Ok(())
+}
+
+/// Restores the installation the current version replaced, and marks the current version as failed
+///
+/// Returns the path to the restored executable
+pub async fn rollback(lodestone_path: &Path) -> Result<PathBuf> {
+    let path = metadata_path(lodestone_path);
+    let metadata = Metadata::read_metadata(&path).await?;
+    let previous = metadata
+        .previous
+        .ok_or_else(|| eyre!("There is no previous version to roll back to"))?;
+    let executable_path = lodestone_path.join(&previous.executable_name);
+    if !executable_path.is_file() {
+        return Err(eyre!(
+            "The executable of the previous version ({}) is missing",
+            executable_path.display()
+        ));
+    }
+    let mut failed_versions = metadata.failed_versions;
+    if !failed_versions.contains(&metadata.current_version) {
+        failed_versions.push(metadata.current_version);
+    }
+    let restored = Metadata {
+        last_updated: Utc::now().to_string(),
+        previous: None,
+        pending_health_check: false,
+        failed_versions,
+        // the channel, constraint and source are settings of the installation, not of the version being rolled back
+        channel: metadata.channel,
+        constraint: metadata.constraint,
+        source: metadata.source,
+        ..*previous
+    };
+    restored.write_metadata(&path).await?;
+    Ok(executable_path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use HealthStatus::{Degraded, Ready, Unreachable};
+
+    #[cfg(unix)]
+    fn exit_status(code: i32) -> ExitStatus {
+        use std::os::unix::process::ExitStatusExt;
+        ExitStatus::from_raw(code << 8)
+    }
+
+    #[cfg(windows)]
+    fn exit_status(code: i32) -> ExitStatus {
+        use std::os::windows::process::ExitStatusExt;
+        ExitStatus::from_raw(code as u32)
+    }
+
+    #[test]
+    fn a_core_that_exits_with_an_error_failed_to_start() {
+        assert!(failed_to_start(&Ok(exit_status(1)), false));
+        assert!(failed_to_start(&Err(eyre!("failed to spawn")), false));
+    }
+
+    #[test]
+    fn a_core_that_exits_successfully_started() {
+        assert!(!failed_to_start(&Ok(exit_status(0)), false));
+    }
+
+    #[test]
+    fn a_core_that_was_stopped_is_not_rolled_back() {
+        // whatever the core exits with once it was asked to stop
+        assert!(!failed_to_start(&Ok(exit_status(137)), true));
+        assert!(!failed_to_start(&Ok(exit_status(1)), true));
+        assert!(!failed_to_start(&Ok(exit_status(0)), true));
+    }
+
+    #[test]
+    fn a_core_degraded_on_every_probe_fails() {
+        assert!(failed_startup(&[Degraded, Degraded, Degraded]));
+        assert!(failed_startup(&[
+            Unreachable,
+            Unreachable,
+            Degraded,
+            Degraded,
+            Degraded,
+            Degraded
+        ]));
+    }
+
+    #[test]
+    fn a_core_degraded_only_at_the_end_passes() {
+        // the one probe answered after starting up was slow
+        assert!(!failed_startup(&[Unreachable, Unreachable, Degraded]));
+        assert!(!failed_startup(&[Unreachable, Degraded, Degraded]));
+        assert!(!failed_startup(&[Degraded]));
+    }
+
+    #[test]
+    fn a_core_that_recovers_or_is_never_reached_passes() {
+        assert!(!failed_startup(&[Degraded, Degraded, Degraded, Ready]));
+        assert!(!failed_startup(&[
+            Degraded,
+            Unreachable,
+            Degraded,
+            Degraded
+        ]));
+        assert!(!failed_startup(&[Unreachable, Unreachable, Unreachable]));
+        assert!(!failed_startup(&[]));
+    }
+}
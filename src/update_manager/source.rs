@@ -0,0 +1,209 @@
+use color_eyre::eyre::{eyre, Context, Result};
+use serde::{Deserialize, Serialize};
+
+use std::fmt::Display;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use crate::versions::VersionWithV;
+use crate::{http, util};
+
+const GITHUB_REPO_URL: &str = "https://github.com/Lodestone-Team/lodestone_core/";
+/// The first page of the releases, the following ones are linked from its `Link` header
+const GITHUB_RELEASES_API_URL: &str =
+    "https://api.github.com/repos/Lodestone-Team/lodestone_core/releases?per_page=100";
+
+/// Name of the file listing the releases of a mirror
+pub const MIRROR_INDEX_NAME: &str = "index.json";
+
+#[derive(Deserialize)]
+struct GitHubRelease {
+    tag_name: String,
+}
+
+/// `index.json` of a mirror, `{"releases": [{"version": "v0.5.1"}, ...]}`
+#[derive(Deserialize)]
+struct MirrorIndex {
+    releases: Vec<MirrorRelease>,
+}
+
+#[derive(Deserialize)]
+struct MirrorRelease {
+    version: String,
+}
+
+/// Where releases of Lodestone Core and their assets are fetched from
+///
+/// Mirrors and directories are laid out the same way, every asset of a release under `<version>/`
+/// with the name it has on GitHub, e.g. `v0.5.1/lodestone_core_linux_x86_64_v0.5.1`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum ReleaseSource {
+    /// The GitHub releases of Lodestone-Team/lodestone_core
+    #[default]
+    GitHub,
+    /// An HTTP server listing its releases in `index.json`
+    Mirror(String),
+    /// A local directory with one directory per release, for machines without network access
+    Directory(PathBuf),
+}
+
+impl FromStr for ReleaseSource {
+    type Err = color_eyre::eyre::Report;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.eq_ignore_ascii_case("github") {
+            Ok(ReleaseSource::GitHub)
+        } else if s.starts_with("http://") || s.starts_with("https://") {
+            Ok(ReleaseSource::Mirror(s.trim_end_matches('/').to_string()))
+        } else if let Some(path) = s.strip_prefix("file://") {
+            Ok(ReleaseSource::Directory(PathBuf::from(path)))
+        } else {
+            Err(eyre!(
+                "invalid release source '{s}', expected 'github', an http(s):// URL or a file:// URL"
+            ))
+        }
+    }
+}
+
+impl Display for ReleaseSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ReleaseSource::GitHub => write!(f, "github"),
+            ReleaseSource::Mirror(url) => write!(f, "{url}"),
+            ReleaseSource::Directory(path) => write!(f, "file://{}", path.display()),
+        }
+    }
+}
+
+impl Serialize for ReleaseSource {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.to_string())
+    }
+}
+
+impl<'de> Deserialize<'de> for ReleaseSource {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        ReleaseSource::from_str(&s).map_err(serde::de::Error::custom)
+    }
+}
+
+impl ReleaseSource {
+    /// Every release the source has, newest first
+    pub async fn releases(&self) -> Result<Vec<VersionWithV>> {
+        let tags = match self {
+            ReleaseSource::GitHub => {
+                let releases: Vec<GitHubRelease> =
+                    http::get_json_pages(GITHUB_RELEASES_API_URL).await?;
+                releases
+                    .into_iter()
+                    .map(|release| release.tag_name)
+                    .collect()
+            }
+            ReleaseSource::Mirror(url) => {
+                let index: MirrorIndex =
+                    http::get_json(&format!("{url}/{MIRROR_INDEX_NAME}")).await?;
+                index
+                    .releases
+                    .into_iter()
+                    .map(|release| release.version)
+                    .collect()
+            }
+            ReleaseSource::Directory(path) => std::fs::read_dir(path)
+                .with_context(|| format!("Failed to read {}", path.display()))?
+                .filter_map(|entry| entry.ok())
+                .filter(|entry| entry.path().is_dir())
+                .map(|entry| entry.file_name().to_string_lossy().into_owned())
+                .collect::<Vec<_>>(),
+        };
+        let mut releases: Vec<VersionWithV> = tags
+            .iter()
+            .filter_map(|tag| VersionWithV::from_str(tag).ok())
+            .collect();
+        releases.sort();
+        releases.reverse();
+        Ok(releases)
+    }
+
+    fn asset_url(&self, version: &VersionWithV, asset_name: &str) -> Option<String> {
+        match self {
+            ReleaseSource::GitHub => Some(format!(
+                "{}releases/download/{}/{}",
+                GITHUB_REPO_URL, version, asset_name
+            )),
+            ReleaseSource::Mirror(url) => Some(format!("{url}/{version}/{asset_name}")),
+            ReleaseSource::Directory(_) => None,
+        }
+    }
+
+    /// Fetches a small text asset of a release, like a checksum or a signature, `None` if the release doesn't have it
+    pub async fn get_asset_text(
+        &self,
+        version: &VersionWithV,
+        asset_name: &str,
+    ) -> Result<Option<String>> {
+        match self {
+            ReleaseSource::Directory(path) => {
+                let asset_path = path.join(version.to_string()).join(asset_name);
+                match tokio::fs::read_to_string(&asset_path).await {
+                    Ok(contents) => Ok(Some(contents)),
+                    Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+                    Err(e) => {
+                        Err(e).with_context(|| format!("Failed to read {}", asset_path.display()))
+                    }
+                }
+            }
+            _ => {
+                let url = self
+                    .asset_url(version, asset_name)
+                    .expect("remote sources have asset URLs");
+                http::get_text(&url).await
+            }
+        }
+    }
+
+    /// Fetches an asset of a release to `dest`, running `verify` on it before it is moved into place
+    ///
+    /// See `util::download_file`, assets of a directory are copied the same way
+    pub async fn download_asset(
+        &self,
+        version: &VersionWithV,
+        asset_name: &str,
+        dest: &Path,
+        lodestone_path: &Path,
+        verify: impl FnOnce(&Path) -> Result<()>,
+    ) -> Result<()> {
+        let ReleaseSource::Directory(path) = self else {
+            let url = self
+                .asset_url(version, asset_name)
+                .expect("remote sources have asset URLs");
+            return util::download_file(&url, dest, lodestone_path, verify).await;
+        };
+        let asset_path = path.join(version.to_string()).join(asset_name);
+        if !asset_path.is_file() {
+            return Err(eyre!(
+                "Release {version} in {} has no {asset_name}",
+                path.display()
+            ));
+        }
+        let lodestone_tmp = lodestone_path.join("tmp");
+        tokio::fs::create_dir_all(&lodestone_tmp)
+            .await
+            .context("Failed to create tmp dir")?;
+        let temp_file_path = lodestone_tmp.join(format!("{asset_name}.part"));
+        tokio::fs::copy(&asset_path, &temp_file_path)
+            .await
+            .with_context(|| format!("Failed to copy {}", asset_path.display()))?;
+        if let Err(e) = verify(&temp_file_path) {
+            let _ = tokio::fs::remove_file(&temp_file_path).await;
+            return Err(e.wrap_err(format!(
+                "The copy of {} was discarded",
+                asset_path.display()
+            )));
+        }
+        tokio::fs::rename(&temp_file_path, dest)
+            .await
+            .context("Failed to move temporary file")?;
+        Ok(())
+    }
+}
@@ -1,17 +1,27 @@
-use crate::{info, versions::VersionWithV};
+use crate::{http, info, output, versions::VersionWithV, warn};
 use color_eyre::{
-    eyre::{Context, Result},
+    eyre::{eyre, Context, Result},
     owo_colors::OwoColorize,
 };
 use futures_util::StreamExt;
-use indicatif::{ProgressBar, ProgressStyle};
+use indicatif::{HumanBytes, ProgressBar, ProgressDrawTarget, ProgressStyle};
+use reqwest::StatusCode;
+use sha2::{Digest, Sha256};
 
 use std::{
     env,
+    fmt::Write,
     path::{Path, PathBuf},
 };
 use tokio::io::AsyncWriteExt;
 
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().fold(String::new(), |mut hex, b| {
+        let _ = write!(hex, "{:02x}", b);
+        hex
+    })
+}
+
 pub fn get_lodestone_path() -> Option<PathBuf> {
     let home_dir = dirs::home_dir()?;
 
@@ -45,45 +55,333 @@ pub fn get_executable_name(version: &VersionWithV) -> String {
             format!("{}_{}", executable_name, version)
         }
     } else {
-        panic!("Unsupported target architecture and operating system: {} {}", target_arch, target_os);
+        panic!(
+            "Unsupported target architecture and operating system: {} {}",
+            target_arch, target_os
+        );
     }
 }
 
-pub async fn download_file(url: &str, dest: &Path, lodestone_path: &Path) -> Result<()> {
-    info!("Downloading {} to {}", url, dest.display().bold().blue());
-    let lodestone_tmp = lodestone_path.join("tmp");
-    tokio::fs::create_dir_all(&lodestone_tmp)
-        .await
-        .context("Failed to create tmp dir")?;
-    let temp_file_path = tempfile::NamedTempFile::new_in(lodestone_tmp)
-        .context("Failed to create temporary file")?
-        .path()
-        .to_owned();
-    let mut temp_file = tokio::fs::File::create(&temp_file_path)
-        .await
-        .context("Failed to create temporary file")?;
-    let response = reqwest::get(url).await?;
-    response.error_for_status_ref()?;
-    if let Some(parent) = dest.parent() {
-        std::fs::create_dir_all(parent)?;
+pub fn sha256_file(path: &Path) -> Result<String> {
+    let mut file = std::fs::File::open(path)?;
+    let mut hasher = Sha256::new();
+    std::io::copy(&mut file, &mut hasher)?;
+    Ok(to_hex(&hasher.finalize()))
+}
+
+/// Checks the SHA-256 checksum of the file at `path`
+pub fn verify_sha256(path: &Path, expected: &str) -> Result<()> {
+    let actual = sha256_file(path)?;
+    if !actual.eq_ignore_ascii_case(expected) {
+        return Err(eyre!(
+            "Checksum mismatch for {}: expected {expected}, got {actual}",
+            path.display()
+        ));
+    }
+    info!("Checksum verified ({})", actual);
+    Ok(())
+}
+
+/// Where a partial download of `url` to `dest` is kept, stable across runs so the download can be resumed
+fn partial_download_path(url: &str, dest: &Path, lodestone_tmp: &Path) -> PathBuf {
+    let url_hash = to_hex(&Sha256::digest(url.as_bytes()));
+    let file_name = dest
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    lodestone_tmp.join(format!("{}.{}.part", file_name, &url_hash[..16]))
+}
+
+/// Where the validator of the partial download at `temp_file_path` is kept
+fn validator_path(temp_file_path: &Path) -> PathBuf {
+    let mut path = temp_file_path.as_os_str().to_owned();
+    path.push(".validator");
+    PathBuf::from(path)
+}
+
+/// The validator sent in `If-Range` to resume a download of what `response` started, its strong `ETag` or else its
+/// `Last-Modified` date
+///
+/// Weak `ETag`s can't be used for ranges
+fn validator(response: &reqwest::Response) -> Option<String> {
+    let header = |name| {
+        response
+            .headers()
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+    };
+    header(reqwest::header::ETAG)
+        .filter(|etag| !etag.starts_with("W/"))
+        .or_else(|| header(reqwest::header::LAST_MODIFIED))
+        .map(str::to_string)
+}
+
+/// Downloads `url` into the partial file at `temp_file_path`, resuming it if it already exists
+///
+/// A download is only resumed with `If-Range`, so the server sends the whole file again if it changed since the
+/// partial file was started. A partial file without a validator is discarded
+async fn fetch_to_partial(url: &str, temp_file_path: &Path) -> Result<()> {
+    let timeout = http::policy().timeout;
+    let (response, resume_from) = loop {
+        let existing_len = tokio::fs::metadata(temp_file_path)
+            .await
+            .map(|metadata| metadata.len())
+            .unwrap_or(0);
+        let mut request = http::client().get(url);
+        if existing_len > 0 {
+            let Ok(validator) = tokio::fs::read_to_string(validator_path(temp_file_path)).await
+            else {
+                warn!(
+                    "Discarding the partial download of {}, it can't be resumed",
+                    url
+                );
+                tokio::fs::remove_file(temp_file_path).await?;
+                continue;
+            };
+            request = request
+                .header(reqwest::header::RANGE, format!("bytes={}-", existing_len))
+                .header(reqwest::header::IF_RANGE, validator);
+        }
+        let response = tokio::time::timeout(timeout, request.send()).await??;
+        if existing_len > 0 {
+            let resumable = response.status() == StatusCode::PARTIAL_CONTENT
+                && response
+                    .headers()
+                    .get(reqwest::header::CONTENT_RANGE)
+                    .and_then(|range| range.to_str().ok())
+                    .is_some_and(|range| range.starts_with(&format!("bytes {}-", existing_len)));
+            if resumable {
+                info!("Resuming download from {}", HumanBytes(existing_len));
+                break (response, existing_len);
+            }
+            if response.status() == StatusCode::RANGE_NOT_SATISFIABLE
+                || response.status() == StatusCode::PARTIAL_CONTENT
+            {
+                warn!(
+                    "Discarding the partial download of {}, it can't be resumed",
+                    url
+                );
+                tokio::fs::remove_file(temp_file_path).await?;
+                continue;
+            }
+            if response.status().is_success() {
+                info!("The file changed or the server does not support resuming downloads, starting over");
+            }
+        }
+        response.error_for_status_ref()?;
+        break (response, 0);
+    };
+    if resume_from == 0 {
+        match validator(&response) {
+            Some(validator) => tokio::fs::write(validator_path(temp_file_path), validator)
+                .await
+                .context("Failed to write temporary file")?,
+            None => {
+                let _ = tokio::fs::remove_file(validator_path(temp_file_path)).await;
+            }
+        }
     }
-    let total = response.content_length().unwrap_or(0);
+
+    let mut temp_file = if resume_from > 0 {
+        tokio::fs::OpenOptions::new()
+            .append(true)
+            .open(temp_file_path)
+            .await
+    } else {
+        tokio::fs::File::create(temp_file_path).await
+    }
+    .context("Failed to open temporary file")?;
+    let expected_len = response.content_length().map(|len| len + resume_from);
     let mut stream = response.bytes_stream();
-    let pb = ProgressBar::new(total);
+    let pb = ProgressBar::new(expected_len.unwrap_or(0));
     pb.set_style(ProgressStyle::default_bar()
         .template("{msg}\n{spinner:.green} [{elapsed_precise}] [{wide_bar:.cyan/blue}] {bytes}/{total_bytes} ({bytes_per_sec}, {eta})").unwrap()
         .progress_chars("#>-"));
     pb.set_message(format!("Downloading {}", url));
+    pb.set_position(resume_from);
+    if output::is_machine_readable() {
+        pb.set_draw_target(ProgressDrawTarget::hidden());
+    }
 
-    while let Some(item) = stream.next().await {
-        let chunk = item?;
+    loop {
+        let chunk = match tokio::time::timeout(timeout, stream.next()).await {
+            Ok(Some(Ok(chunk))) => chunk,
+            Ok(None) => break,
+            Ok(Some(Err(e))) => {
+                pb.abandon_with_message("Download interrupted");
+                temp_file.flush().await?;
+                return Err(e).context("Download interrupted");
+            }
+            Err(e) => {
+                pb.abandon_with_message("Download timed out");
+                temp_file.flush().await?;
+                return Err(e).context("Download timed out");
+            }
+        };
         temp_file.write_all(&chunk).await?;
         pb.inc(chunk.len() as u64);
     }
     pb.finish_with_message("Downloaded file");
+    temp_file.flush().await?;
+    drop(temp_file);
+
+    let downloaded_len = tokio::fs::metadata(temp_file_path).await?.len();
+    if let Some(expected_len) = expected_len {
+        if downloaded_len > expected_len {
+            let _ = tokio::fs::remove_file(temp_file_path).await;
+            return Err(eyre!(
+                "Downloaded {} bytes of {}, but the server announced {} bytes",
+                downloaded_len,
+                url,
+                expected_len
+            ));
+        }
+        if downloaded_len < expected_len {
+            // the connection was closed early, the partial download can be resumed
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::UnexpectedEof,
+                format!(
+                    "Downloaded {} bytes of {}, but the server announced {} bytes",
+                    downloaded_len, url, expected_len
+                ),
+            )
+            .into());
+        }
+    }
+    Ok(())
+}
+
+/// Downloads `url` to `dest`, going through a partial file under `<lodestone_path>/tmp`
+///
+/// Interrupted downloads are resumed with a `Range` request when the server supports it, both across retries and runs.
+/// `verify` is run on the downloaded file before it is moved, if it fails the download is discarded and `dest` left untouched
+pub async fn download_file(
+    url: &str,
+    dest: &Path,
+    lodestone_path: &Path,
+    verify: impl FnOnce(&Path) -> Result<()>,
+) -> Result<()> {
+    info!("Downloading {} to {}", url, dest.display().bold().blue());
+    let lodestone_tmp = lodestone_path.join("tmp");
+    tokio::fs::create_dir_all(&lodestone_tmp)
+        .await
+        .context("Failed to create tmp dir")?;
+    let temp_file_path = partial_download_path(url, dest, &lodestone_tmp);
+    if let Some(parent) = dest.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+
+    http::retry(&format!("Download of {url}"), || {
+        fetch_to_partial(url, &temp_file_path)
+    })
+    .await?;
+    let _ = tokio::fs::remove_file(validator_path(&temp_file_path)).await;
+
+    if let Err(e) = verify(&temp_file_path) {
+        let _ = tokio::fs::remove_file(&temp_file_path).await;
+        return Err(e.wrap_err(format!("The download of {url} was discarded")));
+    }
     tokio::fs::rename(&temp_file_path, &dest)
         .await
         .context("Failed to move temporary file")?;
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_server::{self, Request, Response};
+    use std::sync::{Arc, Mutex};
+
+    const ETAG: &str = "\"v2\"";
+
+    fn content() -> Vec<u8> {
+        (0..4096).map(|i| (i % 251) as u8).collect()
+    }
+
+    /// Serves `content()` with `ETAG`, answering ranges only if `If-Range` matches, and records the ranges asked for
+    fn serve_file() -> (String, Arc<Mutex<Vec<Option<String>>>>) {
+        let ranges = Arc::new(Mutex::new(Vec::new()));
+        let recorded = ranges.clone();
+        let port = test_server::serve(move |request: &Request| {
+            let range = request.headers.get("range").cloned();
+            recorded.lock().unwrap().push(range.clone());
+            let content = content();
+            let start = range
+                .filter(|_| request.headers.get("if-range").map(String::as_str) == Some(ETAG))
+                .and_then(|range| {
+                    range
+                        .strip_prefix("bytes=")?
+                        .strip_suffix('-')?
+                        .parse()
+                        .ok()
+                });
+            match start {
+                Some(start) => Response::new(206, content[start..].to_vec()).header(
+                    "Content-Range",
+                    &format!("bytes {start}-{}/{}", content.len() - 1, content.len()),
+                ),
+                None => Response::new(200, content),
+            }
+            .header("ETag", ETAG)
+        });
+        (format!("http://127.0.0.1:{port}/lodestone_core"), ranges)
+    }
+
+    /// A fresh `LODESTONE_PATH` for the test `name`, with the path the download of `url` to `dest` is kept at
+    /// while it is partial
+    fn test_dir(name: &str, url: &str) -> (PathBuf, PathBuf, PathBuf) {
+        let lodestone_path = env::temp_dir()
+            .join(format!("lodestone_cli_test_{}", std::process::id()))
+            .join(name);
+        let _ = std::fs::remove_dir_all(&lodestone_path);
+        let lodestone_tmp = lodestone_path.join("tmp");
+        std::fs::create_dir_all(&lodestone_tmp).unwrap();
+        let dest = lodestone_path.join("lodestone_core");
+        let partial = partial_download_path(url, &dest, &lodestone_tmp);
+        (lodestone_path, dest, partial)
+    }
+
+    #[tokio::test]
+    async fn resumes_an_unchanged_file() {
+        let (url, ranges) = serve_file();
+        let (lodestone_path, dest, partial) = test_dir("resume_unchanged", &url);
+        std::fs::write(&partial, &content()[..1000]).unwrap();
+        std::fs::write(validator_path(&partial), ETAG).unwrap();
+
+        download_file(&url, &dest, &lodestone_path, |_| Ok(()))
+            .await
+            .unwrap();
+        assert_eq!(std::fs::read(&dest).unwrap(), content());
+        assert_eq!(*ranges.lock().unwrap(), [Some("bytes=1000-".to_string())]);
+        assert!(!validator_path(&partial).exists());
+    }
+
+    #[tokio::test]
+    async fn starts_over_when_the_file_changed() {
+        let (url, ranges) = serve_file();
+        let (lodestone_path, dest, partial) = test_dir("resume_changed", &url);
+        std::fs::write(&partial, b"the start of an older version").unwrap();
+        std::fs::write(validator_path(&partial), "\"v1\"").unwrap();
+
+        download_file(&url, &dest, &lodestone_path, |_| Ok(()))
+            .await
+            .unwrap();
+        assert_eq!(std::fs::read(&dest).unwrap(), content());
+        assert_eq!(ranges.lock().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn discards_a_partial_file_without_validator() {
+        let (url, ranges) = serve_file();
+        let (lodestone_path, dest, partial) = test_dir("resume_unvalidated", &url);
+        std::fs::write(&partial, &content()[..1000]).unwrap();
+
+        download_file(&url, &dest, &lodestone_path, |_| Ok(()))
+            .await
+            .unwrap();
+        assert_eq!(std::fs::read(&dest).unwrap(), content());
+        assert_eq!(*ranges.lock().unwrap(), [None]);
+    }
+}
@@ -0,0 +1,85 @@
+use color_eyre::eyre::{eyre, Context, Result};
+use color_eyre::owo_colors::OwoColorize;
+use minisign_verify::{PublicKey, Signature};
+use std::io::Read;
+use std::path::Path;
+
+use crate::{info, warn};
+
+/// Public key official Lodestone releases are signed with, embedded by the release pipeline
+const BUILTIN_PUBLIC_KEY: Option<&str> = option_env!("LODESTONE_RELEASE_PUBLIC_KEY");
+
+/// How downloaded artifacts are checked before they replace anything on disk
+#[derive(Debug, Clone, Default)]
+pub struct VerifyOptions {
+    /// Don't check the SHA-256 checksum published with the release
+    pub skip_checksum: bool,
+    /// Install artifacts that are unsigned or whose signature doesn't check out
+    pub allow_unsigned: bool,
+    /// Replaces the built-in public key, either a base64 minisign key or a path to a minisign `.pub` file
+    pub public_key: Option<String>,
+}
+
+impl VerifyOptions {
+    pub fn public_key(&self) -> Result<PublicKey> {
+        match self.public_key.as_deref() {
+            Some(key) if Path::new(key).is_file() => PublicKey::from_file(key)
+                .with_context(|| format!("Failed to read public key from {key}")),
+            Some(key) => {
+                PublicKey::from_base64(key).context("Failed to parse the configured public key")
+            }
+            None => PublicKey::from_base64(BUILTIN_PUBLIC_KEY.ok_or_else(|| {
+                eyre!("This build of the cli has no built-in public key, configure one with '--public-key'")
+            })?)
+            .context("Failed to parse the built-in public key"),
+        }
+    }
+}
+
+fn verify_signature(path: &Path, signature: &str, public_key: &PublicKey) -> Result<()> {
+    let signature = Signature::decode(signature).context("Malformed signature")?;
+    let mut verifier = public_key.verify_stream(&signature)?;
+    let mut file = std::fs::File::open(path)?;
+    let mut buf = vec![0; 64 * 1024];
+    loop {
+        let n = file.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        verifier.update(&buf[..n]);
+    }
+    verifier.finalize()?;
+    Ok(())
+}
+
+/// Checks the detached minisign `signature` of the file at `path`
+///
+/// `signature` is `None` if the release didn't publish one. Failures are only let through with `--allow-unsigned`
+pub fn check_signature(
+    path: &Path,
+    artifact_name: &str,
+    signature: Option<&str>,
+    options: &VerifyOptions,
+) -> Result<()> {
+    let result = signature
+        .ok_or_else(|| eyre!("No signature was published for {artifact_name}"))
+        .and_then(|signature| verify_signature(path, signature, &options.public_key()?))
+        .with_context(|| format!("Signature verification failed for {artifact_name}"));
+    match result {
+        Ok(()) => info!("Signature verified for {}", artifact_name),
+        Err(e) if options.allow_unsigned => {
+            warn!(
+                "{}",
+                format!("{e:#}. Installing it anyway because '--allow-unsigned' was passed")
+                    .bold()
+                    .red()
+            );
+        }
+        Err(e) => {
+            return Err(eyre!(
+                "{e:#}. Refusing to install it, pass '--allow-unsigned' to install it anyway"
+            ))
+        }
+    }
+    Ok(())
+}
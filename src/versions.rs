@@ -1,17 +1,16 @@
 use std::fmt::Display;
 use std::str::FromStr;
 
-use color_eyre::eyre::Result;
+use clap::ValueEnum;
+use color_eyre::eyre::{eyre, Result};
 use color_eyre::owo_colors::OwoColorize;
-use semver::Version;
+use semver::{Version, VersionReq};
 use serde::{Deserialize, Serialize};
+use serde_json::json;
 
-use crate::update_manager::metadata::Metadata;
-use crate::util;
-#[derive(serde::Deserialize)]
-pub struct Release {
-    pub tag_name: String,
-}
+use crate::update_manager::metadata::{metadata_path, Metadata};
+use crate::update_manager::source::ReleaseSource;
+use crate::{output, util};
 
 #[derive(Clone, Debug, Eq, PartialEq, PartialOrd, Ord)]
 pub struct VersionWithV(pub Version);
@@ -57,49 +56,158 @@ impl From<VersionWithV> for Version {
     }
 }
 
-pub async fn get_latest_release() -> Result<VersionWithV> {
-    let release_url = "https://api.github.com/repos/Lodestone-Team/lodestone_core/releases/latest";
-    let client = reqwest::Client::new();
+/// What `--version` accepts: an exact version, or a semver requirement such as `^0.5`, `~0.5.2` or `>=0.4, <0.6`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum VersionSpec {
+    Exact(VersionWithV),
+    Req(VersionReq),
+}
+
+impl FromStr for VersionSpec {
+    type Err = semver::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        // a bare version is an exact pin, even though semver reads `0.5.1` as `^0.5.1`
+        match VersionWithV::from_str(s) {
+            Ok(version) => Ok(VersionSpec::Exact(version)),
+            Err(_) => Ok(VersionSpec::Req(VersionReq::parse(s)?)),
+        }
+    }
+}
+
+impl Display for VersionSpec {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            VersionSpec::Exact(version) => write!(f, "{version}"),
+            VersionSpec::Req(req) => write!(f, "{req}"),
+        }
+    }
+}
+
+impl Serialize for VersionSpec {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.to_string())
+    }
+}
+
+impl<'de> Deserialize<'de> for VersionSpec {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        VersionSpec::from_str(&s).map_err(serde::de::Error::custom)
+    }
+}
 
-    let response = client
-        .get(release_url)
-        .header("User-Agent", "lodestone_cli")
-        .send()
-        .await?;
-    response.error_for_status_ref()?;
+/// Which releases of Lodestone Core automatic updates pick, based on their semver pre-release
+#[derive(ValueEnum, Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Channel {
+    /// Only full releases
+    #[default]
+    Stable,
+    /// Full releases, betas and release candidates (`-beta.N`, `-rc.N`)
+    Beta,
+    /// Every release, including alphas and nightly builds
+    Nightly,
+}
 
-    let release: Release = response.json().await?;
-    let latest_version = VersionWithV::from_str(release.tag_name.as_str())?;
-    Ok(latest_version)
+impl Channel {
+    pub fn includes(self, version: &VersionWithV) -> bool {
+        let pre = version.0.pre.as_str();
+        match self {
+            Channel::Stable => pre.is_empty(),
+            Channel::Beta => pre.is_empty() || pre.starts_with("beta") || pre.starts_with("rc"),
+            Channel::Nightly => true,
+        }
+    }
+}
+
+impl Display for Channel {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Channel::Stable => write!(f, "stable"),
+            Channel::Beta => write!(f, "beta"),
+            Channel::Nightly => write!(f, "nightly"),
+        }
+    }
+}
+
+/// The first of `releases`, newest first, on `channel` that matches `constraint` if there is one
+///
+/// Like cargo, a requirement only matches a pre-release if it names a pre-release of the same version
+fn latest_matching(
+    releases: Vec<VersionWithV>,
+    channel: Channel,
+    constraint: Option<&VersionReq>,
+) -> Option<VersionWithV> {
+    releases.into_iter().find(|release| {
+        channel.includes(release) && constraint.map_or(true, |req| req.matches(&release.0))
+    })
+}
+
+/// The newest release of Lodestone Core on `channel`, that matches `constraint` if there is one
+pub async fn get_latest_release(
+    source: &ReleaseSource,
+    channel: Channel,
+    constraint: Option<&VersionReq>,
+) -> Result<VersionWithV> {
+    latest_matching(source.releases().await?, channel, constraint).ok_or_else(|| match constraint {
+        Some(req) => {
+            eyre!("There is no release of Lodestone Core matching '{req}' on the {channel} channel")
+        }
+        None => eyre!("There is no release of Lodestone Core on the {channel} channel"),
+    })
 }
 
 pub async fn get_current_version() -> Result<VersionWithV> {
-    let metadata_path = util::get_lodestone_path()
-        .ok_or_else(|| color_eyre::eyre::eyre!("Could not find lodestone path"))?
-        .join(".lodestone_cli_metadata.json");
+    let metadata_path = metadata_path(
+        &util::get_lodestone_path()
+            .ok_or_else(|| color_eyre::eyre::eyre!("Could not find lodestone path"))?,
+    );
     let metadata = Metadata::read_metadata(&metadata_path).await?;
     Ok(metadata.current_version)
 }
 
-pub async fn list_versions() -> Result<()> {
-    let release_url = "https://api.github.com/repos/Lodestone-Team/lodestone_core/releases";
-    let client = reqwest::Client::new();
-
-    let response = client
-        .get(release_url)
-        .header("User-Agent", "lodestone_cli")
-        .send()
-        .await?;
-    response.error_for_status_ref()?;
-    let releases: Vec<Release> = response.json().await?;
-    let mut releases: Vec<VersionWithV> = releases
-        .iter()
-        .map(|release| VersionWithV::from_str(release.tag_name.as_str()))
-        .filter_map(Result::ok)
-        .collect();
-    releases.sort();
-    releases.reverse();
+/// The channel the installation under LODESTONE_PATH follows, stable if it isn't installed
+pub async fn get_current_channel() -> Channel {
+    let Some(lodestone_path) = util::get_lodestone_path() else {
+        return Channel::default();
+    };
+    Metadata::read_metadata(&metadata_path(&lodestone_path))
+        .await
+        .map(|metadata| metadata.channel)
+        .unwrap_or_default()
+}
+
+/// The releases on `channel`, or all of them if it isn't set
+fn on_channel(releases: Vec<VersionWithV>, channel: Option<Channel>) -> Vec<VersionWithV> {
+    releases
+        .into_iter()
+        .filter(|release| channel.map_or(true, |channel| channel.includes(release)))
+        .collect()
+}
+
+/// Prints every released version, only the ones on `channel` if it is set
+pub async fn list_versions(source: &ReleaseSource, channel: Option<Channel>) -> Result<()> {
+    let releases = on_channel(source.releases().await?, channel);
     let current_version = get_current_version().await.ok();
+    if output::is_machine_readable() {
+        output::result(
+            "versions",
+            releases
+                .iter()
+                .enumerate()
+                .map(|(i, release)| {
+                    json!({
+                        "version": release,
+                        "current": Some(release) == current_version.as_ref(),
+                        "latest": i == 0,
+                        "prerelease": !release.0.pre.is_empty(),
+                    })
+                })
+                .collect::<Vec<_>>(),
+        );
+        return Ok(());
+    }
     println!("Available versions:");
     let mut first = true;
     for release in releases {
@@ -133,3 +241,90 @@ pub async fn list_versions() -> Result<()> {
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn versions(versions: &[&str]) -> Vec<VersionWithV> {
+        versions.iter().map(|v| v.parse().unwrap()).collect()
+    }
+
+    #[test]
+    fn channels_include_their_pre_releases() {
+        let stable = "v0.5.1".parse().unwrap();
+        let beta = "v0.5.2-beta.1".parse().unwrap();
+        let rc = "v0.5.2-rc.2".parse().unwrap();
+        let alpha = "v0.6.0-alpha.1".parse().unwrap();
+        assert!(Channel::Stable.includes(&stable));
+        assert!(!Channel::Stable.includes(&beta));
+        assert!(!Channel::Stable.includes(&rc));
+        assert!(Channel::Beta.includes(&stable));
+        assert!(Channel::Beta.includes(&beta));
+        assert!(Channel::Beta.includes(&rc));
+        assert!(!Channel::Beta.includes(&alpha));
+        assert!(Channel::Nightly.includes(&alpha));
+    }
+
+    #[test]
+    fn on_channel_keeps_the_order_of_releases() {
+        let releases = versions(&["v0.6.0-alpha.1", "v0.5.2-beta.1", "v0.5.1", "v0.5.0"]);
+        assert_eq!(
+            on_channel(releases.clone(), Some(Channel::Stable)),
+            versions(&["v0.5.1", "v0.5.0"])
+        );
+        assert_eq!(
+            on_channel(releases.clone(), Some(Channel::Beta)),
+            versions(&["v0.5.2-beta.1", "v0.5.1", "v0.5.0"])
+        );
+        assert_eq!(
+            on_channel(releases.clone(), Some(Channel::Nightly)),
+            releases
+        );
+        assert_eq!(on_channel(releases.clone(), None), releases);
+    }
+
+    fn latest(releases: &[&str], channel: Channel, constraint: Option<&str>) -> Option<String> {
+        let constraint = constraint.map(|req| VersionReq::parse(req).unwrap());
+        latest_matching(versions(releases), channel, constraint.as_ref())
+            .map(|release| release.to_string())
+    }
+
+    #[test]
+    fn constraints_skip_pre_releases_of_other_versions() {
+        let releases = ["v0.6.0-beta.1", "v0.5.2-beta.1", "v0.5.1", "v0.4.9"];
+        // on the beta channel, the newest release is a pre-release, but '^0.5' only matches full releases
+        assert_eq!(
+            latest(&releases, Channel::Beta, None).as_deref(),
+            Some("v0.6.0-beta.1")
+        );
+        assert_eq!(
+            latest(&releases, Channel::Beta, Some("^0.5")).as_deref(),
+            Some("v0.5.1")
+        );
+        // naming the pre-release opts into pre-releases of that version
+        assert_eq!(
+            latest(&releases, Channel::Beta, Some(">=0.5.2-beta.0, <0.6")).as_deref(),
+            Some("v0.5.2-beta.1")
+        );
+    }
+
+    #[test]
+    fn constraints_only_pick_releases_on_the_channel() {
+        let releases = ["v0.5.2-beta.1", "v0.5.1", "v0.4.9"];
+        // the pre-release matches the requirement, but stable never picks it
+        assert_eq!(
+            latest(&releases, Channel::Stable, Some(">=0.5.2-beta.0")).as_deref(),
+            None
+        );
+        assert_eq!(
+            latest(&releases, Channel::Beta, Some(">=0.5.2-beta.0")).as_deref(),
+            Some("v0.5.2-beta.1")
+        );
+        assert_eq!(
+            latest(&releases, Channel::Stable, Some("~0.4")).as_deref(),
+            Some("v0.4.9")
+        );
+        assert_eq!(latest(&releases, Channel::Stable, Some("^0.6")), None);
+    }
+}